@@ -0,0 +1,89 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::Rng;
+use vdb::storage::{IndexStore, IoBackend, NaiveDisk, ReadMode};
+use vdb::Metric;
+
+fn bench_read_nodes(c: &mut Criterion) {
+    const SIZE: usize = 10000;
+    const VALUE_RANGE: std::ops::Range<f32> = 0.0..2000.0;
+    const DIMENSION: u16 = 128;
+    const MAX_NEIGHBOUR_COUNT: u8 = 32;
+    // about the number of nodes a beam search round with a beam width of 4 reads
+    const BATCH_SIZE: usize = 128;
+
+    let test_vectors: Vec<Vec<f32>> =
+        vdb::vector::generate_random_vectors(SIZE, &VALUE_RANGE, DIMENSION as usize)
+            .into_iter()
+            .map(|(vector, _)| vector)
+            .collect();
+    let mut disk = NaiveDisk::new(
+        DIMENSION,
+        MAX_NEIGHBOUR_COUNT,
+        "disk_io.index",
+        "disk_io.free",
+    )
+    .unwrap();
+    disk.add_nodes(&test_vectors).unwrap();
+
+    let mut rng = rand::thread_rng();
+    let batches: Vec<Vec<u32>> = (0..100)
+        .map(|_| {
+            (0..BATCH_SIZE)
+                .map(|_| rng.gen_range(1..=SIZE as u32))
+                .collect()
+        })
+        .collect();
+
+    c.bench_function("[disk] get_node per node (open + seek)", |b| {
+        b.iter(|| {
+            for batch in &batches {
+                for node_index in batch {
+                    black_box(disk.get_node(*node_index).unwrap());
+                }
+            }
+        })
+    });
+
+    for backend in [IoBackend::Pread, IoBackend::IoUring] {
+        if disk.set_io_backend(backend) != backend {
+            eprintln!("{:?} is not available, skipping", backend);
+            continue;
+        }
+        c.bench_function(&format!("[disk] get_nodes ({:?})", backend), |b| {
+            b.iter(|| {
+                for batch in &batches {
+                    black_box(disk.get_nodes(batch).unwrap());
+                }
+            })
+        });
+    }
+
+    disk.set_read_mode(ReadMode::Mmap).unwrap();
+    c.bench_function("[disk] get_nodes (mmap)", |b| {
+        b.iter(|| {
+            for batch in &batches {
+                black_box(disk.get_nodes(batch).unwrap());
+            }
+        })
+    });
+
+    // what a search needs of most nodes it visits, computed without decoding them into nodes
+    let query = &test_vectors[0];
+    c.bench_function("[disk] distances_to_query (mmap)", |b| {
+        b.iter(|| {
+            for batch in &batches {
+                black_box(
+                    disk.distances_to_query(Metric::L2, query, None, batch)
+                        .unwrap()
+                        .unwrap(),
+                );
+            }
+        })
+    });
+
+    let _ = std::fs::remove_file("disk_io.index");
+    let _ = std::fs::remove_file("disk_io.free");
+}
+
+criterion_group!(benches, bench_read_nodes);
+criterion_main!(benches);
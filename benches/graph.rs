@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use vdb::{graph, InMemStorage};
+use vdb::{graph, InMemStorage, Metric};
 
 fn bench_index(c: &mut Criterion) {
     const SIZE: usize = 100;
@@ -51,20 +51,22 @@ fn bench_query(c: &mut Criterion) {
         vec![test_vectors.clone()].into_iter(),
         2,
         MAX_NEIGHBOUR_COUNT,
+        Metric::L2,
         Box::new(vdb::storage::InMemStorage::default()),
         Box::new(vdb::storage::InMemStorage::default()),
     )
     .unwrap();
-    in_mem_graph.index(1.2).unwrap();
+    in_mem_graph.index(1.2, 10).unwrap();
 
     c.bench_function("[in-mem] query", |b| {
-        b.iter(|| in_mem_graph.greedy_search(1, &[1000.0f32, 1000.0f32], 3, 10));
+        b.iter(|| in_mem_graph.search(&[1000.0f32, 1000.0f32], 3, 10));
     });
 
     let mut disk_graph = graph::Graph::new(
         vec![test_vectors].into_iter(),
         2,
         MAX_NEIGHBOUR_COUNT,
+        Metric::L2,
         Box::new(
             vdb::storage::NaiveDisk::new(DIMENSION, MAX_NEIGHBOUR_COUNT, "disk.index", "disk.free")
                 .unwrap(),
@@ -73,10 +75,10 @@ fn bench_query(c: &mut Criterion) {
     )
     .unwrap();
 
-    disk_graph.index(1.2).unwrap();
+    disk_graph.index(1.2, 10).unwrap();
 
     c.bench_function("[disk] query", |b| {
-        b.iter(|| disk_graph.greedy_search(1, &[1000.0f32, 1000.0f32], 3, 10));
+        b.iter(|| disk_graph.search(&[1000.0f32, 1000.0f32], 3, 10));
     });
 }
 
@@ -91,12 +93,13 @@ fn create_and_index_graph(
         vec![test_vectors.clone()].into_iter(),
         R,
         max_neighbour_count,
+        Metric::L2,
         storage_factory(),
         Box::new(InMemStorage::default()),
     )
     .unwrap();
 
-    graph.index(1.0).unwrap();
+    graph.index(1.0, 10).unwrap();
 }
 
 criterion_group!(benches, bench_index, bench_query);
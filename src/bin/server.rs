@@ -0,0 +1,533 @@
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use vdb::{
+    DiskDataStore, Error, ExternalId, Graph, IdMap, Label, LabelIndex, NaiveDisk, ReadMode,
+    SearchHit,
+};
+
+// prune threshold (alpha) of inserts, as used by the CLI when indexing
+const DISTANCE_THRESHOLD: f32 = 1.0;
+// search list size (L) of searches and inserts that don't pass one, raised to k for searches with a larger k
+const DEFAULT_SEARCH_LIST_SIZE: usize = 50;
+
+/// Serve an index persisted by the pure disk storage over HTTP/JSON
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: String,
+
+    /// Number of threads serving requests. Searches run concurrently, inserts and deletes one at a time
+    #[arg(long, default_value_t = 4)]
+    threads: usize,
+
+    /// Index file of the graph
+    #[arg(long, default_value = "disk.index")]
+    index: String,
+
+    /// Free slots file of the graph
+    #[arg(long, default_value = "disk.free")]
+    free: String,
+
+    /// Payload log of the nodes
+    #[arg(long, default_value = "disk.data")]
+    data: String,
+
+    /// Payload offsets of the nodes
+    #[arg(long, default_value = "disk.offsets")]
+    offsets: String,
+
+    /// Log of external ids, created if missing
+    #[arg(long, default_value = "disk.ids")]
+    ids: String,
+
+    /// Log of node labels, created if missing
+    #[arg(long, default_value = "disk.labels")]
+    labels: String,
+
+    /// Number of nodes expanded per round of a search
+    #[arg(long, default_value_t = 1)]
+    beam_width: usize,
+
+    /// Read the index through a memory map instead of a read per node
+    #[arg(long)]
+    mmap: bool,
+}
+
+// State is what every request thread shares: the graph behind a lock that lets searches run side by side
+struct State {
+    graph: RwLock<Graph>,
+    dimensions: usize,
+}
+
+// SAFETY: Graph is neither Send nor Sync as its stores needn't be, but State::new only takes a graph whose index and
+// data stores are thread-safe, as they tell through as_sync. The stores of this crate that are Sync are also Send
+unsafe impl Send for State {}
+unsafe impl Sync for State {}
+
+impl State {
+    fn new(graph: Graph, dimensions: usize) -> vdb::prelude::Result<Self> {
+        if graph.index_store.as_sync().is_none() || graph.data_store.as_sync().is_none() {
+            return Err(Error::InvalidInput(format!(
+                "{} cannot be served as its stores are not thread-safe",
+                graph.index_store.get_name()
+            )));
+        }
+        Ok(State {
+            graph: RwLock::new(graph),
+            dimensions,
+        })
+    }
+}
+
+// ApiError is a failed request, turned into an HTTP status and a JSON body with its message
+#[derive(Debug)]
+enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    Internal(String),
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::InvalidInput(message) => ApiError::BadRequest(message),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::BadRequest(format!("invalid request body: {}", e))
+    }
+}
+
+// Id is an external id in JSON: a number or a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Id {
+    U64(u64),
+    String(String),
+}
+
+impl From<Id> for ExternalId {
+    fn from(id: Id) -> Self {
+        match id {
+            Id::U64(id) => ExternalId::U64(id),
+            Id::String(id) => ExternalId::String(id),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchRequest {
+    vector: Vec<f32>,
+    k: usize,
+    l: Option<usize>,
+    #[serde(default)]
+    filter: Vec<Label>,
+}
+
+// InsertRequest inserts a node, or replaces the node of id when it is given
+#[derive(Deserialize)]
+struct InsertRequest {
+    id: Option<Id>,
+    vector: Vec<f32>,
+    #[serde(default)]
+    data: String,
+    #[serde(default)]
+    labels: Vec<Label>,
+    l: Option<usize>,
+}
+
+// NodeRequest names a node by its external id, or by its node index for nodes without one
+#[derive(Deserialize)]
+struct NodeRequest {
+    id: Option<Id>,
+    node: Option<u32>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let state = Arc::new(open_state(&args).expect("Failed to open index"));
+    let server = Arc::new(Server::http(&args.addr).expect("Failed to start server"));
+    println!("serving {} on http://{}", args.index, args.addr);
+
+    let handles: Vec<_> = (0..args.threads.max(1))
+        .map(|_| {
+            let state = state.clone();
+            let server = server.clone();
+            thread::spawn(move || {
+                while let Ok(request) = server.recv() {
+                    respond(&state, request);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// open_state reopens the graph and its payloads, external ids and labels, as persisted by previous runs
+fn open_state(args: &Args) -> vdb::prelude::Result<State> {
+    let mut index_store = NaiveDisk::open(&args.index, &args.free)?;
+    if args.mmap {
+        index_store.set_read_mode(ReadMode::Mmap)?;
+    }
+    let dimensions = index_store.dimensions() as usize;
+    let max_neighbour_count = index_store.max_neighbour_count();
+    let data_store = DiskDataStore::open(&args.data, &args.offsets)?;
+
+    let mut graph = Graph::from_stores(
+        Box::new(index_store),
+        Box::new(data_store),
+        max_neighbour_count,
+    )?;
+    graph.set_beam_width(args.beam_width)?;
+    graph.set_id_map(if Path::new(&args.ids).exists() {
+        IdMap::open(&args.ids)?
+    } else {
+        IdMap::new(&args.ids)?
+    });
+    graph.set_label_index(if Path::new(&args.labels).exists() {
+        LabelIndex::open(&args.labels)?
+    } else {
+        LabelIndex::new(&args.labels)?
+    })?;
+
+    State::new(graph, dimensions)
+}
+
+fn respond(state: &State, mut request: Request) {
+    let mut body = Vec::new();
+    let (status, value) = match request.as_reader().read_to_end(&mut body) {
+        Ok(_) => handle(state, request.method(), request.url(), &body),
+        Err(e) => error_response(ApiError::BadRequest(e.to_string())),
+    };
+    let response = Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+    // the client may be gone, which only concerns this request
+    let _ = request.respond(response);
+}
+
+// handle routes a request and returns the status code and JSON body of its response
+fn handle(state: &State, method: &Method, url: &str, body: &[u8]) -> (u16, Value) {
+    let result = match (method, url) {
+        (Method::Post, "/search") => search(state, body),
+        (Method::Post, "/insert") => insert(state, body),
+        (Method::Post, "/delete") => delete(state, body),
+        (Method::Post, "/get") => get(state, body),
+        (Method::Post, "/consolidate") => consolidate(state),
+        (Method::Get, "/stats") => stats(state),
+        _ => Err(ApiError::NotFound(format!(
+            "no endpoint {} {}",
+            method, url
+        ))),
+    };
+    match result {
+        Ok(value) => (200, value),
+        Err(e) => error_response(e),
+    }
+}
+
+fn error_response(e: ApiError) -> (u16, Value) {
+    let (status, message) = match e {
+        ApiError::BadRequest(message) => (400, message),
+        ApiError::NotFound(message) => (404, message),
+        ApiError::Internal(message) => (500, message),
+    };
+    (status, json!({ "error": message }))
+}
+
+fn check_dimensions(state: &State, vector: &[f32]) -> Result<(), ApiError> {
+    if vector.len() != state.dimensions {
+        return Err(ApiError::BadRequest(format!(
+            "vector has {} dimensions, the index has {}",
+            vector.len(),
+            state.dimensions
+        )));
+    }
+    Ok(())
+}
+
+// search_list_size returns the l of a request, which must be at least k, and at least 1 for inserts
+fn search_list_size(l: Option<usize>, k: usize) -> Result<usize, ApiError> {
+    match l {
+        None => Ok(k.max(DEFAULT_SEARCH_LIST_SIZE)),
+        Some(l) if l < k.max(1) => Err(ApiError::BadRequest(format!(
+            "l must be at least {}",
+            k.max(1)
+        ))),
+        Some(l) => Ok(l),
+    }
+}
+
+fn search(state: &State, body: &[u8]) -> Result<Value, ApiError> {
+    let request: SearchRequest = serde_json::from_slice(body)?;
+    check_dimensions(state, &request.vector)?;
+    if request.k == 0 {
+        return Err(ApiError::BadRequest("k must be at least 1".to_owned()));
+    }
+    let search_list_size = search_list_size(request.l, request.k)?;
+
+    let graph = state.graph.read().unwrap();
+    let hits = graph.search_filtered(
+        &request.vector,
+        &request.filter,
+        request.k,
+        search_list_size,
+    )?;
+    let hits: Vec<Value> = hits.into_iter().map(hit_json).collect();
+    Ok(json!({ "hits": hits }))
+}
+
+fn insert(state: &State, body: &[u8]) -> Result<Value, ApiError> {
+    let request: InsertRequest = serde_json::from_slice(body)?;
+    check_dimensions(state, &request.vector)?;
+    let search_list_size = search_list_size(request.l, 1)?;
+
+    let mut graph = state.graph.write().unwrap();
+    let node = match request.id {
+        Some(id) => graph.upsert(
+            id,
+            request.vector,
+            request.data.as_bytes(),
+            &request.labels,
+            DISTANCE_THRESHOLD,
+            search_list_size,
+        )?,
+        None => graph.insert(
+            request.vector,
+            request.data.as_bytes(),
+            &request.labels,
+            DISTANCE_THRESHOLD,
+            search_list_size,
+        )?,
+    };
+    // writes are only acknowledged once they are durable
+    graph.flush()?;
+    Ok(json!({ "node": node.id() }))
+}
+
+fn delete(state: &State, body: &[u8]) -> Result<Value, ApiError> {
+    let request: NodeRequest = serde_json::from_slice(body)?;
+    let mut graph = state.graph.write().unwrap();
+    let node_index = node_index(&graph, request)?;
+    match graph.id_map().external_id(node_index).cloned() {
+        Some(id) => graph.delete_by_id(&id)?,
+        None => graph.delete(node_index)?,
+    }
+    graph.flush()?;
+    Ok(json!({ "node": node_index }))
+}
+
+fn get(state: &State, body: &[u8]) -> Result<Value, ApiError> {
+    let request: NodeRequest = serde_json::from_slice(body)?;
+    let graph = state.graph.read().unwrap();
+    let node_index = node_index(&graph, request)?;
+    let node = graph.index_store.get_node(node_index)?;
+    let data = graph.data_store.get_data(node_index)?;
+    Ok(json!({
+        "node": node_index,
+        "id": graph.id_map().external_id(node_index).map(id_json),
+        "vector": node.vector(),
+        "data": data.map(|data| String::from_utf8_lossy(&data).into_owned()),
+    }))
+}
+
+fn consolidate(state: &State) -> Result<Value, ApiError> {
+    let mut graph = state.graph.write().unwrap();
+    let deleted = graph.index_store.get_deleted_node_indexes()?.len();
+    graph.consolidate_deletes(DISTANCE_THRESHOLD)?;
+    graph.flush()?;
+    Ok(json!({ "removed": deleted }))
+}
+
+fn stats(state: &State) -> Result<Value, ApiError> {
+    let graph = state.graph.read().unwrap();
+    let (node_count, deleted) = graph.index_store.node_counts()?;
+    Ok(json!({
+        "index_store": graph.index_store.get_name(),
+        "nodes": node_count - deleted,
+        "deleted": deleted,
+        "ids": graph.id_map().len(),
+        "dimensions": state.dimensions,
+        "metric": format!("{:?}", graph.index_store.get_metric()),
+        "entry_points": graph.index_store.get_entry_points(),
+    }))
+}
+
+// node_index resolves the node a request names, which must not be deleted
+fn node_index(graph: &Graph, request: NodeRequest) -> Result<u32, ApiError> {
+    let node_index = match (request.id, request.node) {
+        (Some(id), None) => {
+            let id = ExternalId::from(id);
+            graph
+                .id_map()
+                .node_index(&id)
+                .ok_or_else(|| ApiError::NotFound(format!("no node has id {}", id)))?
+        }
+        (None, Some(node_index)) => node_index,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "exactly one of id and node must be given".to_owned(),
+            ))
+        }
+    };
+    if graph.index_store.is_deleted(node_index) || graph.index_store.get_node(node_index).is_err() {
+        return Err(ApiError::NotFound(format!("no node {}", node_index)));
+    }
+    Ok(node_index)
+}
+
+fn hit_json(hit: SearchHit) -> Value {
+    json!({
+        "node": hit.id,
+        "id": hit.external_id.as_ref().map(id_json),
+        "distance": hit.distance,
+        "data": hit.data.map(|data| String::from_utf8_lossy(&data).into_owned()),
+    })
+}
+
+fn id_json(id: &ExternalId) -> Value {
+    match id {
+        ExternalId::U64(id) => json!(id),
+        ExternalId::String(id) => json!(id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vdb::{vector::generate_random_vectors, InMemStorage, Metric};
+
+    fn new_state() -> State {
+        let mut graph = Graph::new(
+            vec![generate_random_vectors(200, &(0.0..2000.0), 2)].into_iter(),
+            2,
+            5,
+            Metric::L2,
+            Box::new(InMemStorage::default()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.0, 10).unwrap();
+        State::new(graph, 2).unwrap()
+    }
+
+    fn post(state: &State, url: &str, body: Value) -> (u16, Value) {
+        handle(state, &Method::Post, url, body.to_string().as_bytes())
+    }
+
+    #[test]
+    fn test_insert_search_get_and_delete() {
+        let state = new_state();
+
+        let (status, inserted) = post(
+            &state,
+            "/insert",
+            json!({ "id": "far", "vector": [5000.0, 5000.0], "data": "{\"title\": \"far\"}" }),
+        );
+        assert_eq!(200, status);
+
+        let (status, found) = post(
+            &state,
+            "/search",
+            json!({ "vector": [5000.0, 5000.0], "k": 3 }),
+        );
+        assert_eq!(200, status);
+        assert_eq!(3, found["hits"].as_array().unwrap().len());
+        assert_eq!(inserted["node"], found["hits"][0]["node"]);
+        assert_eq!("far", found["hits"][0]["id"]);
+        assert_eq!("{\"title\": \"far\"}", found["hits"][0]["data"]);
+
+        let (status, node) = post(&state, "/get", json!({ "id": "far" }));
+        assert_eq!(200, status);
+        assert_eq!(json!([5000.0, 5000.0]), node["vector"]);
+
+        // inserting under the same id replaces the node
+        post(
+            &state,
+            "/insert",
+            json!({ "id": "far", "vector": [6000.0, 6000.0] }),
+        );
+        let (_, node) = post(&state, "/get", json!({ "id": "far" }));
+        assert_eq!(json!([6000.0, 6000.0]), node["vector"]);
+        let (status, _) = post(&state, "/get", json!({ "node": inserted["node"] }));
+        assert_eq!(404, status);
+
+        let (status, _) = post(&state, "/delete", json!({ "id": "far" }));
+        assert_eq!(200, status);
+        let (status, _) = post(&state, "/get", json!({ "id": "far" }));
+        assert_eq!(404, status);
+
+        let (status, stats) = handle(&state, &Method::Get, "/stats", &[]);
+        assert_eq!(200, status);
+        assert_eq!(200, stats["nodes"]);
+        assert_eq!(2, stats["deleted"]);
+        let (_, consolidated) = post(&state, "/consolidate", json!({}));
+        assert_eq!(2, consolidated["removed"]);
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let state = new_state();
+        let cases = [
+            ("/search", json!({ "vector": [1.0], "k": 3 }), 400),
+            ("/search", json!({ "vector": [1.0, 2.0] }), 400),
+            ("/search", json!({ "vector": [1.0, 2.0], "k": 0 }), 400),
+            (
+                "/search",
+                json!({ "vector": [1.0, 2.0], "k": 5, "l": 3 }),
+                400,
+            ),
+            ("/insert", json!({ "vector": [1.0, 2.0], "l": 0 }), 400),
+            ("/get", json!({ "id": 7, "node": 7 }), 400),
+            ("/get", json!({ "id": 7 }), 404),
+            ("/get", json!({ "node": 1000 }), 404),
+            ("/unknown", json!({}), 404),
+        ];
+        for (url, body, expected) in cases {
+            let (status, response) = post(&state, url, body);
+            assert_eq!(expected, status, "{} {}", url, response);
+            assert!(response["error"].is_string());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_searches_and_inserts() {
+        let state = new_state();
+        thread::scope(|scope| {
+            for i in 0..4 {
+                let state = &state;
+                scope.spawn(move || {
+                    for j in 0..10 {
+                        let vector = json!([i as f32 * 100.0, j as f32 * 100.0]);
+                        if i == 0 {
+                            let (status, _) =
+                                post(state, "/insert", json!({ "id": j, "vector": vector }));
+                            assert_eq!(200, status);
+                        } else {
+                            let (status, _) =
+                                post(state, "/search", json!({ "vector": vector, "k": 5 }));
+                            assert_eq!(200, status);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(10, state.graph.read().unwrap().id_map().len());
+    }
+}
@@ -1,4 +1,7 @@
+use std::{path::PathBuf, time::Duration};
+
 use clap::{Parser, ValueEnum};
+use vdb::{Backpressure, FreshDiskConfig};
 
 /// Run toy implementation of DiskANN
 #[derive(Parser)]
@@ -11,6 +14,98 @@ pub(crate) struct Args {
     /// Type of dataset to run test with
     #[arg(value_enum)]
     pub(crate) dataset: Dataset,
+
+    /// Distance metric to build and query the index with
+    #[arg(long, value_enum, default_value = "l2")]
+    pub(crate) metric: Metric,
+
+    /// Number of threads to build the index with
+    #[arg(long, default_value_t = 1)]
+    pub(crate) threads: usize,
+
+    /// Search list size (L) of the searches that connect nodes while building the index
+    #[arg(long, default_value_t = 10)]
+    pub(crate) build_search_list_size: usize,
+
+    /// Number of nodes expanded per round of a search, with their records read in one batch
+    #[arg(long, default_value_t = 1)]
+    pub(crate) beam_width: usize,
+
+    /// Read the pure disk index through a memory map instead of a read per node
+    #[arg(long)]
+    pub(crate) mmap: bool,
+
+    /// Index only this many dbpedia files up front, then stream the rest into the built index in batches
+    #[arg(long)]
+    pub(crate) initial_files: Option<usize>,
+
+    /// Keep product quantization codes of this many subspaces in RAM, and search with them
+    #[arg(long)]
+    pub(crate) pq_subspaces: Option<usize>,
+
+    /// Report recall@k, latency and nodes visited of the built index against an exact search
+    #[arg(long)]
+    pub(crate) eval: bool,
+
+    /// Number of random indexed vectors to evaluate with
+    #[arg(long, default_value_t = 100)]
+    pub(crate) eval_queries: usize,
+
+    /// Number of nearest neighbours to evaluate recall for
+    #[arg(long, default_value_t = 10)]
+    pub(crate) eval_k: usize,
+
+    /// Comma separated search list sizes (L) to evaluate
+    #[arg(long, value_delimiter = ',', default_value = "10,20,50,100")]
+    pub(crate) eval_search_list_sizes: Vec<usize>,
+
+    /// Base vectors of the files dataset, in .fvecs, .bvecs or .fbin format
+    #[arg(long, required_if_eq("dataset", "files"))]
+    pub(crate) base: Option<PathBuf>,
+
+    /// Query vectors of the files dataset to evaluate with, instead of random indexed vectors
+    #[arg(long, requires = "base")]
+    pub(crate) queries: Option<PathBuf>,
+
+    /// Nearest neighbour ids of the queries, in .ivecs or .ibin format. Computed by brute force when missing
+    #[arg(long, requires = "queries")]
+    pub(crate) ground_truth: Option<PathBuf>,
+
+    /// Number of updates the fresh disk temp index holds before it is frozen and merged into the long term index
+    #[arg(long, default_value_t = 10000)]
+    pub(crate) segment_entries: usize,
+
+    /// Size in MiB the fresh disk temp index grows to before it is frozen and merged into the long term index
+    #[arg(long, default_value_t = 256)]
+    pub(crate) segment_mib: usize,
+
+    /// Age in seconds of its oldest update at which the fresh disk temp index is merged anyway. 0 disables it
+    #[arg(long, default_value_t = 60)]
+    pub(crate) segment_max_age_secs: u64,
+
+    /// Number of frozen fresh disk temp indexes that may wait to be merged before writes wait for them
+    #[arg(long, default_value_t = 4)]
+    pub(crate) max_frozen_segments: usize,
+
+    /// Limit the fresh disk merges to writing this many MiB per second, leaving the disk to searches
+    #[arg(long)]
+    pub(crate) flush_mib_per_sec: Option<u64>,
+}
+
+impl Args {
+    pub(crate) fn fresh_disk_config(&self) -> FreshDiskConfig {
+        FreshDiskConfig {
+            max_segment_entries: self.segment_entries,
+            max_segment_bytes: self.segment_mib << 20,
+            max_segment_age: (self.segment_max_age_secs > 0)
+                .then(|| Duration::from_secs(self.segment_max_age_secs)),
+            backpressure: Backpressure {
+                max_frozen_segments: self.max_frozen_segments,
+                ..Default::default()
+            },
+            max_flush_bytes_per_sec: self.flush_mib_per_sec.map(|mib| mib << 20),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
@@ -21,6 +116,8 @@ pub(crate) enum Storage {
     PureDisk = 1,
     /// FreshDiskANN
     FreshDisk = 2,
+    /// Pure disk, with vectors quantized to i8
+    PureDiskInt8 = 3,
 }
 
 #[derive(Copy, Clone, ValueEnum)]
@@ -29,4 +126,26 @@ pub(crate) enum Dataset {
     Dbpedia,
     /// randomly generated 2 thousand vectors of 2 dimensions. Visual graphs plotted under static/${date}
     Debug,
+    /// vectors read from --base, such as SIFT or GIST
+    Files,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+pub(crate) enum Metric {
+    /// Squared euclidean distance
+    L2,
+    /// Cosine distance, suited for OpenAI embeddings
+    Cosine,
+    /// Negated inner product
+    InnerProduct,
+}
+
+impl From<Metric> for vdb::Metric {
+    fn from(metric: Metric) -> Self {
+        match metric {
+            Metric::L2 => vdb::Metric::L2,
+            Metric::Cosine => vdb::Metric::Cosine,
+            Metric::InnerProduct => vdb::Metric::InnerProduct,
+        }
+    }
 }
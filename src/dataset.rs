@@ -0,0 +1,338 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    path::Path,
+};
+
+use crate::prelude::*;
+
+// readers for the standard ANN benchmark formats. All values are little endian
+//
+// TEXMEX .fvecs, .ivecs and .bvecs files hold one record per vector:
+// [dimensions][values                ]
+// [i32       ][f32 | i32 | u8 * dims ]
+//
+// big-ann .fbin and .ibin files hold a header, then all vectors back to back:
+// [vector count][dimensions][values                    ]
+// [u32         ][u32       ][f32 | u32 * count * dims  ]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorFormat {
+    Fvecs,
+    Ivecs,
+    Bvecs,
+    Fbin,
+    Ibin,
+}
+
+impl VectorFormat {
+    // from_path picks the format from the file extension
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+        match extension {
+            "fvecs" => Ok(VectorFormat::Fvecs),
+            "ivecs" => Ok(VectorFormat::Ivecs),
+            "bvecs" => Ok(VectorFormat::Bvecs),
+            "fbin" => Ok(VectorFormat::Fbin),
+            "ibin" => Ok(VectorFormat::Ibin),
+            _ => Err(Error::InvalidInput(format!(
+                "unknown vector file format {}",
+                path.display()
+            ))),
+        }
+    }
+
+    fn value_size(&self) -> usize {
+        match self {
+            VectorFormat::Bvecs => 1,
+            _ => 4,
+        }
+    }
+
+    fn has_header(&self) -> bool {
+        matches!(self, VectorFormat::Fbin | VectorFormat::Ibin)
+    }
+}
+
+// VectorFile reads the vectors of a file one at a time, so that base sets larger than memory can be indexed
+pub struct VectorFile {
+    reader: BufReader<File>,
+    format: VectorFormat,
+    dimensions: usize,
+    // vectors left to read, only known upfront for .fbin and .ibin
+    remaining: Option<usize>,
+}
+
+impl VectorFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = VectorFormat::from_path(path)?;
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let (dimensions, remaining) = if format.has_header() {
+            let count = read_u32(&mut reader)? as usize;
+            (read_u32(&mut reader)? as usize, Some(count))
+        } else {
+            // every record repeats the dimensions, peek at the first one
+            let mut header = [0u8; 4];
+            match reader.read_exact(&mut header) {
+                Ok(()) => (u32::from_le_bytes(header) as usize, None),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => (0, Some(0)),
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        Ok(VectorFile {
+            reader,
+            format,
+            dimensions,
+            remaining,
+        })
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    // read the raw values of the next vector, or None at the end of the file
+    fn read_values(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.remaining {
+            Some(0) => return Ok(None),
+            Some(ref mut remaining) => *remaining -= 1,
+            None => {}
+        }
+
+        let mut values = vec![0u8; self.dimensions * self.format.value_size()];
+        self.reader.read_exact(&mut values)?;
+
+        if !self.format.has_header() {
+            // the next record's dimensions. The first record's were read by open
+            let mut header = [0u8; 4];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) if u32::from_le_bytes(header) as usize == self.dimensions => {}
+                Ok(()) => {
+                    return Err(Error::InvalidInput(format!(
+                        "vector of {} dimensions in a file of {} dimensions",
+                        u32::from_le_bytes(header),
+                        self.dimensions
+                    )))
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => self.remaining = Some(0),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Some(values))
+    }
+
+    // read the next vector, converting its values to f32
+    pub fn read_vector(&mut self) -> Result<Option<Vec<f32>>> {
+        let format = self.format;
+        Ok(self.read_values()?.map(|values| match format {
+            VectorFormat::Bvecs => values.into_iter().map(f32::from).collect(),
+            VectorFormat::Fvecs | VectorFormat::Fbin => values
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+            VectorFormat::Ivecs => values
+                .chunks_exact(4)
+                .map(|x| i32::from_le_bytes(x.try_into().unwrap()) as f32)
+                .collect(),
+            VectorFormat::Ibin => values
+                .chunks_exact(4)
+                .map(|x| u32::from_le_bytes(x.try_into().unwrap()) as f32)
+                .collect(),
+        }))
+    }
+
+    // read the next vector of an .ivecs or .ibin file, such as the nearest neighbour ids of a ground truth file.
+    // .ivecs values are signed, so a negative one is rejected rather than read as a huge id
+    pub fn read_ids(&mut self) -> Result<Option<Vec<u32>>> {
+        let format = self.format;
+        if !matches!(format, VectorFormat::Ivecs | VectorFormat::Ibin) {
+            return Err(Error::InvalidInput(format!(
+                "{:?} files do not hold ids",
+                format
+            )));
+        }
+        let Some(values) = self.read_values()? else {
+            return Ok(None);
+        };
+        values
+            .chunks_exact(4)
+            .map(|x| match format {
+                VectorFormat::Ivecs => {
+                    let id = i32::from_le_bytes(x.try_into().unwrap());
+                    u32::try_from(id)
+                        .map_err(|_| Error::InvalidInput(format!("negative id {}", id)))
+                }
+                _ => Ok(u32::from_le_bytes(x.try_into().unwrap())),
+            })
+            .collect::<Result<Vec<u32>>>()
+            .map(Some)
+    }
+
+    // batches turns the file into the input of Graph::new. The data of every vector is its position in the file,
+    // which is what ground truth files refer to. A failed read ends the batches with its error
+    pub fn batches(
+        self,
+        batch_size: usize,
+    ) -> impl Iterator<Item = Result<Vec<(Vec<f32>, String)>>> {
+        let mut file = Some(self);
+        let mut position = 0;
+        std::iter::from_fn(move || {
+            let reader = file.as_mut()?;
+            let mut batch = Vec::with_capacity(batch_size);
+            while batch.len() < batch_size {
+                match reader.read_vector() {
+                    Ok(Some(vector)) => batch.push((vector, position.to_string())),
+                    Ok(None) => break,
+                    Err(e) => {
+                        file = None;
+                        return Some(Err(e));
+                    }
+                }
+                position += 1;
+            }
+            (!batch.is_empty()).then_some(Ok(batch))
+        })
+    }
+}
+
+// read_vectors reads all vectors of a file, such as a query set
+pub fn read_vectors(path: impl AsRef<Path>) -> Result<Vec<Vec<f32>>> {
+    let mut file = VectorFile::open(path)?;
+    let mut vectors = Vec::new();
+    while let Some(vector) = file.read_vector()? {
+        vectors.push(vector);
+    }
+    Ok(vectors)
+}
+
+// read_ground_truth reads the nearest neighbour ids of every query from an .ivecs or .ibin file, closest first
+pub fn read_ground_truth(path: impl AsRef<Path>) -> Result<Vec<Vec<u32>>> {
+    let mut file = VectorFile::open(path)?;
+    let mut ids = Vec::new();
+    while let Some(neighbours) = file.read_ids()? {
+        ids.push(neighbours);
+    }
+    Ok(ids)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn write_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    // encode vectors in the TEXMEX layout, or the big-ann layout if with_header
+    fn encode<const N: usize>(vectors: &[[[u8; N]; 2]], with_header: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if with_header {
+            bytes.extend_from_slice(&(vectors.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&2u32.to_le_bytes());
+        }
+        for vector in vectors {
+            if !with_header {
+                bytes.extend_from_slice(&2u32.to_le_bytes());
+            }
+            for value in vector {
+                bytes.extend_from_slice(value);
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_float_formats() {
+        let vectors = [
+            [1.0f32.to_le_bytes(), 2.0f32.to_le_bytes()],
+            [3.0f32.to_le_bytes(), 4.5f32.to_le_bytes()],
+        ];
+        let expected = vec![vec![1.0, 2.0], vec![3.0, 4.5]];
+
+        let fvecs = write_file("test_read.fvecs", &encode(&vectors, false));
+        assert_eq!(expected, read_vectors(&fvecs).unwrap());
+        let fbin = write_file("test_read.fbin", &encode(&vectors, true));
+        assert_eq!(expected, read_vectors(&fbin).unwrap());
+
+        let bvecs = write_file(
+            "test_read.bvecs",
+            &encode(&[[[1], [2]], [[3], [255]]], false),
+        );
+        assert_eq!(
+            vec![vec![1.0, 2.0], vec![3.0, 255.0]],
+            read_vectors(&bvecs).unwrap()
+        );
+
+        assert!(read_ground_truth(&fvecs).is_err());
+    }
+
+    #[test]
+    fn test_read_ground_truth() {
+        let ids = [
+            [7u32.to_le_bytes(), 16777217u32.to_le_bytes()],
+            [0u32.to_le_bytes(), 3u32.to_le_bytes()],
+        ];
+        let expected = vec![vec![7, 16777217], vec![0, 3]];
+
+        let ivecs = write_file("test_read.ivecs", &encode(&ids, false));
+        assert_eq!(expected, read_ground_truth(&ivecs).unwrap());
+        let ibin = write_file("test_read.ibin", &encode(&ids, true));
+        assert_eq!(expected, read_ground_truth(&ibin).unwrap());
+
+        // .ivecs values are signed
+        let ivecs = write_file(
+            "test_read_negative.ivecs",
+            &encode(&[[7i32.to_le_bytes(), (-1i32).to_le_bytes()]], false),
+        );
+        assert!(read_ground_truth(&ivecs).is_err());
+        assert_eq!(vec![vec![7.0, -1.0]], read_vectors(&ivecs).unwrap());
+    }
+
+    #[test]
+    fn test_batches() {
+        let vectors: Vec<[[u8; 4]; 2]> = (0..5)
+            .map(|x| [(x as f32).to_le_bytes(), 0f32.to_le_bytes()])
+            .collect();
+        let path = write_file("test_batches.fvecs", &encode(&vectors, false));
+
+        let file = VectorFile::open(&path).unwrap();
+        assert_eq!(2, file.dimensions());
+        let batches: Vec<Vec<(Vec<f32>, String)>> = file.batches(2).collect::<Result<_>>().unwrap();
+
+        assert_eq!(
+            vec![2, 2, 1],
+            batches.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!((vec![4.0, 0.0], "4".to_string()), batches[2][0]);
+
+        // a vector cut short by the end of the file ends the batches with an error
+        let mut bytes = encode(&vectors, false);
+        bytes.truncate(bytes.len() - 2);
+        let path = write_file("test_batches_truncated.fvecs", &bytes);
+        let batches: Vec<_> = VectorFile::open(&path).unwrap().batches(2).collect();
+        assert_eq!(3, batches.len());
+        assert!(batches[..2].iter().all(Result::is_ok));
+        assert!(batches[2].is_err());
+    }
+
+    #[test]
+    fn test_rejects_mismatched_dimensions() {
+        let mut bytes = encode(&[[1.0f32.to_le_bytes(), 2.0f32.to_le_bytes()]], false);
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 12]);
+        let path = write_file("test_mismatched.fvecs", &bytes);
+
+        assert!(read_vectors(&path).is_err());
+    }
+}
@@ -1,37 +1,69 @@
-use vdb::{InMemStorage, IndexStore};
+use vdb::{DataStore, IndexStore, SearchHit};
 
-use crate::{data, MAX_NEIGHBOUR_COUNT};
+use crate::{cli::Args, data, MAX_NEIGHBOUR_COUNT};
 
-// index_dbpedia indexes the dbpedia dataset using index_storage_type. The number of files to read from the dataset can be specified with dataset_files. -1 to load all files (note that this will incur a huge indexing time)
-pub(super) fn index_dbpedia(index_storage: Box<dyn IndexStore>, dataset_files: i64) -> vdb::Graph {
-    let res = data::read_dataset("dataset/dbpedia-entities-openai-1M/data/", dataset_files);
+// number of entities streamed into the index per insert_batch
+const INSERT_BATCH_SIZE: usize = 1000;
+
+// index_dbpedia indexes the dbpedia dataset into index_storage, with the text of every entity in data_storage. The number of files to read from the dataset can be specified with dataset_files. -1 to load all files (note that this will incur a huge indexing time)
+// the index is built with the metric, threads, build search list size and beam width of args.
+// With initial_files, only that many files are indexed up front, and the rest are streamed into the built index with insert_batch
+pub(super) fn index_dbpedia(
+    index_storage: Box<dyn IndexStore>,
+    data_storage: Box<dyn DataStore>,
+    dataset_files: i64,
+    args: &Args,
+) -> vdb::Graph {
+    let (initial_files, search_list_size, thread_count) = (
+        args.initial_files,
+        args.build_search_list_size,
+        args.threads,
+    );
+    let mut res = data::read_dataset("dataset/dbpedia-entities-openai-1M/data/", dataset_files);
     let start = std::time::Instant::now();
     let index_name = index_storage.get_name();
     let mut graph = vdb::graph::Graph::new(
-        res,
+        res.by_ref().take(initial_files.unwrap_or(usize::MAX)),
         5,
         MAX_NEIGHBOUR_COUNT,
+        args.metric.into(),
         index_storage,
-        Box::new(InMemStorage::default()), // TODO: Can provide other implementations
+        data_storage,
     )
     .unwrap();
+    graph.set_beam_width(args.beam_width).unwrap();
     println!("{} graph::new took {:?}", index_name, start.elapsed());
 
     let start = std::time::Instant::now();
-    graph.index(1.0).unwrap();
-    graph.index(1.0).unwrap();
+    for _ in 0..2 {
+        if thread_count > 1 {
+            graph
+                .index_parallel(1.0, search_list_size, thread_count)
+                .unwrap();
+        } else {
+            graph.index(1.0, search_list_size).unwrap();
+        }
+    }
     println!("{} graph::index took {:?}", index_name, start.elapsed());
-    graph
-}
 
-pub(super) fn query_dbpedia_index(graph: &vdb::Graph, query: &[f32], k: usize) -> Vec<String> {
-    let closest_k_nodes = graph.greedy_search_random_start(query, k, 10);
-    let mut result = Vec::with_capacity(closest_k_nodes.0.len());
-    for node_index in closest_k_nodes.0 {
-        let data = graph.data_store.get_data(node_index);
-        if let Some(content) = data {
-            result.push(content);
+    let start = std::time::Instant::now();
+    for batch in res {
+        for chunk in batch.chunks(INSERT_BATCH_SIZE) {
+            graph
+                .insert_batch(chunk.to_vec(), 1.0, search_list_size, thread_count)
+                .unwrap();
         }
     }
-    result
+    if initial_files.is_some() {
+        println!(
+            "{} graph::insert_batch took {:?}",
+            index_name,
+            start.elapsed()
+        );
+    }
+    graph
+}
+
+pub(super) fn query_dbpedia_index(graph: &vdb::Graph, query: &[f32], k: usize) -> Vec<SearchHit> {
+    graph.search(query, k, 10).unwrap()
 }
@@ -1,7 +1,7 @@
 use std::fs;
 
 use chrono::Local;
-use vdb::{vector::generate_random_vectors, InMemStorage, Node};
+use vdb::{vector::generate_random_vectors, FreshDiskConfig, InMemStorage, Metric, Node};
 
 use crate::{new_index_storage, Storage, MAX_NEIGHBOUR_COUNT};
 
@@ -9,18 +9,24 @@ pub(super) fn debug(
     seed_dataset_size: usize,
     vector_value_range: std::ops::Range<f32>,
     storage_type: Storage,
-) {
+    metric: Metric,
+    mmap: bool,
+    fresh_disk_config: FreshDiskConfig,
+) -> vdb::Graph {
     let test_vectors = generate_random_vectors(seed_dataset_size, &vector_value_range, 2);
     let storage = new_index_storage(
         storage_type,
         test_vectors[0].0.len() as u16,
         MAX_NEIGHBOUR_COUNT,
+        mmap,
+        fresh_disk_config,
     );
 
     let mut graph = vdb::graph::Graph::new(
         vec![test_vectors].into_iter(),
         3,
         MAX_NEIGHBOUR_COUNT,
+        metric,
         storage,
         Box::new(InMemStorage::default()),
     )
@@ -31,10 +37,10 @@ pub(super) fn debug(
     let nodes = graph.index_store.get_all_nodes().unwrap();
     plotter.set_connected_nodes(&nodes);
 
-    let (closests, _) = graph.greedy_search(1, &[1000.0f32, 1000.0f32], 3, 10);
+    let closests = graph.search(&[1000.0f32, 1000.0f32], 3, 10).unwrap();
     let closest_nodes: Vec<Node> = closests
         .iter()
-        .filter_map(|&id| graph.index_store.get_node(id).ok())
+        .filter_map(|hit| graph.index_store.get_node(hit.id).ok())
         .collect();
     plotter.set_isolated_nodes(&closest_nodes);
 
@@ -45,11 +51,11 @@ pub(super) fn debug(
         .unwrap();
 
     // plot alpha=1.0
-    graph.index(1.0).unwrap();
-    let (closests, _) = graph.greedy_search(1, &[1000.0f32, 1000.0f32], 3, 10);
+    graph.index(1.0, 10).unwrap();
+    let closests = graph.search(&[1000.0f32, 1000.0f32], 3, 10).unwrap();
     let closest_nodes: Vec<Node> = closests
         .iter()
-        .filter_map(|&id| graph.index_store.get_node(id).ok())
+        .filter_map(|hit| graph.index_store.get_node(hit.id).ok())
         .collect();
     plotter.set_isolated_nodes(&closest_nodes);
     plotter.set_connected_nodes(&graph.index_store.get_all_nodes().unwrap());
@@ -58,11 +64,11 @@ pub(super) fn debug(
         .unwrap();
 
     // alpha=1.0
-    graph.index(1.0).unwrap();
-    let (closests, _) = graph.greedy_search(1, &[1000.0f32, 1000.0f32], 3, 10);
+    graph.index(1.0, 10).unwrap();
+    let closests = graph.search(&[1000.0f32, 1000.0f32], 3, 10).unwrap();
     let closest_nodes: Vec<Node> = closests
         .iter()
-        .filter_map(|&id| graph.index_store.get_node(id).ok())
+        .filter_map(|hit| graph.index_store.get_node(hit.id).ok())
         .collect();
     plotter.set_isolated_nodes(&closest_nodes);
     plotter.set_connected_nodes(&graph.index_store.get_all_nodes().unwrap());
@@ -72,11 +78,12 @@ pub(super) fn debug(
 
     // insert new node
     let inserted_node = graph
-        .insert(vec![1000.0, 1000.0], "".to_string(), 1, 1.0, 10)
+        .insert(vec![1000.0, 1000.0], b"", &[], 1.0, 10)
         .unwrap();
     plotter.set_connected_nodes(&graph.index_store.get_all_nodes().unwrap());
     plotter.set_isolated_nodes(&vec![inserted_node]);
     plotter
         .plot(&format!("{}/graph-3.png", path), "inserted")
         .unwrap();
+    graph
 }
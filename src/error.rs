@@ -2,6 +2,9 @@
 pub enum Error {
     #[error("invalid input: `{0}`")]
     InvalidInput(String),
+    // the write can't be taken until the index store catches up, it may be retried later
+    #[error("busy: {0}")]
+    Busy(String),
     #[error(transparent)]
     IO(#[from] std::io::Error),
     #[error(transparent)]
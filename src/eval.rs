@@ -0,0 +1,268 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use crate::{prelude::*, Graph, IndexStore, Metric};
+
+// EvalReport summarises how a graph did on a query set for one search_list_size
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalReport {
+    pub search_list_size: usize,
+    // fraction of the true k nearest neighbours that were returned
+    pub recall: f64,
+    pub mean_latency: Duration,
+    pub p99_latency: Duration,
+    // average number of nodes visited per query
+    pub mean_visited: f64,
+}
+
+// brute_force_knn returns the exact k nearest non-deleted nodes of every query, closest first, by scanning the whole index store.
+// It is meant as the ground truth for evaluate, not for serving queries
+pub fn brute_force_knn(
+    index_store: &dyn IndexStore,
+    metric: Metric,
+    queries: &[Vec<f32>],
+    k: usize,
+) -> Result<Vec<Vec<u32>>> {
+    brute_force_knn_excluding(index_store, metric, queries, &vec![None; queries.len()], k)
+}
+
+// brute_force_knn_excluding is brute_force_knn where the node excluded next to a query is never one of its neighbours
+fn brute_force_knn_excluding(
+    index_store: &dyn IndexStore,
+    metric: Metric,
+    queries: &[Vec<f32>],
+    excluded: &[Option<u32>],
+    k: usize,
+) -> Result<Vec<Vec<u32>>> {
+    let nodes: Vec<(u32, Vec<f32>)> = index_store
+        .get_all_nodes()?
+        .into_iter()
+        .filter(|(node_index, _)| !index_store.is_deleted(*node_index))
+        .map(|(node_index, node)| (node_index, node.vector))
+        .collect();
+
+    Ok(queries
+        .iter()
+        .zip(excluded)
+        .map(|(query, excluded)| {
+            let mut distances: Vec<(i64, u32)> = nodes
+                .iter()
+                .filter(|(node_index, _)| Some(*node_index) != *excluded)
+                .map(|(node_index, vector)| (metric.distance(query, vector), *node_index))
+                .collect();
+            distances.sort_unstable();
+            distances
+                .into_iter()
+                .take(k)
+                .map(|(_, node_index)| node_index)
+                .collect()
+        })
+        .collect())
+}
+
+// evaluate runs every query against the graph once per search_list_size, and compares the k results with ground_truth.
+// ground_truth holds the true nearest neighbours of each query, closest first. Only the first k of each are used
+pub fn evaluate(
+    graph: &Graph,
+    queries: &[Vec<f32>],
+    ground_truth: &[Vec<u32>],
+    k: usize,
+    search_list_sizes: &[usize],
+) -> Result<Vec<EvalReport>> {
+    let excluded = vec![None; queries.len()];
+    evaluate_excluding(
+        graph,
+        queries,
+        ground_truth,
+        &excluded,
+        k,
+        search_list_sizes,
+    )
+}
+
+// evaluate_held_out is evaluate for queries taken from the graph, the vectors of query_node_indexes. A query would find
+// its own node first, so each query node is held out: it is left out of the query's brute force ground truth and of its
+// search results. It still takes a place in the search list, so searches keep one more candidate than search_list_size
+pub fn evaluate_held_out(
+    graph: &Graph,
+    query_node_indexes: &[u32],
+    k: usize,
+    search_list_sizes: &[usize],
+) -> Result<Vec<EvalReport>> {
+    let queries = query_node_indexes
+        .iter()
+        .map(|node_index| Ok(graph.index_store.get_node(*node_index)?.vector))
+        .collect::<Result<Vec<Vec<f32>>>>()?;
+    let excluded: Vec<Option<u32>> = query_node_indexes.iter().copied().map(Some).collect();
+    let ground_truth = brute_force_knn_excluding(
+        &*graph.index_store,
+        graph.index_store.get_metric(),
+        &queries,
+        &excluded,
+        k,
+    )?;
+    evaluate_excluding(
+        graph,
+        &queries,
+        &ground_truth,
+        &excluded,
+        k,
+        search_list_sizes,
+    )
+}
+
+// evaluate_excluding is evaluate where the node excluded next to a query is dropped from its search results
+fn evaluate_excluding(
+    graph: &Graph,
+    queries: &[Vec<f32>],
+    ground_truth: &[Vec<u32>],
+    excluded: &[Option<u32>],
+    k: usize,
+    search_list_sizes: &[usize],
+) -> Result<Vec<EvalReport>> {
+    if queries.is_empty() || queries.len() != ground_truth.len() {
+        return Err(Error::InvalidInput(format!(
+            "expected ground truth for each of the {} queries, got {}",
+            queries.len(),
+            ground_truth.len()
+        )));
+    }
+
+    let start_node_indexes = graph.start_node_indexes();
+    let mut reports = Vec::new();
+    for &search_list_size in search_list_sizes {
+        if search_list_size < k {
+            return Err(Error::InvalidInput(format!(
+                "search_list_size {} is smaller than k {}",
+                search_list_size, k
+            )));
+        }
+
+        let mut found = 0;
+        let mut expected = 0;
+        let mut visited_count = 0;
+        let mut latencies = Vec::with_capacity(queries.len());
+        for ((query, truth), excluded) in queries.iter().zip(ground_truth).zip(excluded) {
+            let held_out = excluded.is_some() as usize;
+            let start = Instant::now();
+            let (k_closests, visited) = graph.greedy_search(
+                &start_node_indexes,
+                query,
+                &HashSet::new(),
+                k + held_out,
+                search_list_size + held_out,
+            )?;
+            latencies.push(start.elapsed());
+
+            let truth: HashSet<u32> = truth.iter().take(k).copied().collect();
+            found += k_closests
+                .iter()
+                .filter(|(_, node_index)| Some(*node_index) != *excluded)
+                .take(k)
+                .filter(|(_, node_index)| truth.contains(node_index))
+                .count();
+            expected += truth.len();
+            visited_count += visited.len();
+        }
+
+        latencies.sort_unstable();
+        let p99_index = (latencies.len() * 99).div_ceil(100) - 1;
+        reports.push(EvalReport {
+            search_list_size,
+            recall: found as f64 / expected.max(1) as f64,
+            mean_latency: latencies.iter().sum::<Duration>() / latencies.len() as u32,
+            p99_latency: latencies[p99_index],
+            mean_visited: visited_count as f64 / queries.len() as f64,
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{vector::generate_random_vectors, InMemStorage};
+
+    #[test]
+    fn test_brute_force_knn() {
+        let mut index_store = InMemStorage::default();
+        index_store
+            .add_nodes(&[
+                vec![0.0, 0.0],
+                vec![1.0, 0.0],
+                vec![5.0, 0.0],
+                vec![2.0, 0.0],
+            ])
+            .unwrap();
+        index_store.delete_node(1).unwrap();
+
+        let ground_truth = brute_force_knn(&index_store, Metric::L2, &[vec![1.1, 0.0]], 2).unwrap();
+
+        assert_eq!(vec![vec![3, 0]], ground_truth);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let vectors = generate_random_vectors(300, &(0.0..2000.0), 2);
+        let mut graph = Graph::new(
+            vec![vectors].into_iter(),
+            2,
+            5,
+            Metric::L2,
+            Box::new(InMemStorage::default()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.0, 10).unwrap();
+        graph.index(1.0, 10).unwrap();
+
+        let queries: Vec<Vec<f32>> = generate_random_vectors(20, &(0.0..2000.0), 2)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let ground_truth = brute_force_knn(&*graph.index_store, Metric::L2, &queries, 5).unwrap();
+
+        let reports = evaluate(&graph, &queries, &ground_truth, 5, &[5, 300]).unwrap();
+
+        assert_eq!(2, reports.len());
+        assert_eq!(5, reports[0].search_list_size);
+        assert!(reports[0].p99_latency >= reports[0].mean_latency);
+        assert!(reports[0].mean_visited >= 1.0);
+        // a search list as large as the graph visits every reachable node
+        assert!(reports[1].recall >= reports[0].recall);
+        assert!(reports[1].recall > 0.9);
+
+        assert!(evaluate(&graph, &queries, &ground_truth, 5, &[4]).is_err());
+        assert!(evaluate(&graph, &queries, &ground_truth[1..], 5, &[5]).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_held_out() {
+        // a line of nodes 10 apart, where the true neighbours of a node are the nodes next to it
+        let vectors: Vec<(Vec<f32>, String)> = (0..50)
+            .map(|i| (vec![i as f32 * 10.0, 0.0], i.to_string()))
+            .collect();
+        let mut graph = Graph::new(
+            vec![vectors].into_iter(),
+            2,
+            5,
+            Metric::L2,
+            Box::new(InMemStorage::default()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.0, 10).unwrap();
+        graph.index(1.0, 10).unwrap();
+
+        let query_node_indexes: Vec<u32> = (10..20).collect();
+        let reports = evaluate_held_out(&graph, &query_node_indexes, 2, &[2, 50]).unwrap();
+
+        // the node of a query is not its own neighbour, so it doesn't take the place of one in the results
+        assert_eq!(2, reports.len());
+        assert_eq!(1.0, reports[1].recall);
+        assert!(evaluate_held_out(&graph, &query_node_indexes, 2, &[1]).is_err());
+        assert!(evaluate_held_out(&graph, &[], 2, &[2]).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::{collections::HashMap, path::Path};
+
+use vdb::{dataset, dataset::VectorFile, DataStore, Graph, IndexStore};
+
+use crate::{cli::Args, recall_cli, MAX_NEIGHBOUR_COUNT};
+
+// index_files indexes every vector of base with the metric, threads, build search list size and beam width of args.
+// The data of each node is its position in base
+pub(super) fn index_files(
+    base: VectorFile,
+    index_storage: Box<dyn IndexStore>,
+    data_storage: Box<dyn DataStore>,
+    args: &Args,
+) -> Graph {
+    let (search_list_size, thread_count) = (args.build_search_list_size, args.threads);
+    let start = std::time::Instant::now();
+    let index_name = index_storage.get_name();
+    let mut read_error = None;
+    let batches = base
+        .batches(1000)
+        .map_while(|batch| batch.map_err(|e| read_error = Some(e)).ok());
+    let mut graph = Graph::new(
+        batches,
+        3,
+        MAX_NEIGHBOUR_COUNT,
+        args.metric.into(),
+        index_storage,
+        data_storage,
+    )
+    .unwrap();
+    if let Some(e) = read_error {
+        panic!("Failed to read base vectors: {}", e);
+    }
+    graph.set_beam_width(args.beam_width).unwrap();
+    println!("{} graph::new took {:?}", index_name, start.elapsed());
+
+    let start = std::time::Instant::now();
+    for _ in 0..2 {
+        if thread_count > 1 {
+            graph
+                .index_parallel(1.0, search_list_size, thread_count)
+                .unwrap();
+        } else {
+            graph.index(1.0, search_list_size).unwrap();
+        }
+    }
+    println!("{} graph::index took {:?}", index_name, start.elapsed());
+    graph
+}
+
+// evaluate_files evaluates graph with the vectors of queries_path. Ground truth files hold positions in the base file,
+// which are mapped to node indexes through the data of every node
+pub(super) fn evaluate_files(
+    graph: &Graph,
+    queries_path: &Path,
+    ground_truth_path: Option<&Path>,
+    args: &Args,
+) {
+    let queries = dataset::read_vectors(queries_path).expect("Failed to read queries");
+
+    let ground_truth = ground_truth_path.map(|path| {
+        let positions: HashMap<u32, u32> = graph
+            .index_store
+            .get_all_node_indexes()
+            .unwrap()
+            .into_iter()
+            .filter_map(|node_index| {
+                let data = graph.data_store.get_data(node_index).unwrap()?;
+                let position = std::str::from_utf8(&data).ok()?.parse().ok()?;
+                Some((position, node_index))
+            })
+            .collect();
+
+        dataset::read_ground_truth(path)
+            .expect("Failed to read ground truth")
+            .into_iter()
+            .map(|neighbours| {
+                neighbours
+                    .iter()
+                    .filter_map(|position| positions.get(position).copied())
+                    .collect()
+            })
+            .collect()
+    });
+
+    recall_cli::evaluate_queries(graph, &queries, ground_truth, args);
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use rand::{seq::SliceRandom, thread_rng};
+
+use crate::prelude::*;
+
+// Label is what filtered searches match nodes by, such as the id of a category or of a tenant
+pub type Label = u32;
+
+// the start node of a label is picked among a random sample of its nodes, see LabelIndex::update_start_nodes
+const START_NODE_SAMPLE_SIZE: usize = 100;
+
+// label log layout
+// append-only, replayed into memory by LabelIndex::open
+//
+// .labels file:
+// [record][record]...
+//
+// where [record], as in the id log:
+// [payload length][crc32 of payload][payload          ]
+// [u32           ][u32             ][u8 * length      ]
+//
+// where [payload]:
+// [node index][labels       ]
+// [u32       ][u32 * as many]
+//
+// a record replaces the labels of the node, and one without labels removes them. A record that is cut short or fails
+// its checksum is treated as the end of the log
+
+// LabelIndex holds the labels of every node and the start node of every label, in RAM. An index created with new or
+// open also appends every change of labels to a log, and start nodes are picked again when it is reopened.
+// It is the filter-aware part of FilteredVamana, from the Filtered-DiskANN paper: filtered searches start from the
+// start nodes of their labels and only walk through matching nodes, and pruning keeps an edge for every shared label
+#[derive(Debug, Default)]
+pub struct LabelIndex {
+    // nodes without labels are left out, they only match unfiltered searches
+    labels: HashMap<u32, HashSet<Label>>,
+    start_nodes: HashMap<Label, u32>,
+    // None for an index that is only kept in RAM
+    log: Option<BufWriter<File>>,
+}
+
+impl LabelIndex {
+    // create an empty index logged at path, truncating any existing log
+    pub fn new(path: &str) -> Result<Self> {
+        Ok(LabelIndex {
+            log: Some(BufWriter::new(File::create(path)?)),
+            ..Default::default()
+        })
+    }
+
+    // reopen an index previously created with LabelIndex::new. The log is rewritten with only the current labels, which
+    // drops the records of replaced and removed labels as well as a record torn by a crash
+    pub fn open(path: &str) -> Result<Self> {
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+
+        let mut labels = LabelIndex::default();
+        let mut offset = 0;
+        while let Some(header) = buffer.get(offset..offset + 8) {
+            let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let Some(payload) = buffer.get(offset + 8..offset + 8 + length) else {
+                break;
+            };
+            if crc32fast::hash(payload) != checksum || !labels.apply(payload) {
+                break;
+            }
+            offset += 8 + length;
+        }
+        labels.update_start_nodes();
+
+        // the compacted log replaces the old one at once, so a crash leaves either of them intact
+        let compacted_path = PathBuf::from(format!("{}.compact", path));
+        let mut log = BufWriter::new(File::create(&compacted_path)?);
+        for (node_index, node_labels) in labels.labels.iter() {
+            let node_labels: Vec<Label> = node_labels.iter().copied().collect();
+            Self::append(&mut log, &Self::encode(*node_index, &node_labels))?;
+        }
+        log.flush()?;
+        log.get_ref().sync_all()?;
+        fs::rename(&compacted_path, path)?;
+
+        labels.log = Some(BufWriter::new(OpenOptions::new().append(true).open(path)?));
+        Ok(labels)
+    }
+
+    pub(crate) fn labels(&self, node_index: u32) -> HashSet<Label> {
+        self.labels.get(&node_index).cloned().unwrap_or_default()
+    }
+
+    // set_labels replaces the labels of node_index. A node that loses a label it started hands it over to another node
+    pub(crate) fn set_labels(&mut self, node_index: u32, labels: &[Label]) -> Result<()> {
+        let labels: HashSet<Label> = labels.iter().copied().collect();
+        let previous = self.labels.get(&node_index);
+        if previous.map_or(labels.is_empty(), |previous| *previous == labels) {
+            return Ok(());
+        }
+        let started_lost_label = previous.is_some_and(|previous| {
+            previous
+                .difference(&labels)
+                .any(|label| self.start_nodes.get(label) == Some(&node_index))
+        });
+        self.log(&Self::encode(
+            node_index,
+            &Vec::from_iter(labels.iter().copied()),
+        ))?;
+
+        // the first node of a label starts its searches until update_start_nodes is called
+        for label in labels.iter() {
+            self.start_nodes.entry(*label).or_insert(node_index);
+        }
+        if labels.is_empty() {
+            self.labels.remove(&node_index);
+        } else {
+            self.labels.insert(node_index, labels);
+        }
+        if started_lost_label {
+            self.update_start_nodes();
+        }
+        Ok(())
+    }
+
+    // remove the labels of node_indexes, and pick new start nodes for the labels they started
+    pub(crate) fn remove(&mut self, node_indexes: &HashSet<u32>) -> Result<()> {
+        for node_index in node_indexes {
+            if self.labels.remove(node_index).is_some() {
+                self.log(&Self::encode(*node_index, &[]))?;
+            }
+        }
+        if self
+            .start_nodes
+            .values()
+            .any(|start_node| node_indexes.contains(start_node))
+        {
+            self.update_start_nodes();
+        }
+        Ok(())
+    }
+
+    // carry_over sets the labels of every node of other, such as the labels given to Graph::new, and takes over the
+    // start nodes of their labels
+    pub(crate) fn carry_over(&mut self, other: LabelIndex) -> Result<()> {
+        for (node_index, labels) in other.labels.iter() {
+            self.set_labels(*node_index, &Vec::from_iter(labels.iter().copied()))?;
+        }
+        for (label, start_node) in other.start_nodes {
+            self.start_nodes.insert(label, start_node);
+        }
+        Ok(())
+    }
+
+    // matches tells if node_index carries any label of filter. Every node matches an empty filter
+    pub(crate) fn matches(&self, node_index: u32, filter: &HashSet<Label>) -> bool {
+        filter.is_empty()
+            || self
+                .labels
+                .get(&node_index)
+                .is_some_and(|labels| !labels.is_disjoint(filter))
+    }
+
+    // start_nodes returns where a search with filter starts: the start nodes of the labels of filter, or entry_points
+    // and the start nodes of every label when filter is empty. FilteredVamana only connects nodes that share a label,
+    // so unfiltered searches start from every label to reach nodes of all labels
+    pub(crate) fn start_nodes(&self, filter: &HashSet<Label>, entry_points: &[u32]) -> Vec<u32> {
+        let mut start_nodes: Vec<u32> = if filter.is_empty() {
+            entry_points
+                .iter()
+                .chain(self.start_nodes.values())
+                .copied()
+                .collect()
+        } else {
+            filter
+                .iter()
+                .filter_map(|label| self.start_nodes.get(label))
+                .copied()
+                .collect()
+        };
+        start_nodes.sort_unstable();
+        start_nodes.dedup();
+        start_nodes
+    }
+
+    // covers tells if via carries every label that p and candidate share. FilteredRobustPrune only drops the edge
+    // from p to candidate in favour of via when it does, so that filtered searches of those labels can still get through
+    pub(crate) fn covers(&self, p: u32, candidate: u32, via: u32) -> bool {
+        let (Some(p_labels), Some(candidate_labels)) =
+            (self.labels.get(&p), self.labels.get(&candidate))
+        else {
+            return true;
+        };
+        let via_labels = self.labels.get(&via);
+        p_labels
+            .intersection(candidate_labels)
+            .all(|label| via_labels.is_some_and(|via_labels| via_labels.contains(label)))
+    }
+
+    // update_start_nodes picks the start node of every label like FindMedoid of the paper: out of a random sample of
+    // the label's nodes, the node that already starts the fewest labels, so that searches of different labels spread out
+    pub(crate) fn update_start_nodes(&mut self) {
+        let mut nodes_by_label: HashMap<Label, Vec<u32>> = HashMap::new();
+        for (node_index, labels) in self.labels.iter() {
+            for label in labels {
+                nodes_by_label.entry(*label).or_default().push(*node_index);
+            }
+        }
+
+        // labels with the fewest nodes pick first, as they have the fewest nodes to pick from
+        let mut nodes_by_label: Vec<(Label, Vec<u32>)> = nodes_by_label.into_iter().collect();
+        nodes_by_label.sort_unstable_by_key(|(label, node_indexes)| (node_indexes.len(), *label));
+
+        let mut load: HashMap<u32, usize> = HashMap::new();
+        self.start_nodes.clear();
+        for (label, node_indexes) in nodes_by_label {
+            let start_node = *node_indexes
+                .choose_multiple(&mut thread_rng(), START_NODE_SAMPLE_SIZE)
+                .min_by_key(|node_index| load.get(node_index).copied().unwrap_or(0))
+                .unwrap();
+            *load.entry(start_node).or_default() += 1;
+            self.start_nodes.insert(label, start_node);
+        }
+    }
+
+    // sync fsyncs the log, if any, so that the changes so far survive a crash of the machine
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        if let Some(log) = &mut self.log {
+            log.flush()?;
+            log.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    // append payload to the log, if any, and hand it to the OS so that it survives a crash of the process
+    fn log(&mut self, payload: &[u8]) -> Result<()> {
+        if let Some(log) = &mut self.log {
+            Self::append(log, payload)?;
+            log.flush()?;
+        }
+        Ok(())
+    }
+
+    fn append(log: &mut BufWriter<File>, payload: &[u8]) -> Result<()> {
+        log.write_all(&(payload.len() as u32).to_be_bytes())?;
+        log.write_all(&crc32fast::hash(payload).to_be_bytes())?;
+        log.write_all(payload)?;
+        Ok(())
+    }
+
+    fn encode(node_index: u32, labels: &[Label]) -> Vec<u8> {
+        let mut payload = node_index.to_be_bytes().to_vec();
+        for label in labels {
+            payload.extend_from_slice(&label.to_be_bytes());
+        }
+        payload
+    }
+
+    // apply a replayed record to the index, returning false if it can't be decoded
+    fn apply(&mut self, payload: &[u8]) -> bool {
+        if payload.len() < 4 || !payload.len().is_multiple_of(4) {
+            return false;
+        }
+        let mut words = payload
+            .chunks_exact(4)
+            .map(|word| u32::from_be_bytes(word.try_into().unwrap()));
+        let node_index = words.next().unwrap();
+        let labels: Vec<Label> = words.collect();
+        // the index has no log yet, so this can't fail
+        let _ = self.set_labels(node_index, &labels);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_matches_and_start_nodes() {
+        let mut label_index = LabelIndex::default();
+        label_index.set_labels(1, &[10]).unwrap();
+        label_index.set_labels(2, &[10, 20]).unwrap();
+        label_index.set_labels(3, &[]).unwrap();
+
+        assert!(label_index.matches(1, &HashSet::from([10, 30])));
+        assert!(!label_index.matches(1, &HashSet::from([20])));
+        assert!(!label_index.matches(3, &HashSet::from([10])));
+        assert!(label_index.matches(3, &HashSet::new()));
+
+        // the first node of a label starts it
+        assert_eq!(vec![1], label_index.start_nodes(&HashSet::from([10]), &[3]));
+        assert_eq!(vec![2], label_index.start_nodes(&HashSet::from([20]), &[3]));
+        assert!(label_index
+            .start_nodes(&HashSet::from([30]), &[3])
+            .is_empty());
+        assert_eq!(
+            vec![1, 2, 3],
+            label_index.start_nodes(&HashSet::new(), &[3])
+        );
+
+        // start nodes are spread out over nodes when they can be
+        label_index.update_start_nodes();
+        assert_eq!(
+            vec![1, 2],
+            label_index.start_nodes(&HashSet::from([10, 20]), &[])
+        );
+
+        label_index.remove(&HashSet::from([2])).unwrap();
+        assert!(label_index
+            .start_nodes(&HashSet::from([20]), &[])
+            .is_empty());
+        assert_eq!(vec![1], label_index.start_nodes(&HashSet::from([10]), &[]));
+    }
+
+    #[test]
+    fn test_start_node_losing_its_labels() {
+        let mut label_index = LabelIndex::default();
+        label_index.set_labels(1, &[10, 20]).unwrap();
+        label_index.set_labels(2, &[10]).unwrap();
+        assert_eq!(vec![1], label_index.start_nodes(&HashSet::from([20]), &[]));
+
+        // 1 no longer carries 20, so searches of 20 don't start from it, and 2 may take over 10
+        label_index.set_labels(1, &[10]).unwrap();
+        assert!(label_index
+            .start_nodes(&HashSet::from([20]), &[])
+            .is_empty());
+        label_index.set_labels(1, &[]).unwrap();
+        assert_eq!(vec![2], label_index.start_nodes(&HashSet::from([10]), &[]));
+        assert!(!label_index.matches(1, &HashSet::from([10])));
+    }
+
+    #[test]
+    fn test_open_existing_label_index() {
+        let path = env::temp_dir().join("test.labels");
+        let path = path.to_str().unwrap();
+
+        let mut label_index = LabelIndex::new(path).unwrap();
+        label_index.set_labels(1, &[10, 20]).unwrap();
+        label_index.set_labels(2, &[10]).unwrap();
+        label_index.set_labels(3, &[30]).unwrap();
+        label_index.set_labels(2, &[20]).unwrap();
+        label_index.remove(&HashSet::from([3])).unwrap();
+        drop(label_index);
+
+        // a torn record at the end of the log is dropped
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[0, 0, 0, 9, 1, 2]).unwrap();
+        drop(file);
+
+        // start nodes are picked again from the replayed labels
+        let mut reopened = LabelIndex::open(path).unwrap();
+        assert_eq!(HashSet::from([10, 20]), reopened.labels(1));
+        assert_eq!(HashSet::from([20]), reopened.labels(2));
+        assert!(reopened.labels(3).is_empty());
+        assert_eq!(vec![1], reopened.start_nodes(&HashSet::from([10]), &[]));
+        assert!(reopened.start_nodes(&HashSet::from([30]), &[]).is_empty());
+
+        // changes after reopening are appended to the compacted log
+        reopened.set_labels(4, &[30]).unwrap();
+        drop(reopened);
+        let reopened = LabelIndex::open(path).unwrap();
+        assert_eq!(vec![4], reopened.start_nodes(&HashSet::from([30]), &[]));
+    }
+
+    #[test]
+    fn test_covers() {
+        let mut label_index = LabelIndex::default();
+        label_index.set_labels(1, &[10, 20]).unwrap();
+        label_index.set_labels(2, &[10, 20]).unwrap();
+        label_index.set_labels(3, &[10]).unwrap();
+        label_index.set_labels(4, &[30]).unwrap();
+
+        // 3 doesn't carry label 20 that 1 and 2 share, so it can't stand in for the edge between them
+        assert!(!label_index.covers(1, 2, 3));
+        assert!(label_index.covers(1, 3, 2));
+        // nothing is shared, or some nodes are unlabelled
+        assert!(label_index.covers(1, 4, 3));
+        assert!(label_index.covers(1, 5, 3));
+    }
+}
@@ -1,37 +1,79 @@
-use crate::storage::IndexStore;
-use crate::{prelude::*, DataStore};
+use crate::filter::{Label, LabelIndex};
+use crate::pq::{DistanceTable, PqIndex, ProductQuantizer};
+use crate::sq::ScalarQuantizer;
+use crate::storage::{ExternalId, IdMap, IndexStore, MAX_ENTRY_POINTS};
+use crate::{metric::DISTANCE_SCALE, prelude::*, DataStore, Metric};
 use rand::{seq::SliceRandom, thread_rng, Rng};
-use simsimd::SpatialSimilarity;
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, HashSet},
+    collections::{BinaryHeap, HashMap, HashSet},
+    thread,
 };
 
+// (distance, node index) of the closest nodes, and all nodes visited by greedy_search
+pub(crate) type GreedySearchResult = (Vec<(i64, u32)>, HashSet<u32>);
+
+// (vectors, data, labels) of the entries Graph::new adds to the stores at once
+type EntryBatch = (Vec<Vec<f32>>, Vec<Vec<u8>>, Vec<Vec<Label>>);
+
 pub struct Graph {
     pub index_store: Box<dyn IndexStore>,
     pub data_store: Box<dyn DataStore>,
     pub(crate) max_neighbour_count: usize,
+    pub(crate) metric: Metric,
+    // searches and inserts start from all entry points, the first being the (approximate) medoid
+    pub(crate) entry_points: Vec<u32>,
+    // product quantization codes of every node, see train_pq
+    pub(crate) pq: Option<PqIndex>,
+    // number of nodes greedy_search expands per round
+    pub(crate) beam_width: usize,
+    // labels of every node, for filtered searches
+    pub(crate) labels: LabelIndex,
+    // external ids of the nodes upserted with one, see set_id_map
+    pub(crate) ids: IdMap,
 }
 
+// the medoid is approximated over a random sample of nodes, so that large disk indexes aren't read in full
+const ENTRY_POINT_SAMPLE_SIZE: usize = 10000;
+
+// product quantizers are trained over a random sample of nodes
+const PQ_TRAINING_SAMPLE_SIZE: usize = 10000;
+const PQ_TRAINING_ITERATIONS: usize = 10;
+
+// index_parallel batches never exceed this fraction of the graph, as nodes of the same batch cannot see each other
+const PARALLEL_MAX_BATCH_FRACTION: f64 = 0.02;
+
+// search list size of the searches for the nodes that consolidate_deletes left without in-edges, see reconnect
+const RECONNECT_SEARCH_LIST_SIZE: usize = 50;
+
 impl Graph {
-    pub fn new<I>(
+    // new adds every entry of input to index_store, and connects each node to r random nodes. Entries are
+    // (vector, data) or (vector, data, labels) tuples, or Entry
+    pub fn new<I, E>(
         input: I,
         r: usize,
         max_neighbour_count: u8,
+        metric: Metric,
         mut index_store: Box<dyn IndexStore>,
         mut data_store: Box<dyn DataStore>,
     ) -> Result<Self>
     where
-        I: Iterator<Item = Vec<(Vec<f32>, String)>>,
+        I: Iterator<Item = Vec<E>>,
+        E: Into<Entry>,
     {
-        let mut batch_input: (Vec<Vec<f32>>, Vec<String>) = (vec![], vec![]);
+        index_store.set_metric(metric)?;
+
+        let mut batch_input: EntryBatch = (vec![], vec![], vec![]);
         let mut new_nodes: Vec<Node> = Vec::new();
+        let mut labels = LabelIndex::default();
         let batch_size = 1000;
 
         for vecs in input {
             for entry in vecs {
-                batch_input.0.push(entry.0);
-                batch_input.1.push(entry.1);
+                let entry: Entry = entry.into();
+                batch_input.0.push(entry.vector);
+                batch_input.1.push(entry.data);
+                batch_input.2.push(entry.labels);
                 if batch_input.0.len() == batch_size {
                     let batch_indices = index_store.add_nodes(&batch_input.0)?;
                     for (i, &node_index) in batch_indices.iter().enumerate() {
@@ -39,11 +81,14 @@ impl Graph {
                             id: node_index,
                             vector: batch_input.0[i].clone(),
                             connected: HashSet::new(),
+                            quantized: None,
                         });
-                        data_store.add_data(node_index, batch_input.1[i].clone())?;
+                        data_store.add_data(node_index, &batch_input.1[i])?;
+                        labels.set_labels(node_index, &batch_input.2[i])?;
                     }
                     batch_input.0.clear();
                     batch_input.1.clear();
+                    batch_input.2.clear();
                 }
             }
         }
@@ -55,8 +100,10 @@ impl Graph {
                     id: node_index,
                     vector: batch_input.0[i].clone(),
                     connected: HashSet::new(),
+                    quantized: None,
                 });
-                data_store.add_data(node_index, batch_input.1[i].clone())?;
+                data_store.add_data(node_index, &batch_input.1[i])?;
+                labels.set_labels(node_index, &batch_input.2[i])?;
             }
         }
 
@@ -89,132 +136,263 @@ impl Graph {
             index_store,
             data_store,
             max_neighbour_count: max_neighbour_count as usize,
+            metric,
+            entry_points: Vec::new(),
+            pq: None,
+            beam_width: 1,
+            labels,
+            ids: IdMap::default(),
         })
     }
 
-    pub fn greedy_search_random_start(
-        &self,
-        query_node: &[f32],
-        k: usize,
-        search_list_size: usize,
-    ) -> (Vec<u32>, HashSet<u32>) {
-        let all_node_indexes = self.index_store.get_all_node_indexes();
-        let random_index = *all_node_indexes.unwrap().choose(&mut thread_rng()).unwrap();
-        self.greedy_search(random_index, query_node, k, search_list_size)
+    // from_stores rebuilds a graph around stores that already hold an indexed graph (e.g. NaiveDisk::open), without re-adding or re-indexing any node.
+    // The rebuilt graph has no labels until a LabelIndex reopened with LabelIndex::open is passed to set_label_index
+    pub fn from_stores(
+        index_store: Box<dyn IndexStore>,
+        data_store: Box<dyn DataStore>,
+        max_neighbour_count: u8,
+    ) -> Result<Self> {
+        let metric = index_store.get_metric();
+        let entry_points = index_store.get_entry_points();
+        Ok(Graph {
+            index_store,
+            data_store,
+            max_neighbour_count: max_neighbour_count as usize,
+            metric,
+            entry_points,
+            pq: None,
+            beam_width: 1,
+            labels: LabelIndex::default(),
+            ids: IdMap::default(),
+        })
     }
 
-    // returns a tuple (node_indexes, visited) where node_indexes are the indexes of the k closest nodes to query_node and visited is a set of all visited nodes during the search
-    pub fn greedy_search(
-        &self,
-        start_node_index: u32,
-        query_node: &[f32],
-        k: usize,
-        search_list_size: usize,
-    ) -> (Vec<u32>, HashSet<u32>) {
-        let mut closest_l: BinaryHeap<(i64, u32)> = BinaryHeap::new();
-        let mut closest_l_set: HashSet<u32> = HashSet::new();
-
-        let mut visited: HashSet<u32> = HashSet::new();
-        // .0 is the distance from query_node_index, .1 is the index of the node
-        let mut to_visit: BinaryHeap<Reverse<(i64, u32)>> = BinaryHeap::new();
-
-        let start_node = self.index_store.get_node(start_node_index).unwrap();
-        // Initial distance
-        let start_node_distance = euclidean_distance(query_node, &start_node.vector);
-        to_visit.push(Reverse((start_node_distance, start_node_index)));
-        closest_l.push((start_node_distance, start_node_index));
-        closest_l_set.insert(start_node_index);
+    // set_id_map replaces the map of external ids, such as with an IdMap logged next to the index, or one reopened with
+    // IdMap::open for a graph rebuilt with from_stores. Graphs start with an empty map kept in RAM
+    pub fn set_id_map(&mut self, ids: IdMap) {
+        self.ids = ids;
+    }
 
-        while let Some(Reverse((_, visiting))) = to_visit.pop() {
-            visited.insert(visiting);
+    pub fn id_map(&self) -> &IdMap {
+        &self.ids
+    }
 
-            let visiting_node = self.index_store.get_node(visiting).unwrap();
-            for neighbor in &visiting_node.connected {
-                if visited.contains(neighbor) {
-                    continue;
-                }
+    // set_label_index replaces the labels of the nodes, such as with a LabelIndex logged next to the index, or one
+    // reopened with LabelIndex::open for a graph rebuilt with from_stores. The labels the graph already has, such as
+    // those of the entries given to Graph::new, are carried over and logged. Graphs start with labels kept in RAM
+    pub fn set_label_index(&mut self, mut labels: LabelIndex) -> Result<()> {
+        labels.carry_over(std::mem::take(&mut self.labels))?;
+        self.labels = labels;
+        Ok(())
+    }
 
-                let visiting_node_neighbor = self.index_store.get_node(*neighbor).unwrap();
-                let distance_to_q = euclidean_distance(&visiting_node_neighbor.vector, query_node);
+    // update_entry_points picks entry_point_count entry points and persists them in the index store.
+    // The first is the sampled node closest to the centroid of the sample (an approximate medoid), and each next one is the sampled node farthest from the entry points picked so far
+    pub fn update_entry_points(&mut self, entry_point_count: usize) -> Result<()> {
+        if entry_point_count == 0 || entry_point_count > MAX_ENTRY_POINTS {
+            return Err(Error::InvalidInput(format!(
+                "entry point count must be between 1 and {}",
+                MAX_ENTRY_POINTS
+            )));
+        }
 
-                // TODO: Maybe should update neighbour instead of excluding?
-                if !closest_l_set.contains(neighbor) {
-                    closest_l.push((distance_to_q, *neighbor));
-                    closest_l_set.insert(*neighbor);
-                }
-            }
+        let mut node_indexes = self.index_store.get_all_node_indexes()?;
+        node_indexes.retain(|node_index| !self.index_store.is_deleted(*node_index));
+        let sample = node_indexes
+            .choose_multiple(&mut thread_rng(), ENTRY_POINT_SAMPLE_SIZE)
+            .map(|node_index| self.index_store.get_node(*node_index))
+            .collect::<Result<Vec<Node>>>()?;
+        let Some(first) = sample.first() else {
+            return Ok(());
+        };
 
-            // since closest_k is a max heap, we will keep the k closest after popping
-            while closest_l.len() > search_list_size {
-                if let Some((_, node)) = closest_l.pop() {
-                    closest_l_set.remove(&node);
-                }
+        let mut centroid = vec![0f64; first.vector.len()];
+        for node in sample.iter() {
+            for (sum, value) in centroid.iter_mut().zip(node.vector.iter()) {
+                *sum += *value as f64;
             }
-
-            to_visit.clear();
-            closest_l.iter().for_each(|node| {
-                if !visited.contains(&node.1) {
-                    to_visit.push(Reverse(*node));
-                }
-            });
         }
+        let centroid: Vec<f32> = centroid
+            .into_iter()
+            .map(|sum| (sum / sample.len() as f64) as f32)
+            .collect();
 
-        let k_closests: Vec<u32> = closest_l
-            .into_sorted_vec()
+        let medoid = sample
+            .iter()
+            .min_by_key(|node| self.metric.distance(&centroid, &node.vector))
+            .unwrap();
+        let mut entry_points = vec![medoid.id];
+
+        // distance from each sampled node to its closest entry point
+        let mut closest_entry_point_distances: Vec<i64> = sample
             .iter()
-            .take(k)
-            .map(|x| x.1)
+            .map(|node| self.metric.distance(&medoid.vector, &node.vector))
             .collect();
+        while entry_points.len() < entry_point_count.min(sample.len()) {
+            let (farthest, _) = closest_entry_point_distances
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, distance)| **distance)
+                .unwrap();
+            let farthest_node = &sample[farthest];
+            entry_points.push(farthest_node.id);
 
-        return (k_closests, visited);
+            for (distance, node) in closest_entry_point_distances.iter_mut().zip(sample.iter()) {
+                *distance =
+                    (*distance).min(self.metric.distance(&farthest_node.vector, &node.vector));
+            }
+        }
+
+        self.index_store.set_entry_points(&entry_points)?;
+        self.entry_points = entry_points;
+        Ok(())
     }
 
-    pub(super) fn robust_prune(
-        &mut self,
-        p_index: u32,
-        visited: &HashSet<u32>,
-        distance_threshold: f32,
-        degree_bound: usize,
-    ) -> Result<Node> {
-        // add all nodes that was visited to try to reach p (excluding p) into working set
-        let mut working_set = visited.clone();
-        working_set.retain(|x| *x != p_index);
+    // train_pq trains a product quantizer of subspace_count subspaces over a sample of nodes, and keeps the code of every node in RAM.
+    // greedy_search then ranks candidates by their codes instead of reading their vectors, and only reads the vectors of the final search list to rerank it
+    pub fn train_pq(&mut self, subspace_count: usize) -> Result<()> {
+        let mut node_indexes = self.index_store.get_all_node_indexes()?;
+        node_indexes.retain(|node_index| !self.index_store.is_deleted(*node_index));
+        let sample = node_indexes
+            .choose_multiple(&mut thread_rng(), PQ_TRAINING_SAMPLE_SIZE)
+            .map(|node_index| Ok(self.index_store.get_node(*node_index)?.vector))
+            .collect::<Result<Vec<Vec<f32>>>>()?;
 
-        // add all nodes connected to p into working set
-        let p_node = self.index_store.get_node(p_index).unwrap();
+        let quantizer =
+            ProductQuantizer::train(&sample, subspace_count, PQ_TRAINING_ITERATIONS, self.metric)?;
+        let mut codes = HashMap::with_capacity(node_indexes.len());
+        for node_index in node_indexes {
+            let node = self.index_store.get_node(node_index)?;
+            codes.insert(node_index, quantizer.encode(&node.vector)?);
+        }
 
-        working_set.extend(p_node.connected.iter());
+        self.pq = Some(PqIndex { quantizer, codes });
+        Ok(())
+    }
 
-        let mut distance_heap: BinaryHeap<Reverse<(i64, u32)>> = BinaryHeap::new();
-        for node_index in working_set.iter() {
-            let working_set_node = self.index_store.get_node(*node_index).unwrap();
-            let distance_from_p = euclidean_distance(&p_node.vector, &working_set_node.vector);
-            distance_heap.push(Reverse((distance_from_p, *node_index)));
+    // set_beam_width sets how many nodes searches and inserts expand per round. Wider beams read more nodes per batch from the index store, in fewer rounds
+    pub fn set_beam_width(&mut self, beam_width: usize) -> Result<()> {
+        if beam_width == 0 {
+            return Err(Error::InvalidInput(
+                "beam width must be at least 1".to_owned(),
+            ));
         }
+        self.beam_width = beam_width;
+        Ok(())
+    }
 
-        // reset p's connected
-        let mut p_node_connections: HashSet<u32> = HashSet::new();
+    // entry_point_indexes returns the entry points, or a random node if they weren't computed yet
+    fn entry_point_indexes(&self) -> Vec<u32> {
+        if !self.entry_points.is_empty() {
+            return self.entry_points.clone();
+        }
+        self.index_store
+            .get_random_node()
+            .map(|node| vec![node.id])
+            .unwrap_or_default()
+    }
 
-        while let Some(Reverse((_, min_node_index))) = distance_heap.pop() {
-            // add min_node to p_index's connected
-            // note: the reverse connection is added by the caller of this method
-            p_node_connections.insert(min_node_index);
-            if p_node_connections.len() == degree_bound {
-                break;
-            }
+    // start_node_indexes returns where an unfiltered search starts: the entry points, and the start node of every label
+    pub(crate) fn start_node_indexes(&self) -> Vec<u32> {
+        self.labels
+            .start_nodes(&HashSet::new(), &self.entry_point_indexes())
+    }
 
-            let min_node = self.index_store.get_node(min_node_index).unwrap();
+    // search returns the k closest nodes to query, closest first. search_list_size (L) trades latency for accuracy and must be at least k
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<Vec<SearchHit>> {
+        Ok(self.search_with_visited(query, k, search_list_size)?.0)
+    }
 
-            distance_heap.retain(|x| {
-                let comparison_node = self.index_store.get_node(x.0 .1).unwrap();
+    // search_filtered is search restricted to nodes that carry at least one label of filter. An empty filter matches every node
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        filter: &[Label],
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<Vec<SearchHit>> {
+        let filter: HashSet<Label> = filter.iter().copied().collect();
+        Ok(self.search_hits(query, &filter, k, search_list_size)?.0)
+    }
 
-                let distance_to_min_node =
-                    euclidean_distance(&min_node.vector, &comparison_node.vector) as f64;
-                let distance_to_p =
-                    euclidean_distance(&comparison_node.vector, &p_node.vector) as f64;
-                distance_to_min_node * distance_threshold as f64 > distance_to_p
-            });
-        }
+    // search_with_visited is search that also returns every node visited during the search, for diagnostics
+    pub fn search_with_visited(
+        &self,
+        query: &[f32],
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<(Vec<SearchHit>, HashSet<u32>)> {
+        self.search_hits(query, &HashSet::new(), k, search_list_size)
+    }
+
+    fn search_hits(
+        &self,
+        query: &[f32],
+        filter: &HashSet<Label>,
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<(Vec<SearchHit>, HashSet<u32>)> {
+        let (k_closests, visited) = self.filtered_search(query, filter, k, search_list_size)?;
+        let hits = k_closests
+            .into_iter()
+            .map(|(distance, id)| {
+                Ok(SearchHit {
+                    id,
+                    external_id: self.ids.external_id(id).cloned(),
+                    distance: (distance as f64 / DISTANCE_SCALE) as f32,
+                    data: self.data_store.get_data(id)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((hits, visited))
+    }
+
+    // returns a tuple (k_closests, visited) where k_closests are the (distance, index) of the k closest nodes to query_node that match filter, closest first, and visited is a set of all visited nodes during the search
+    pub(crate) fn greedy_search(
+        &self,
+        start_node_indexes: &[u32],
+        query_node: &[f32],
+        filter: &HashSet<Label>,
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<GreedySearchResult> {
+        self.view()
+            .greedy_search(start_node_indexes, query_node, filter, k, search_list_size)
+    }
+
+    // filtered_search is greedy_search from where a search with filter starts, see GraphView::filtered_search
+    pub(crate) fn filtered_search(
+        &self,
+        query_node: &[f32],
+        filter: &HashSet<Label>,
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<GreedySearchResult> {
+        self.view().filtered_search(
+            &self.entry_point_indexes(),
+            query_node,
+            filter,
+            k,
+            search_list_size,
+        )
+    }
+
+    pub(super) fn robust_prune(
+        &mut self,
+        p_index: u32,
+        visited: &HashSet<u32>,
+        distance_threshold: f32,
+        degree_bound: usize,
+    ) -> Result<Node> {
+        let p_node = self.index_store.get_node(p_index)?;
+        let p_node_connections =
+            self.view()
+                .prune(&p_node, visited, distance_threshold, degree_bound)?;
 
         self.index_store
             .set_connections(p_index, &p_node_connections)?;
@@ -223,20 +401,46 @@ impl Graph {
             id: p_node.id,
             vector: p_node.vector,
             connected: p_node_connections,
+            quantized: p_node.quantized,
         })
     }
 
-    pub fn index(&mut self, distance_threshold: f32) -> Result<()> {
-        let start_node = self.index_store.get_random_node().unwrap();
-        let start_node_index = start_node.id;
+    pub(crate) fn view(&self) -> GraphView<'_, dyn IndexStore> {
+        GraphView {
+            index_store: &*self.index_store,
+            metric: self.metric,
+            pq: self.pq.as_ref(),
+            quantizer: self.index_store.get_scalar_quantizer(),
+            beam_width: self.beam_width,
+            labels: &self.labels,
+        }
+    }
+
+    // index builds the graph with Vamana, or FilteredVamana for labelled nodes: a labelled node is searched for from the
+    // start nodes of its labels, through nodes that share one of them. Every node is connected to the candidates of a
+    // search with a list of search_list_size nodes
+    pub fn index(&mut self, distance_threshold: f32, search_list_size: usize) -> Result<()> {
+        if self.entry_points.is_empty() {
+            self.update_entry_points(1)?;
+        }
+        self.labels.update_start_nodes();
 
         let mut node_indices: Vec<u32> = self.index_store.get_all_node_indexes()?;
         let mut rng = thread_rng();
         node_indices.shuffle(&mut rng);
 
         for node_index in node_indices {
+            if self.index_store.is_deleted(node_index) {
+                continue;
+            }
+
             let query_node = self.index_store.get_node(node_index)?;
-            let (_, visited) = self.greedy_search(start_node_index, &query_node.vector, 3, 10);
+            let visited = self.view().candidates(
+                &self.entry_point_indexes(),
+                &query_node.vector,
+                &self.labels.labels(node_index),
+                search_list_size,
+            )?;
 
             let query_node = self.robust_prune(
                 node_index,
@@ -267,18 +471,176 @@ impl Graph {
         Ok(())
     }
 
+    // index_parallel is index spread over thread_count threads. It needs an index store that is thread-safe.
+    // Nodes are connected in batches that double in size up to PARALLEL_MAX_BATCH_FRACTION of the graph, see connect_batches
+    pub fn index_parallel(
+        &mut self,
+        distance_threshold: f32,
+        search_list_size: usize,
+        thread_count: usize,
+    ) -> Result<()> {
+        if thread_count == 0 {
+            return Err(Error::InvalidInput(
+                "thread_count must be at least 1".to_owned(),
+            ));
+        }
+        self.sync_view()?;
+
+        if self.entry_points.is_empty() {
+            self.update_entry_points(1)?;
+        }
+        self.labels.update_start_nodes();
+        let entry_points = self.entry_points.clone();
+
+        let mut node_indices: Vec<u32> = self.index_store.get_all_node_indexes()?;
+        node_indices.retain(|node_index| !self.index_store.is_deleted(*node_index));
+        node_indices.shuffle(&mut thread_rng());
+
+        let max_batch_size =
+            ((node_indices.len() as f64 * PARALLEL_MAX_BATCH_FRACTION) as usize).max(thread_count);
+        self.connect_batches(
+            &node_indices,
+            &entry_points,
+            max_batch_size,
+            distance_threshold,
+            search_list_size,
+            thread_count,
+        )
+    }
+
+    // connect_batches connects node_indexes to the graph in batches that double in size up to max_batch_size. Every node
+    // of a batch searches and prunes against the graph as it was before the batch, then the batch's edges and reverse
+    // edges are written. Searches and prunes run on thread_count threads, which needs a thread-safe index store above 1
+    fn connect_batches(
+        &mut self,
+        node_indexes: &[u32],
+        entry_points: &[u32],
+        max_batch_size: usize,
+        distance_threshold: f32,
+        search_list_size: usize,
+        thread_count: usize,
+    ) -> Result<()> {
+        let degree_bound = self.max_neighbour_count;
+        let mut batch_size = 1;
+        let mut remaining = node_indexes;
+        while !remaining.is_empty() {
+            let (batch, rest) = remaining.split_at(batch_size.min(remaining.len()));
+            remaining = rest;
+            batch_size = (batch_size * 2).min(max_batch_size);
+
+            let out_edges = if thread_count > 1 {
+                let view = self.sync_view()?;
+                parallel_map(batch, thread_count, |node_index| {
+                    view.out_edges(
+                        entry_points,
+                        *node_index,
+                        distance_threshold,
+                        degree_bound,
+                        search_list_size,
+                    )
+                })?
+            } else {
+                let view = self.view();
+                batch
+                    .iter()
+                    .map(|node_index| {
+                        view.out_edges(
+                            entry_points,
+                            *node_index,
+                            distance_threshold,
+                            degree_bound,
+                            search_list_size,
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            };
+
+            let mut in_edges: HashMap<u32, HashSet<u32>> = HashMap::new();
+            for (node_index, connections) in batch.iter().zip(&out_edges) {
+                self.index_store.set_connections(*node_index, connections)?;
+                for connected_node_index in connections {
+                    in_edges
+                        .entry(*connected_node_index)
+                        .or_default()
+                        .insert(*node_index);
+                }
+            }
+
+            let in_edges: Vec<(u32, HashSet<u32>)> = in_edges.into_iter().collect();
+            let reverse_connections = if thread_count > 1 {
+                let view = self.sync_view()?;
+                parallel_map(&in_edges, thread_count, |(node_index, sources)| {
+                    view.reverse_edges(*node_index, sources, distance_threshold, degree_bound)
+                })?
+            } else {
+                let view = self.view();
+                in_edges
+                    .iter()
+                    .map(|(node_index, sources)| {
+                        view.reverse_edges(*node_index, sources, distance_threshold, degree_bound)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            };
+
+            for ((node_index, _), connections) in in_edges.iter().zip(&reverse_connections) {
+                self.index_store.set_connections(*node_index, connections)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync_view(&self) -> Result<GraphView<'_, dyn IndexStore + Sync + '_>> {
+        let index_store = self.index_store.as_sync().ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "{} cannot be indexed in parallel as it is not thread-safe",
+                self.index_store.get_name()
+            ))
+        })?;
+        Ok(GraphView {
+            index_store,
+            metric: self.metric,
+            pq: self.pq.as_ref(),
+            quantizer: index_store.get_scalar_quantizer(),
+            beam_width: self.beam_width,
+            labels: &self.labels,
+        })
+    }
+
+    // insert adds a node carrying labels to the indexed graph. Its first node of a label starts the label's filtered searches
     pub fn insert(
         &mut self,
         insert_vector: Vec<f32>,
-        insert_data: String,
-        start_node_index: u32,
+        insert_data: &[u8],
+        labels: &[Label],
         distance_threshold: f32,
         search_list_size: usize,
     ) -> Result<Node> {
-        let (_, visited) =
-            self.greedy_search(start_node_index, &insert_vector, 1, search_list_size);
+        let filter: HashSet<Label> = labels.iter().copied().collect();
+        let graph_was_empty = self.start_node_indexes().is_empty();
+        let visited = self.view().candidates(
+            &self.entry_point_indexes(),
+            &insert_vector,
+            &filter,
+            search_list_size,
+        )?;
+        let code = self
+            .pq
+            .as_ref()
+            .map(|pq| pq.quantizer.encode(&insert_vector))
+            .transpose()?;
         let new_node_index = self.index_store.add_nodes(&[insert_vector])?[0];
         self.data_store.add_data(new_node_index, insert_data)?;
+        self.labels.set_labels(new_node_index, labels)?;
+        if let (Some(pq), Some(code)) = (&mut self.pq, code) {
+            pq.codes.insert(new_node_index, code);
+        }
+
+        // the first node of an empty graph is the only possible entry point
+        if self.entry_points.is_empty() && graph_was_empty {
+            self.index_store.set_entry_points(&[new_node_index])?;
+            self.entry_points = vec![new_node_index];
+        }
 
         let new_node = self.robust_prune(
             new_node_index,
@@ -307,16 +669,1459 @@ impl Graph {
 
         Ok(new_node)
     }
+
+    // insert_batch adds entries to the indexed graph, such as a batch streamed from a dataset. Their vectors are added to
+    // the index store at once, then they are connected like index_parallel connects nodes: in batches that double in
+    // size, searching and pruning against the graph as it was before each batch, with the reverse edges of a batch
+    // written together. Nodes of a batch don't see each other, so entries should be few next to the graph, or the graph
+    // built with new and index instead. thread_count above 1 needs a thread-safe index store.
+    // It returns the node indexes of entries, in order
+    pub fn insert_batch<E: Into<Entry>>(
+        &mut self,
+        entries: Vec<E>,
+        distance_threshold: f32,
+        search_list_size: usize,
+        thread_count: usize,
+    ) -> Result<Vec<u32>> {
+        if thread_count == 0 {
+            return Err(Error::InvalidInput(
+                "thread_count must be at least 1".to_owned(),
+            ));
+        }
+        if thread_count > 1 {
+            self.sync_view()?;
+        }
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let graph_was_empty = self.start_node_indexes().is_empty();
+        let mut entry_points = self.entry_point_indexes();
+        let entries: Vec<Entry> = entries.into_iter().map(Into::into).collect();
+        let vectors: Vec<Vec<f32>> = entries.iter().map(|entry| entry.vector.clone()).collect();
+        let node_indexes = self.index_store.add_nodes(&vectors)?;
+        for (node_index, entry) in node_indexes.iter().zip(&entries) {
+            self.data_store.add_data(*node_index, &entry.data)?;
+            self.labels.set_labels(*node_index, &entry.labels)?;
+            if let Some(pq) = &mut self.pq {
+                let code = pq.quantizer.encode(&entry.vector)?;
+                pq.codes.insert(*node_index, code);
+            }
+        }
+
+        // the first node of an empty graph is the only possible entry point
+        if self.entry_points.is_empty() && graph_was_empty {
+            self.index_store.set_entry_points(&node_indexes[..1])?;
+            self.entry_points = node_indexes[..1].to_vec();
+            entry_points = self.entry_points.clone();
+        }
+
+        self.connect_batches(
+            &node_indexes,
+            &entry_points,
+            node_indexes.len(),
+            distance_threshold,
+            search_list_size,
+            thread_count,
+        )?;
+        Ok(node_indexes)
+    }
+
+    // delete tombstones the node, so that searches no longer return it. The node is still used for routing until consolidate_deletes is called
+    pub fn delete(&mut self, node_index: u32) -> Result<()> {
+        self.index_store.delete_node(node_index)
+    }
+
+    // upsert inserts a node under an external id. When id already names a node, the new node replaces it: the id moves
+    // to the new node and the old one is deleted
+    pub fn upsert(
+        &mut self,
+        id: impl Into<ExternalId>,
+        insert_vector: Vec<f32>,
+        insert_data: &[u8],
+        labels: &[Label],
+        distance_threshold: f32,
+        search_list_size: usize,
+    ) -> Result<Node> {
+        let id = id.into();
+        let node = self.insert(
+            insert_vector,
+            insert_data,
+            labels,
+            distance_threshold,
+            search_list_size,
+        )?;
+        if let Some(previous) = self.ids.set(id, node.id)? {
+            if !self.index_store.is_deleted(previous) {
+                self.delete(previous)?;
+            }
+        }
+        Ok(node)
+    }
+
+    // delete_by_id unmaps id and deletes its node, see delete
+    pub fn delete_by_id(&mut self, id: &ExternalId) -> Result<()> {
+        let node_index = self
+            .ids
+            .remove(id)?
+            .ok_or_else(|| Error::InvalidInput(format!("no node has id {}", id)))?;
+        self.delete(node_index)
+    }
+
+    // consolidate_deletes removes all deleted nodes from the graph, following Algorithm 4 of the FreshDiskANN paper.
+    // Every node with an edge to a deleted node is reconnected to the deleted node's out-neighbours, then pruned with robust_prune.
+    // Prunes may drop the last in-edge of a node, most often with a distance_threshold of 1.0, so such nodes are then
+    // reconnected to the graph, see reconnect
+    pub fn consolidate_deletes(&mut self, distance_threshold: f32) -> Result<()> {
+        let deleted = self.index_store.get_deleted_node_indexes()?;
+        if deleted.is_empty() {
+            return Ok(());
+        }
+
+        let mut live: Vec<u32> = Vec::new();
+        let mut in_degrees: HashMap<u32, usize> = HashMap::new();
+        for node_index in self.index_store.get_all_node_indexes()? {
+            if deleted.contains(&node_index) {
+                continue;
+            }
+            live.push(node_index);
+
+            let node = self.index_store.get_node(node_index)?;
+            if node.connected.is_disjoint(&deleted) {
+                for neighbour in node.connected.iter() {
+                    *in_degrees.entry(*neighbour).or_default() += 1;
+                }
+                continue;
+            }
+
+            let mut candidates: HashSet<u32> = HashSet::new();
+            for neighbour in node.connected.iter() {
+                if deleted.contains(neighbour) {
+                    let deleted_node = self.index_store.get_node(*neighbour)?;
+                    candidates.extend(deleted_node.connected.difference(&deleted));
+                } else {
+                    candidates.insert(*neighbour);
+                }
+            }
+
+            // robust_prune drops p itself and the deleted nodes from the candidates
+            let node = self.robust_prune(
+                node_index,
+                &candidates,
+                distance_threshold,
+                self.max_neighbour_count,
+            )?;
+            for neighbour in node.connected.iter() {
+                *in_degrees.entry(*neighbour).or_default() += 1;
+            }
+        }
+
+        self.index_store.remove_nodes(&deleted)?;
+        for node_index in deleted.iter() {
+            self.data_store.delete_data(*node_index)?;
+        }
+        self.labels.remove(&deleted)?;
+        self.ids.remove_nodes(&deleted)?;
+        if let Some(pq) = &mut self.pq {
+            pq.codes
+                .retain(|node_index, _| !deleted.contains(node_index));
+        }
+
+        if self
+            .entry_points
+            .iter()
+            .any(|entry_point| deleted.contains(entry_point))
+        {
+            self.update_entry_points(self.entry_points.len())?;
+        }
+
+        for node_index in live {
+            if !in_degrees.contains_key(&node_index) && !self.entry_points.contains(&node_index) {
+                self.reconnect(node_index, &mut in_degrees, distance_threshold)?;
+            }
+        }
+        Ok(())
+    }
+
+    // reconnect gives a node that no other node points to an in-edge, so that searches can reach it again. The edge
+    // comes from the closest node visited by a search for it that has room for it, or keeps it when pruned. Failing
+    // that, the closest node replaces its farthest neighbour with it, as long as that neighbour keeps another in-edge.
+    // Visited nodes are reachable from the start nodes, and so is the node once it is reconnected
+    fn reconnect(
+        &mut self,
+        node_index: u32,
+        in_degrees: &mut HashMap<u32, usize>,
+        distance_threshold: f32,
+    ) -> Result<()> {
+        let node = self.index_store.get_node(node_index)?;
+        let (_, mut candidates) =
+            self.filtered_search(&node.vector, &HashSet::new(), 1, RECONNECT_SEARCH_LIST_SIZE)?;
+        candidates.remove(&node_index);
+        let mut candidates = self.index_store.get_nodes(&Vec::from_iter(candidates))?;
+        candidates.sort_by_key(|candidate| self.metric.distance(&node.vector, &candidate.vector));
+
+        let keeps_in_edge = |in_degrees: &HashMap<u32, usize>, neighbour: &u32| {
+            in_degrees.get(neighbour).copied().unwrap_or_default() > 1
+        };
+        let mut new_connections = None;
+        for candidate in candidates.iter() {
+            if candidate.connected.len() < self.max_neighbour_count {
+                let mut connected = candidate.connected.clone();
+                connected.insert(node_index);
+                new_connections = Some((candidate, connected));
+                break;
+            }
+            let pruned = self.view().prune(
+                candidate,
+                &HashSet::from([node_index]),
+                distance_threshold,
+                self.max_neighbour_count,
+            )?;
+            if pruned.contains(&node_index)
+                && candidate
+                    .connected
+                    .difference(&pruned)
+                    .all(|neighbour| keeps_in_edge(in_degrees, neighbour))
+            {
+                new_connections = Some((candidate, pruned));
+                break;
+            }
+        }
+        if new_connections.is_none() {
+            for candidate in candidates.iter() {
+                let farthest = candidate
+                    .connected
+                    .iter()
+                    .filter(|neighbour| keeps_in_edge(in_degrees, neighbour))
+                    .map(|neighbour| Ok((self.index_store.get_node(*neighbour)?, *neighbour)))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .max_by_key(|(neighbour, _)| {
+                        self.metric.distance(&candidate.vector, &neighbour.vector)
+                    });
+                if let Some((_, farthest)) = farthest {
+                    let mut connected = candidate.connected.clone();
+                    connected.remove(&farthest);
+                    connected.insert(node_index);
+                    new_connections = Some((candidate, connected));
+                    break;
+                }
+            }
+        }
+
+        let Some((candidate, connected)) = new_connections else {
+            return Ok(());
+        };
+        for neighbour in candidate.connected.difference(&connected) {
+            *in_degrees.get_mut(neighbour).unwrap() -= 1;
+        }
+        for neighbour in connected.difference(&candidate.connected) {
+            *in_degrees.entry(*neighbour).or_default() += 1;
+        }
+        self.index_store.set_connections(candidate.id, &connected)
+    }
+
+    // flush blocks until every update to the graph so far is durable in its stores and in the logs of its ids and
+    // labels, such as before the process exits
+    pub fn flush(&mut self) -> Result<()> {
+        self.index_store.flush()?;
+        self.data_store.flush()?;
+        self.ids.sync()?;
+        self.labels.sync()
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Node {
-    pub(crate) id: u32,
-    pub(crate) vector: Vec<f32>,
-    pub(crate) connected: HashSet<u32>,
+// parallel_map applies f to every item on up to thread_count threads, keeping the order of items
+fn parallel_map<T, R, F>(items: &[T], thread_count: usize, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let chunk_size = items.len().div_ceil(thread_count).max(1);
+    thread::scope(|scope| {
+        let f = &f;
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Result<Vec<R>>>()))
+            .collect();
+
+        let mut results = Vec::with_capacity(items.len());
+        for handle in handles {
+            results.extend(handle.join().expect("index thread panicked")?);
+        }
+        Ok(results)
+    })
 }
 
-fn euclidean_distance(a: &[f32], b: &[f32]) -> i64 {
-    let l2sq_dist = f32::l2sq(a, b);
-    return l2sq_dist.unwrap().mul_add(1000000.0, 0.0) as i64;
+// GraphView is the read-only part of the graph that searching and pruning need.
+// Borrowing a thread-safe store through it lets index_parallel run searches and prunes on many threads
+pub(crate) struct GraphView<'a, S: IndexStore + ?Sized> {
+    pub(crate) index_store: &'a S,
+    pub(crate) metric: Metric,
+    pub(crate) pq: Option<&'a PqIndex>,
+    pub(crate) quantizer: Option<ScalarQuantizer>,
+    pub(crate) beam_width: usize,
+    pub(crate) labels: &'a LabelIndex,
+}
+
+// SearchQuery is the query of greedy_search, with what is precomputed from it once per search
+struct SearchQuery<'a> {
+    vector: &'a [f32],
+    quantized: Option<Vec<i8>>,
+    distance_table: Option<DistanceTable>,
+}
+
+impl<S: IndexStore + ?Sized> GraphView<'_, S> {
+    // returns a tuple (k_closests, visited) where k_closests are the (distance, index) of the k closest nodes to query_node, closest first, and visited is a set of all visited nodes during the search.
+    // Every round expands the beam_width closest unvisited nodes of the search list, so that their records and their neighbours' are read in batches.
+    // Only neighbours that match filter are visited, as in FilteredGreedySearch, so start_node_indexes must match it too
+    pub(crate) fn greedy_search(
+        &self,
+        start_node_indexes: &[u32],
+        query_node: &[f32],
+        filter: &HashSet<Label>,
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<GreedySearchResult> {
+        let mut closest_l: BinaryHeap<(i64, u32)> = BinaryHeap::new();
+        let mut closest_l_set: HashSet<u32> = HashSet::new();
+
+        let mut visited: HashSet<u32> = HashSet::new();
+        // records read during the search, kept while their node is in the search list so that expanding it needs no read
+        let mut records: HashMap<u32, Node> = HashMap::new();
+
+        let query = SearchQuery {
+            vector: query_node,
+            quantized: self
+                .quantizer
+                .map(|quantizer| quantizer.quantize(query_node)),
+            distance_table: self.pq.map(|pq| pq.quantizer.distance_table(query_node)),
+        };
+
+        let mut start_node_indexes = start_node_indexes.to_vec();
+        start_node_indexes.dedup();
+        // Initial distance
+        let start_node_distances =
+            self.distances_to_query(&query, &start_node_indexes, &mut records)?;
+        for (distance, start_node_index) in start_node_distances.into_iter().zip(start_node_indexes)
+        {
+            closest_l.push((distance, start_node_index));
+            closest_l_set.insert(start_node_index);
+        }
+
+        loop {
+            let mut frontier: Vec<(i64, u32)> = closest_l
+                .iter()
+                .filter(|node| !visited.contains(&node.1))
+                .copied()
+                .collect();
+            if frontier.is_empty() {
+                break;
+            }
+            frontier.sort_unstable();
+            let frontier: Vec<u32> = frontier
+                .into_iter()
+                .take(self.beam_width.max(1))
+                .map(|node| node.1)
+                .collect();
+            self.read_records(&frontier, &mut records)?;
+
+            let mut neighbors = Vec::new();
+            for visiting in frontier.iter() {
+                visited.insert(*visiting);
+                for neighbor in &records[visiting].connected {
+                    // TODO: Maybe should update neighbour instead of excluding?
+                    if visited.contains(neighbor)
+                        || closest_l_set.contains(neighbor)
+                        || !self.labels.matches(*neighbor, filter)
+                    {
+                        continue;
+                    }
+                    closest_l_set.insert(*neighbor);
+                    neighbors.push(*neighbor);
+                }
+            }
+
+            let distances = self.distances_to_query(&query, &neighbors, &mut records)?;
+            closest_l.extend(distances.into_iter().zip(neighbors));
+
+            // since closest_k is a max heap, we will keep the k closest after popping
+            while closest_l.len() > search_list_size {
+                if let Some((_, node)) = closest_l.pop() {
+                    closest_l_set.remove(&node);
+                    records.remove(&node);
+                }
+            }
+        }
+
+        // deleted nodes are still used for routing, but are never returned
+        let mut k_closests: Vec<(i64, u32)> = closest_l
+            .into_sorted_vec()
+            .into_iter()
+            .filter(|x| !self.index_store.is_deleted(x.1))
+            .collect();
+
+        // codes only approximate distances, rerank the search list with full precision vectors
+        if self.pq.is_some() {
+            let node_indexes: Vec<u32> = k_closests.iter().map(|x| x.1).collect();
+            self.read_records(&node_indexes, &mut records)?;
+            for (distance, node_index) in k_closests.iter_mut() {
+                *distance =
+                    self.distance(query_node, query.quantized.as_deref(), &records[node_index]);
+            }
+            k_closests.sort_unstable();
+        }
+        k_closests.truncate(k);
+
+        Ok((k_closests, visited))
+    }
+
+    // filtered_search is greedy_search from the start nodes of filter, or from entry_points and every label's start node
+    // when filter is empty. Filters of several labels are searched one label at a time and merged, as FilteredVamana
+    // doesn't connect nodes without a label in common, and a single search would stay around its closest start node
+    pub(crate) fn filtered_search(
+        &self,
+        entry_points: &[u32],
+        query_node: &[f32],
+        filter: &HashSet<Label>,
+        k: usize,
+        search_list_size: usize,
+    ) -> Result<GreedySearchResult> {
+        if filter.len() <= 1 {
+            let start_node_indexes = self.labels.start_nodes(filter, entry_points);
+            return self.greedy_search(
+                &start_node_indexes,
+                query_node,
+                filter,
+                k,
+                search_list_size,
+            );
+        }
+
+        let mut k_closests = Vec::new();
+        let mut visited = HashSet::new();
+        for label in filter {
+            let label_filter = HashSet::from([*label]);
+            let start_node_indexes = self.labels.start_nodes(&label_filter, entry_points);
+            let (label_closests, label_visited) = self.greedy_search(
+                &start_node_indexes,
+                query_node,
+                &label_filter,
+                k,
+                search_list_size,
+            )?;
+            k_closests.extend(label_closests);
+            visited.extend(label_visited);
+        }
+        // nodes of several labels of filter are found once per label
+        k_closests.sort_unstable();
+        k_closests.dedup();
+        k_closests.truncate(k);
+        Ok((k_closests, visited))
+    }
+
+    // candidates returns the nodes visited while searching for a node carrying labels, which are its candidate
+    // neighbours. Labelled nodes are searched for with their labels as filter, as in FilteredVamana, and without a
+    // filter too, so that they also get edges across labels that unfiltered searches can follow
+    pub(crate) fn candidates(
+        &self,
+        entry_points: &[u32],
+        vector: &[f32],
+        labels: &HashSet<Label>,
+        search_list_size: usize,
+    ) -> Result<HashSet<u32>> {
+        let (_, mut visited) =
+            self.filtered_search(entry_points, vector, &HashSet::new(), 1, search_list_size)?;
+        if !labels.is_empty() {
+            visited.extend(
+                self.filtered_search(entry_points, vector, labels, 1, search_list_size)?
+                    .1,
+            );
+        }
+        Ok(visited)
+    }
+
+    // read the records of node_indexes that aren't in records yet, in one batch
+    fn read_records(&self, node_indexes: &[u32], records: &mut HashMap<u32, Node>) -> Result<()> {
+        let unread: Vec<u32> = node_indexes
+            .iter()
+            .filter(|node_index| !records.contains_key(node_index))
+            .copied()
+            .collect();
+        let nodes = self.index_store.get_nodes(&unread)?;
+        records.extend(unread.into_iter().zip(nodes));
+        Ok(())
+    }
+
+    // distances_to_query returns the distance from query to every node of node_indexes.
+    // With PQ, nodes are ranked by the asymmetric distance of their in-RAM code, without reading their record
+    fn distances_to_query(
+        &self,
+        query: &SearchQuery,
+        node_indexes: &[u32],
+        records: &mut HashMap<u32, Node>,
+    ) -> Result<Vec<i64>> {
+        let code = |node_index: &u32| match (self.pq, &query.distance_table) {
+            (Some(pq), Some(distance_table)) => pq
+                .codes
+                .get(node_index)
+                .map(|code| (distance_table.distance(code) as f64 * DISTANCE_SCALE) as i64),
+            _ => None,
+        };
+
+        let uncoded: Vec<u32> = node_indexes
+            .iter()
+            .filter(|node_index| code(node_index).is_none())
+            .copied()
+            .collect();
+
+        // stores that compute distances in place spare reading whole records of nodes that may never be expanded
+        let mut uncoded_distances: HashMap<u32, i64> = HashMap::new();
+        match self.index_store.distances_to_query(
+            self.metric,
+            query.vector,
+            query.quantized.as_deref(),
+            &uncoded,
+        ) {
+            Some(distances) => uncoded_distances.extend(uncoded.iter().copied().zip(distances?)),
+            None => self.read_records(&uncoded, records)?,
+        }
+
+        Ok(node_indexes
+            .iter()
+            .map(|node_index| {
+                code(node_index)
+                    .or_else(|| uncoded_distances.get(node_index).copied())
+                    .unwrap_or_else(|| {
+                        self.distance(
+                            query.vector,
+                            query.quantized.as_deref(),
+                            &records[node_index],
+                        )
+                    })
+            })
+            .collect())
+    }
+
+    // out_edges searches for node_index from entry_points and returns the out-neighbours prune picks for it
+    pub(crate) fn out_edges(
+        &self,
+        entry_points: &[u32],
+        node_index: u32,
+        distance_threshold: f32,
+        degree_bound: usize,
+        search_list_size: usize,
+    ) -> Result<HashSet<u32>> {
+        let node = self.index_store.get_node(node_index)?;
+        let visited = self.candidates(
+            entry_points,
+            &node.vector,
+            &self.labels.labels(node_index),
+            search_list_size,
+        )?;
+        self.prune(&node, &visited, distance_threshold, degree_bound)
+    }
+
+    // reverse_edges returns the connections of node_index once sources link to it, pruned if they exceed degree_bound
+    pub(crate) fn reverse_edges(
+        &self,
+        node_index: u32,
+        sources: &HashSet<u32>,
+        distance_threshold: f32,
+        degree_bound: usize,
+    ) -> Result<HashSet<u32>> {
+        let node = self.index_store.get_node(node_index)?;
+        let mut connected = node.connected.clone();
+        connected.extend(sources);
+        if connected.len() > degree_bound {
+            self.prune(&node, sources, distance_threshold, degree_bound)
+        } else {
+            Ok(connected)
+        }
+    }
+
+    // prune returns the out-neighbours robust_prune picks for p_node out of candidates and p_node's current connections, without writing them.
+    // As in FilteredRobustPrune, a candidate is only pruned in favour of a closer neighbour that carries every label it shares with p
+    pub(crate) fn prune(
+        &self,
+        p_node: &Node,
+        candidates: &HashSet<u32>,
+        distance_threshold: f32,
+        degree_bound: usize,
+    ) -> Result<HashSet<u32>> {
+        // add all nodes that was visited to try to reach p (excluding p) into working set
+        let mut working_set = candidates.clone();
+
+        // add all nodes connected to p into working set
+        working_set.extend(p_node.connected.iter());
+
+        // p must not connect to itself or to deleted nodes
+        working_set.retain(|x| *x != p_node.id && !self.index_store.is_deleted(*x));
+
+        let mut distance_heap: BinaryHeap<Reverse<(i64, u32)>> = BinaryHeap::new();
+        for node_index in working_set.iter() {
+            let working_set_node = self.index_store.get_node(*node_index)?;
+            let distance_from_p = self.distance(
+                &p_node.vector,
+                p_node.quantized.as_deref(),
+                &working_set_node,
+            );
+            distance_heap.push(Reverse((distance_from_p, *node_index)));
+        }
+
+        // reset p's connected
+        let mut p_node_connections: HashSet<u32> = HashSet::new();
+
+        while let Some(Reverse((_, min_node_index))) = distance_heap.pop() {
+            // add min_node to p_index's connected
+            // note: the reverse connection is added by the caller of this method
+            p_node_connections.insert(min_node_index);
+            if p_node_connections.len() == degree_bound {
+                break;
+            }
+
+            let min_node = self.index_store.get_node(min_node_index)?;
+
+            let mut pruned = Vec::with_capacity(distance_heap.len());
+            for Reverse((distance_to_p, node_index)) in distance_heap.drain() {
+                let comparison_node = self.index_store.get_node(node_index)?;
+                let distance_to_min_node = self.distance(
+                    &min_node.vector,
+                    min_node.quantized.as_deref(),
+                    &comparison_node,
+                ) as f64;
+                if distance_to_min_node * distance_threshold as f64 > distance_to_p as f64
+                    || !self.labels.covers(p_node.id, node_index, min_node_index)
+                {
+                    pruned.push(Reverse((distance_to_p, node_index)));
+                }
+            }
+            distance_heap = pruned.into();
+        }
+
+        Ok(p_node_connections)
+    }
+
+    // distance from vector to node. It is computed on their i8 vectors when the index store quantizes vectors
+    fn distance(&self, vector: &[f32], quantized: Option<&[i8]>, node: &Node) -> i64 {
+        match (self.quantizer, quantized, &node.quantized) {
+            (Some(quantizer), Some(a), Some(b)) => quantizer.distance(self.metric, a, b),
+            _ => self.metric.distance(vector, &node.vector),
+        }
+    }
+}
+
+// SearchHit is a single search result
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    // the node index of the hit
+    pub id: u32,
+    // the id the node was upserted with, if any
+    pub external_id: Option<ExternalId>,
+    // distance to the query under the graph's metric
+    pub distance: f32,
+    pub data: Option<Vec<u8>>,
+}
+
+// Entry is a vector to add to the graph, with its data and the labels filtered searches match it by
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub vector: Vec<f32>,
+    pub data: Vec<u8>,
+    pub labels: Vec<Label>,
+}
+
+// data is anything that converts into bytes, such as a String or a serialized JSON document
+impl<D: Into<Vec<u8>>> From<(Vec<f32>, D)> for Entry {
+    fn from((vector, data): (Vec<f32>, D)) -> Self {
+        Entry {
+            vector,
+            data: data.into(),
+            labels: Vec::new(),
+        }
+    }
+}
+
+impl<D: Into<Vec<u8>>> From<(Vec<f32>, D, Vec<Label>)> for Entry {
+    fn from((vector, data, labels): (Vec<f32>, D, Vec<Label>)) -> Self {
+        Entry {
+            vector,
+            data: data.into(),
+            labels,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub(crate) id: u32,
+    pub(crate) vector: Vec<f32>,
+    pub(crate) connected: HashSet<u32>,
+    // the vector as stored by a quantizing index store, see IndexStore::get_scalar_quantizer
+    pub(crate) quantized: Option<Vec<i8>>,
+}
+
+impl Node {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn vector(&self) -> &[f32] {
+        &self.vector
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{brute_force_knn, evaluate};
+    use crate::{
+        vector::generate_random_vectors, DiskDataStore, FreshDisk, InMemStorage, NaiveDisk,
+        ReadMode,
+    };
+    use std::env;
+
+    const MAX_NEIGHBOUR_COUNT: u8 = 5;
+
+    fn new_indexed_graph(index_store: Box<dyn IndexStore>) -> Graph {
+        let test_vectors = generate_random_vectors(200, &(0.0..2000.0), 2);
+        let mut graph = Graph::new(
+            vec![test_vectors].into_iter(),
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            Metric::L2,
+            index_store,
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.0, 10).unwrap();
+        graph
+    }
+
+    // new_grid_graph indexes a 10x10 grid of points 200 apart, which unlike random points reliably leaves every
+    // node reachable
+    fn new_grid_graph(index_store: Box<dyn IndexStore>) -> Graph {
+        let grid: Vec<(Vec<f32>, String)> = (0..100)
+            .map(|i| [i % 10, i / 10].map(|x| x as f32 * 200.0))
+            .map(|point| (point.to_vec(), "".to_string()))
+            .collect();
+        let mut graph = Graph::new(
+            vec![grid].into_iter(),
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            Metric::L2,
+            index_store,
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.2, 10).unwrap();
+        graph
+    }
+
+    // reachable returns the nodes that searches can reach from the start nodes
+    fn reachable(graph: &Graph) -> HashSet<u32> {
+        let nodes = graph.index_store.get_all_nodes().unwrap();
+        let mut reached: HashSet<u32> = HashSet::new();
+        let mut to_visit = graph.start_node_indexes();
+        while let Some(node_index) = to_visit.pop() {
+            if reached.insert(node_index) {
+                to_visit.extend(nodes[&node_index].connected.iter());
+            }
+        }
+        reached
+    }
+
+    fn assert_delete_and_consolidate(mut graph: Graph) {
+        let node_indexes = graph.index_store.get_all_node_indexes().unwrap();
+        let deleted: HashSet<u32> = node_indexes.iter().step_by(10).copied().collect();
+
+        // tombstoned nodes are no longer returned by searches
+        for node_index in deleted.iter() {
+            graph.delete(*node_index).unwrap();
+        }
+        for node_index in deleted.iter() {
+            let query = graph.index_store.get_node(*node_index).unwrap().vector;
+            let closests = graph.search(&query, 3, 10).unwrap();
+            assert!(closests.iter().all(|hit| !deleted.contains(&hit.id)));
+        }
+
+        graph.consolidate_deletes(1.0).unwrap();
+
+        // deleted nodes are removed and no remaining node points to them
+        assert!(graph.index_store.get_deleted_node_indexes().unwrap().is_empty());
+        let remaining = graph.index_store.get_all_nodes().unwrap();
+        assert_eq!(node_indexes.len() - deleted.len(), remaining.len());
+        for (node_index, node) in remaining.iter() {
+            assert!(!deleted.contains(node_index));
+            assert!(node.connected.is_disjoint(&deleted));
+        }
+        for node_index in deleted.iter() {
+            assert!(graph.index_store.get_node(*node_index).is_err());
+            assert_eq!(None, graph.data_store.get_data(*node_index).unwrap());
+        }
+        for node_index in remaining.keys() {
+            assert!(graph.data_store.get_data(*node_index).unwrap().is_some());
+        }
+        assert!(graph
+            .entry_points
+            .iter()
+            .all(|entry_point| !deleted.contains(entry_point)));
+
+        // prunes with a distance threshold of 1.0 easily drop the last in-edge of a node, which is then reconnected
+        let start_node_indexes = graph.start_node_indexes();
+        let pointed_to: HashSet<u32> = remaining
+            .values()
+            .flat_map(|node| node.connected.iter().copied())
+            .collect();
+        for node_index in remaining.keys() {
+            assert!(pointed_to.contains(node_index) || start_node_indexes.contains(node_index));
+        }
+    }
+
+    // recall_at_k is the fraction of the true k nearest neighbours of queries that graph finds
+    fn recall_at_k(graph: &Graph, vectors: &[Vec<f32>], queries: &[Vec<f32>], k: usize) -> f64 {
+        let mut found = 0;
+        for query in queries {
+            let mut exact: Vec<(i64, u32)> = vectors
+                .iter()
+                .enumerate()
+                .map(|(id, vector)| (Metric::L2.distance(query, vector), id as u32))
+                .collect();
+            exact.sort();
+            let exact: HashSet<u32> = exact.iter().take(k).map(|x| x.1).collect();
+
+            let hits = graph.search(query, k, 20).unwrap();
+            found += hits.iter().filter(|hit| exact.contains(&hit.id)).count();
+        }
+        found as f64 / (queries.len() * k) as f64
+    }
+
+    #[test]
+    fn test_index_parallel_matches_sequential_recall() {
+        let vectors = generate_random_vectors(2000, &(0.0..2000.0), 2);
+        let queries: Vec<Vec<f32>> = generate_random_vectors(100, &(0.0..2000.0), 2)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let new_graph = || {
+            Graph::new(
+                vec![vectors.clone()].into_iter(),
+                2,
+                MAX_NEIGHBOUR_COUNT,
+                Metric::L2,
+                Box::new(InMemStorage::default()),
+                Box::new(InMemStorage::default()),
+            )
+            .unwrap()
+        };
+        let vectors: Vec<Vec<f32>> = vectors.iter().map(|x| x.0.clone()).collect();
+
+        let mut sequential = new_graph();
+        sequential.index(1.0, 10).unwrap();
+        sequential.index(1.0, 10).unwrap();
+
+        let mut parallel = new_graph();
+        parallel.index_parallel(1.0, 10, 4).unwrap();
+        parallel.index_parallel(1.0, 10, 4).unwrap();
+
+        for node in parallel.index_store.get_all_nodes().unwrap().values() {
+            assert!(node.connected.len() <= MAX_NEIGHBOUR_COUNT as usize);
+            assert!(!node.connected.contains(&node.id));
+        }
+
+        let sequential_recall = recall_at_k(&sequential, &vectors, &queries, 5);
+        let parallel_recall = recall_at_k(&parallel, &vectors, &queries, 5);
+        assert!(parallel_recall >= sequential_recall - 0.05);
+    }
+
+    #[test]
+    fn test_search_with_pq_reranks_with_full_precision() {
+        let vectors = generate_random_vectors(1000, &(0.0..100.0), 8);
+        let queries: Vec<Vec<f32>> = generate_random_vectors(50, &(0.0..100.0), 8)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let mut graph = Graph::new(
+            vec![vectors.clone()].into_iter(),
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            Metric::L2,
+            Box::new(InMemStorage::default()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.0, 10).unwrap();
+        graph.index(1.0, 10).unwrap();
+        let vectors: Vec<Vec<f32>> = vectors.into_iter().map(|x| x.0).collect();
+        let full_precision_recall = recall_at_k(&graph, &vectors, &queries, 5);
+
+        graph.train_pq(4).unwrap();
+
+        // returned distances are exact, not approximated from codes
+        let hits = graph.search(&queries[0], 5, 20).unwrap();
+        for hit in hits.iter() {
+            let vector = graph.index_store.get_node(hit.id).unwrap().vector;
+            let exact = Metric::L2.distance(&queries[0], &vector) as f64 / DISTANCE_SCALE;
+            assert_eq!(exact as f32, hit.distance);
+        }
+
+        let pq_recall = recall_at_k(&graph, &vectors, &queries, 5);
+        assert!(pq_recall >= full_precision_recall - 0.1);
+
+        let inserted = graph
+            .insert(vec![50.0; 8], b"inserted", &[], 1.0, 20)
+            .unwrap();
+        assert!(graph.pq.as_ref().unwrap().codes.contains_key(&inserted.id));
+    }
+
+    #[test]
+    fn test_quantized_naive_disk_recall() {
+        let vectors = generate_random_vectors(500, &(-100.0..100.0), 8);
+        let queries: Vec<Vec<f32>> = generate_random_vectors(50, &(-100.0..100.0), 8)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let new_graph = |name: &str, index_store: fn(u16, u8, &str, &str) -> Result<NaiveDisk>| {
+            let index_path = env::temp_dir().join(format!("{}.index", name));
+            let free_path = env::temp_dir().join(format!("{}.free", name));
+            let index_store = index_store(
+                8,
+                MAX_NEIGHBOUR_COUNT,
+                index_path.to_str().unwrap(),
+                free_path.to_str().unwrap(),
+            )
+            .unwrap();
+            let mut graph = Graph::new(
+                vec![vectors.clone()].into_iter(),
+                2,
+                MAX_NEIGHBOUR_COUNT,
+                Metric::L2,
+                Box::new(index_store),
+                Box::new(InMemStorage::default()),
+            )
+            .unwrap();
+            graph.index(1.0, 10).unwrap();
+            graph.index(1.0, 10).unwrap();
+            graph
+        };
+
+        let full_precision = new_graph("test_graph_f32", NaiveDisk::new);
+        let quantized = new_graph("test_graph_i8", NaiveDisk::new_quantized);
+        assert!(quantized.index_store.get_scalar_quantizer().is_some());
+
+        // both graphs number nodes in the same order, so they share the exact ground truth
+        let ground_truth =
+            brute_force_knn(&*full_precision.index_store, Metric::L2, &queries, 5).unwrap();
+        let full_precision_recall =
+            evaluate(&full_precision, &queries, &ground_truth, 5, &[20]).unwrap()[0].recall;
+        let quantized_recall =
+            evaluate(&quantized, &queries, &ground_truth, 5, &[20]).unwrap()[0].recall;
+        assert!(quantized_recall >= full_precision_recall - 0.1);
+    }
+
+    #[test]
+    fn test_beam_search() {
+        let index_path = env::temp_dir().join("test_graph_beam.index");
+        let free_path = env::temp_dir().join("test_graph_beam.free");
+        let mut graph = new_indexed_graph(Box::new(
+            NaiveDisk::new(
+                2,
+                MAX_NEIGHBOUR_COUNT,
+                index_path.to_str().unwrap(),
+                free_path.to_str().unwrap(),
+            )
+            .unwrap(),
+        ));
+        let queries: Vec<Vec<f32>> = generate_random_vectors(50, &(0.0..2000.0), 2)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let ground_truth = brute_force_knn(&*graph.index_store, Metric::L2, &queries, 5).unwrap();
+        let single_recall = evaluate(&graph, &queries, &ground_truth, 5, &[20]).unwrap()[0].recall;
+
+        assert!(graph.set_beam_width(0).is_err());
+        graph.set_beam_width(4).unwrap();
+        let beam_recall = evaluate(&graph, &queries, &ground_truth, 5, &[20]).unwrap()[0].recall;
+        assert!(beam_recall >= single_recall - 0.05);
+
+        // inserts search with the beam too
+        let inserted = graph
+            .insert(vec![1000.0, 1000.0], b"", &[], 1.0, 10)
+            .unwrap();
+        let hits = graph.search(&[1000.0, 1000.0], 1, 10).unwrap();
+        assert_eq!(inserted.id, hits[0].id);
+    }
+
+    #[test]
+    fn test_mmap_search() {
+        let index_path = env::temp_dir().join("test_graph_mmap.index");
+        let free_path = env::temp_dir().join("test_graph_mmap.free");
+        let mut disk = NaiveDisk::new(
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        disk.set_read_mode(ReadMode::Mmap).unwrap();
+        let mut graph = new_indexed_graph(Box::new(disk));
+
+        // an index built and searched through the map is as good as one built with file reads
+        let queries: Vec<Vec<f32>> = generate_random_vectors(50, &(0.0..2000.0), 2)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let ground_truth = brute_force_knn(&*graph.index_store, Metric::L2, &queries, 5).unwrap();
+        let recall = evaluate(&graph, &queries, &ground_truth, 5, &[20]).unwrap()[0].recall;
+        assert!(recall >= 0.9, "recall {}", recall);
+
+        let inserted = graph
+            .insert(vec![1000.0, 1000.0], b"", &[], 1.0, 10)
+            .unwrap();
+        let hits = graph.search(&[1000.0, 1000.0], 1, 10).unwrap();
+        assert_eq!(inserted.id, hits[0].id);
+        assert_eq!(0.0, hits[0].distance);
+    }
+
+    #[test]
+    fn test_filtered_search() {
+        // every node carries label i % 4, and every tenth one label 10 too
+        let vectors = generate_random_vectors(2000, &(0.0..2000.0), 2);
+        let labels: Vec<Vec<Label>> = (0..vectors.len() as u32)
+            .map(|i| match i % 10 {
+                0 => vec![i % 4, 10],
+                _ => vec![i % 4],
+            })
+            .collect();
+        let entries: Vec<(Vec<f32>, String, Vec<Label>)> = vectors
+            .iter()
+            .zip(&labels)
+            .map(|((vector, data), labels)| (vector.clone(), data.clone(), labels.clone()))
+            .collect();
+        // a larger degree bound than other tests, as nodes keep edges for each of their labels and across labels
+        let mut graph = Graph::new(
+            vec![entries].into_iter(),
+            2,
+            16,
+            Metric::L2,
+            Box::new(InMemStorage::default()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.2, 10).unwrap();
+
+        let queries: Vec<Vec<f32>> = generate_random_vectors(50, &(0.0..2000.0), 2)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let k = 5;
+        for filter in [vec![1], vec![10], vec![2, 3]] {
+            let mut found = 0;
+            for query in queries.iter() {
+                let mut exact: Vec<(i64, u32)> = vectors
+                    .iter()
+                    .enumerate()
+                    .filter(|(id, _)| labels[*id].iter().any(|label| filter.contains(label)))
+                    .map(|(id, vector)| (Metric::L2.distance(query, &vector.0), id as u32))
+                    .collect();
+                exact.sort();
+                let exact: HashSet<u32> = exact.iter().take(k).map(|x| x.1).collect();
+
+                let hits = graph.search_filtered(query, &filter, k, 20).unwrap();
+                assert_eq!(k, hits.len());
+                for hit in hits.iter() {
+                    assert!(labels[hit.id as usize]
+                        .iter()
+                        .any(|label| filter.contains(label)));
+                }
+                found += hits.iter().filter(|hit| exact.contains(&hit.id)).count();
+            }
+            let recall = found as f64 / (queries.len() * k) as f64;
+            assert!(recall >= 0.9, "recall {} for filter {:?}", recall, filter);
+        }
+
+        // unfiltered searches still reach nodes of every label
+        let vectors: Vec<Vec<f32>> = vectors.into_iter().map(|x| x.0).collect();
+        let recall = recall_at_k(&graph, &vectors, &queries, k);
+        assert!(recall >= 0.9, "unfiltered recall {}", recall);
+
+        // the first node of a new label starts its searches
+        let inserted = graph
+            .insert(vec![1000.0, 1000.0], b"", &[20, 1], 1.2, 20)
+            .unwrap();
+        let hits = graph.search_filtered(&[0.0, 0.0], &[20], 5, 20).unwrap();
+        assert_eq!(
+            vec![inserted.id],
+            hits.iter().map(|hit| hit.id).collect::<Vec<u32>>()
+        );
+        let hits = graph
+            .search_filtered(&[1000.0, 1000.0], &[1], 1, 20)
+            .unwrap();
+        assert_eq!(inserted.id, hits[0].id);
+        assert!(graph
+            .search_filtered(&[0.0, 0.0], &[30], 5, 20)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_labels_survive_reopen() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.join("test_graph_labels.index");
+        let free_path = temp_dir.join("test_graph_labels.free");
+        let labels_path = temp_dir.join("test_graph_labels.labels");
+        let paths = [&index_path, &free_path, &labels_path].map(|path| path.to_str().unwrap());
+
+        // every node carries label i % 2
+        let entries: Vec<(Vec<f32>, String, Vec<Label>)> =
+            generate_random_vectors(200, &(0.0..2000.0), 2)
+                .into_iter()
+                .enumerate()
+                .map(|(i, (vector, data))| (vector, data, vec![i as Label % 2]))
+                .collect();
+        let mut graph = Graph::new(
+            vec![entries].into_iter(),
+            2,
+            16,
+            Metric::L2,
+            Box::new(NaiveDisk::new(2, 16, paths[0], paths[1]).unwrap()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph
+            .set_label_index(LabelIndex::new(paths[2]).unwrap())
+            .unwrap();
+        graph.index(1.2, 10).unwrap();
+        let inserted = graph
+            .insert(vec![1000.0, 1000.0], b"", &[7], 1.2, 20)
+            .unwrap();
+        drop(graph);
+
+        let mut graph = Graph::from_stores(
+            Box::new(NaiveDisk::open(paths[0], paths[1]).unwrap()),
+            Box::new(InMemStorage::default()),
+            16,
+        )
+        .unwrap();
+        graph
+            .set_label_index(LabelIndex::open(paths[2]).unwrap())
+            .unwrap();
+        for label in [0, 1] {
+            let hits = graph.search_filtered(&[0.0, 0.0], &[label], 5, 20).unwrap();
+            assert_eq!(5, hits.len());
+            assert!(hits
+                .iter()
+                .all(|hit| graph.labels.labels(hit.id).contains(&label)));
+        }
+        let hits = graph.search_filtered(&[0.0, 0.0], &[7], 5, 20).unwrap();
+        assert_eq!(
+            vec![inserted.id],
+            Vec::from_iter(hits.iter().map(|hit| hit.id))
+        );
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let vectors: Vec<Vec<f32>> = generate_random_vectors(400, &(0.0..2000.0), 2)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let queries: Vec<Vec<f32>> = generate_random_vectors(50, &(0.0..2000.0), 2)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        let entries = |range: std::ops::Range<usize>| -> Vec<(Vec<f32>, String)> {
+            range.map(|i| (vectors[i].clone(), i.to_string())).collect()
+        };
+
+        // streamed into an index built from the first vectors
+        for thread_count in [1, 4] {
+            let mut graph = Graph::new(
+                vec![entries(0..200)].into_iter(),
+                2,
+                MAX_NEIGHBOUR_COUNT,
+                Metric::L2,
+                Box::new(InMemStorage::default()),
+                Box::new(InMemStorage::default()),
+            )
+            .unwrap();
+            graph.index(1.0, 10).unwrap();
+            graph.index(1.0, 10).unwrap();
+            for start in (200..400).step_by(50) {
+                let node_indexes = graph
+                    .insert_batch(entries(start..start + 50), 1.0, 20, thread_count)
+                    .unwrap();
+                assert_eq!(
+                    (start as u32..start as u32 + 50).collect::<Vec<_>>(),
+                    node_indexes
+                );
+            }
+
+            let recall = recall_at_k(&graph, &vectors, &queries, 5);
+            assert!(recall >= 0.9, "recall {}", recall);
+            let hits = graph.search(&vectors[300], 1, 20).unwrap();
+            assert_eq!(Some(b"300".to_vec()), hits[0].data);
+        }
+
+        // streamed into an empty graph
+        let mut graph = Graph::new(
+            std::iter::empty::<Vec<(Vec<f32>, String)>>(),
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            Metric::L2,
+            Box::new(InMemStorage::default()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        assert!(graph
+            .insert_batch(Vec::<Entry>::new(), 1.0, 20, 1)
+            .unwrap()
+            .is_empty());
+        for start in (0..400).step_by(100) {
+            graph
+                .insert_batch(entries(start..start + 100), 1.0, 20, 1)
+                .unwrap();
+        }
+        let recall = recall_at_k(&graph, &vectors, &queries, 5);
+        assert!(recall >= 0.9, "recall {}", recall);
+    }
+
+    #[test]
+    fn test_search_returns_sorted_hits() {
+        let mut graph = new_indexed_graph(Box::new(InMemStorage::default()));
+        graph.data_store.add_data(0, b"first").unwrap();
+
+        let query = graph.index_store.get_node(0).unwrap().vector;
+        let hits = graph.search(&query, 5, 20).unwrap();
+
+        assert_eq!(5, hits.len());
+        assert_eq!(0, hits[0].id);
+        assert_eq!(0.0, hits[0].distance);
+        assert_eq!(Some(b"first".to_vec()), hits[0].data);
+        for pair in hits.windows(2) {
+            assert!(pair[0].distance <= pair[1].distance);
+        }
+        for hit in hits.iter() {
+            let vector = graph.index_store.get_node(hit.id).unwrap().vector;
+            let distance = Metric::L2.distance(&query, &vector) as f64 / DISTANCE_SCALE;
+            assert_eq!(distance as f32, hit.distance);
+        }
+    }
+
+    #[test]
+    fn test_reopen_with_disk_data_store() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.join("test_graph_reopen.index");
+        let free_path = temp_dir.join("test_graph_reopen.free");
+        let data_path = temp_dir.join("test_graph_reopen.data");
+        let offsets_path = temp_dir.join("test_graph_reopen.offsets");
+        let paths =
+            [&index_path, &free_path, &data_path, &offsets_path].map(|path| path.to_str().unwrap());
+
+        let entries: Vec<(Vec<f32>, Vec<u8>)> = generate_random_vectors(200, &(0.0..2000.0), 2)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (vector, _))| (vector, format!(r#"{{"position": {}}}"#, i).into_bytes()))
+            .collect();
+        let mut graph = Graph::new(
+            vec![entries.clone()].into_iter(),
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            Metric::L2,
+            Box::new(NaiveDisk::new(2, MAX_NEIGHBOUR_COUNT, paths[0], paths[1]).unwrap()),
+            Box::new(DiskDataStore::new(paths[2], paths[3]).unwrap()),
+        )
+        .unwrap();
+        graph.index(1.0, 10).unwrap();
+        graph.delete(1).unwrap();
+        graph.consolidate_deletes(1.0).unwrap();
+        drop(graph);
+
+        // payloads come back with the nodes they belong to, and deleted ones stay deleted
+        let graph = Graph::from_stores(
+            Box::new(NaiveDisk::open(paths[0], paths[1]).unwrap()),
+            Box::new(DiskDataStore::open(paths[2], paths[3]).unwrap()),
+            MAX_NEIGHBOUR_COUNT,
+        )
+        .unwrap();
+        // NaiveDisk numbers nodes from 1, in the order of entries
+        for (vector, _) in entries.iter().skip(1) {
+            for hit in graph.search(vector, 5, 20).unwrap() {
+                assert_eq!(Some(&entries[hit.id as usize - 1].1), hit.data.as_ref());
+            }
+        }
+        assert_eq!(None, graph.data_store.get_data(1).unwrap());
+    }
+
+    #[test]
+    fn test_deletes_survive_reopen() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.join("test_graph_deletes_reopen.index");
+        let free_path = temp_dir.join("test_graph_deletes_reopen.free");
+        let paths = [&index_path, &free_path].map(|path| path.to_str().unwrap());
+
+        let mut graph = new_indexed_graph(Box::new(
+            NaiveDisk::new(2, MAX_NEIGHBOUR_COUNT, paths[0], paths[1]).unwrap(),
+        ));
+        let deleted: HashSet<u32> = HashSet::from([1, 2, 3]);
+        for node_index in deleted.iter() {
+            graph.delete(*node_index).unwrap();
+        }
+        let queries: Vec<Vec<f32>> = deleted
+            .iter()
+            .map(|node_index| graph.index_store.get_node(*node_index).unwrap().vector)
+            .collect();
+        drop(graph);
+
+        // tombstones are read back with the index, so deleted nodes stay out of searches until they are consolidated
+        let mut graph = Graph::from_stores(
+            Box::new(NaiveDisk::open(paths[0], paths[1]).unwrap()),
+            Box::new(InMemStorage::default()),
+            MAX_NEIGHBOUR_COUNT,
+        )
+        .unwrap();
+        assert_eq!(
+            deleted,
+            graph.index_store.get_deleted_node_indexes().unwrap()
+        );
+        for query in queries.iter() {
+            let hits = graph.search(query, 5, 20).unwrap();
+            assert!(hits.iter().all(|hit| !deleted.contains(&hit.id)));
+        }
+
+        graph.consolidate_deletes(1.0).unwrap();
+        drop(graph);
+        let reopened = NaiveDisk::open(paths[0], paths[1]).unwrap();
+        assert!(reopened.get_deleted_node_indexes().unwrap().is_empty());
+        assert_eq!(197, reopened.get_all_node_indexes().unwrap().len());
+    }
+
+    #[test]
+    fn test_upsert_by_external_id() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.join("test_graph_upsert.index");
+        let free_path = temp_dir.join("test_graph_upsert.free");
+        let ids_path = temp_dir.join("test_graph_upsert.ids");
+        let paths = [&index_path, &free_path, &ids_path].map(|path| path.to_str().unwrap());
+
+        let mut graph = new_grid_graph(Box::new(
+            NaiveDisk::new(2, MAX_NEIGHBOUR_COUNT, paths[0], paths[1]).unwrap(),
+        ));
+        graph.set_id_map(IdMap::new(paths[2]).unwrap());
+
+        let first = graph
+            .upsert("a", vec![3000.0, 3000.0], b"first", &[], 1.2, 20)
+            .unwrap();
+        graph
+            .upsert(7u64, vec![-1000.0, -1000.0], b"", &[], 1.2, 20)
+            .unwrap();
+        let hits = graph.search(&[3000.0, 3000.0], 1, 20).unwrap();
+        assert_eq!(Some(ExternalId::from("a")), hits[0].external_id);
+
+        // upserting an existing id replaces its vector
+        let second = graph
+            .upsert("a", vec![5000.0, 5000.0], b"second", &[], 1.2, 20)
+            .unwrap();
+        assert!(graph.index_store.is_deleted(first.id));
+        assert_eq!(Some(second.id), graph.id_map().node_index(&"a".into()));
+        let hits = graph.search(&[3000.0, 3000.0], 5, 20).unwrap();
+        assert!(hits.iter().all(|hit| hit.id != first.id));
+        let hits = graph.search(&[5000.0, 5000.0], 1, 20).unwrap();
+        assert_eq!(second.id, hits[0].id);
+        assert_eq!(Some(b"second".to_vec()), hits[0].data);
+
+        // the ids of removed nodes are dropped, so that their slots can be reused, and the new node stays reachable
+        graph.consolidate_deletes(1.2).unwrap();
+        assert!(reachable(&graph).contains(&second.id));
+        assert!(graph.delete_by_id(&ExternalId::U64(8)).is_err());
+        assert_eq!(2, graph.id_map().len());
+        drop(graph);
+
+        let mut graph = Graph::from_stores(
+            Box::new(NaiveDisk::open(paths[0], paths[1]).unwrap()),
+            Box::new(InMemStorage::default()),
+            MAX_NEIGHBOUR_COUNT,
+        )
+        .unwrap();
+        graph.set_id_map(IdMap::open(paths[2]).unwrap());
+        assert!(reachable(&graph).contains(&second.id));
+        let hits = graph.search(&[5000.0, 5000.0], 1, 20).unwrap();
+        assert_eq!(Some(ExternalId::from("a")), hits[0].external_id);
+
+        graph.delete_by_id(&ExternalId::U64(7)).unwrap();
+        let hits = graph.search(&[-1000.0, -1000.0], 1, 20).unwrap();
+        assert_eq!(None, hits[0].external_id);
+        assert_eq!(1, graph.id_map().len());
+    }
+
+    #[test]
+    fn test_index_uses_medoid_entry_point() {
+        let line: Vec<(Vec<f32>, String)> = (0..11)
+            .map(|x| (vec![x as f32, 0.0], "".to_string()))
+            .collect();
+        let mut graph = Graph::new(
+            vec![line].into_iter(),
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            Metric::L2,
+            Box::new(InMemStorage::default()),
+            Box::new(InMemStorage::default()),
+        )
+        .unwrap();
+        graph.index(1.0, 10).unwrap();
+
+        // the middle of the line is its medoid, and is persisted in the index store
+        assert_eq!(vec![5], graph.entry_points);
+        assert_eq!(vec![5], graph.index_store.get_entry_points());
+
+        // additional entry points spread out from the medoid
+        graph.update_entry_points(3).unwrap();
+        assert_eq!(5, graph.entry_points[0]);
+        assert_eq!(
+            HashSet::from([0, 10]),
+            graph.entry_points[1..].iter().copied().collect()
+        );
+        assert!(graph.update_entry_points(MAX_ENTRY_POINTS + 1).is_err());
+    }
+
+    #[test]
+    fn test_delete_and_consolidate_in_mem() {
+        let graph = new_indexed_graph(Box::new(InMemStorage::default()));
+        assert_delete_and_consolidate(graph);
+    }
+
+    #[test]
+    fn test_delete_and_consolidate_naive_disk() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_graph_delete.index");
+        let free_path = temp_dir.as_path().join("test_graph_delete.free");
+        let graph = new_indexed_graph(Box::new(
+            NaiveDisk::new(
+                2,
+                MAX_NEIGHBOUR_COUNT,
+                index_path.to_str().unwrap(),
+                free_path.to_str().unwrap(),
+            )
+            .unwrap(),
+        ));
+        assert_delete_and_consolidate(graph);
+    }
+
+    #[test]
+    fn test_delete_and_consolidate_fresh_disk() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_graph_delete_fresh.index");
+        let free_path = temp_dir.as_path().join("test_graph_delete_fresh.free");
+        let graph = new_indexed_graph(Box::new(
+            FreshDisk::new(
+                2,
+                MAX_NEIGHBOUR_COUNT,
+                index_path.to_str().unwrap(),
+                free_path.to_str().unwrap(),
+            )
+            .unwrap(),
+        ));
+        assert_delete_and_consolidate(graph);
+    }
 }
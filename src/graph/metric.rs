@@ -0,0 +1,121 @@
+use simsimd::SpatialSimilarity;
+
+use crate::prelude::*;
+
+// distances are scaled by DISTANCE_SCALE and truncated to i64, so that they can be ordered in heaps
+pub const DISTANCE_SCALE: f64 = 1000000.0;
+
+// Metric is the distance function the graph is built and searched with. Smaller distances are closer for every metric
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Metric {
+    // squared euclidean distance
+    #[default]
+    L2 = 0,
+    // 1 - cosine similarity
+    Cosine = 1,
+    // negated dot product, for maximum inner product search
+    InnerProduct = 2,
+}
+
+impl Metric {
+    pub fn distance(&self, a: &[f32], b: &[f32]) -> i64 {
+        let distance = match self {
+            Metric::L2 => SpatialSimilarity::l2sq(a, b).unwrap(),
+            Metric::Cosine => SpatialSimilarity::cos(a, b).unwrap(),
+            Metric::InnerProduct => -SpatialSimilarity::dot(a, b).unwrap(),
+        };
+        distance.mul_add(DISTANCE_SCALE, 0.0) as i64
+    }
+
+    // distance_be is distance for b given as the big-endian f32 bytes NaiveDisk stores vectors as.
+    // b is decoded a chunk at a time into a stack buffer, so that no vector is allocated
+    pub(crate) fn distance_be(&self, a: &[f32], b: &[u8]) -> i64 {
+        const CHUNK_SIZE: usize = 64;
+        let mut buffer = [0f32; CHUNK_SIZE];
+        // dot product (or squared euclidean distance for L2), and squared norms of a and b for cosine
+        let (mut ab, mut aa, mut bb) = (0f64, 0f64, 0f64);
+        for (a, b) in a.chunks(CHUNK_SIZE).zip(b.chunks(CHUNK_SIZE * 4)) {
+            let b_chunk = &mut buffer[..a.len()];
+            for (value, bytes) in b_chunk.iter_mut().zip(b.chunks_exact(4)) {
+                *value = f32::from_be_bytes(bytes.try_into().unwrap());
+            }
+            match self {
+                Metric::L2 => ab += SpatialSimilarity::l2sq(a, b_chunk).unwrap(),
+                Metric::Cosine => {
+                    ab += SpatialSimilarity::dot(a, b_chunk).unwrap();
+                    aa += SpatialSimilarity::dot(a, a).unwrap();
+                    bb += SpatialSimilarity::dot(b_chunk, b_chunk).unwrap();
+                }
+                Metric::InnerProduct => ab += SpatialSimilarity::dot(a, b_chunk).unwrap(),
+            }
+        }
+
+        let distance = match self {
+            Metric::L2 => ab,
+            // same as simsimd's cos for zero vectors
+            Metric::Cosine if aa == 0.0 && bb == 0.0 => 0.0,
+            Metric::Cosine if ab == 0.0 => 1.0,
+            Metric::Cosine => (1.0 - ab / (aa * bb).sqrt()).max(0.0),
+            Metric::InnerProduct => -ab,
+        };
+        distance.mul_add(DISTANCE_SCALE, 0.0) as i64
+    }
+}
+
+impl TryFrom<u8> for Metric {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Metric::L2),
+            1 => Ok(Metric::Cosine),
+            2 => Ok(Metric::InnerProduct),
+            _ => Err(Error::InvalidInput(format!("unknown metric {}", value))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        let a = [1.0, 0.0];
+        let b = [2.0, 0.0];
+        let c = [0.0, 1.0];
+
+        assert_eq!(1000000, Metric::L2.distance(&a, &b));
+        assert_eq!(2000000, Metric::L2.distance(&a, &c));
+
+        // b points in the same direction as a, c is orthogonal to it
+        assert_eq!(0, Metric::Cosine.distance(&a, &b));
+        assert_eq!(1000000, Metric::Cosine.distance(&a, &c));
+
+        assert_eq!(-2000000, Metric::InnerProduct.distance(&a, &b));
+        assert_eq!(0, Metric::InnerProduct.distance(&a, &c));
+    }
+
+    #[test]
+    fn test_distance_be() {
+        // longer than a chunk, so that partial sums are combined
+        let a: Vec<f32> = (0..100).map(|x| (x as f32).sin()).collect();
+        let b: Vec<f32> = (0..100).map(|x| (x as f32 * 0.5).cos()).collect();
+        let b_bytes: Vec<u8> = b.iter().flat_map(|value| value.to_be_bytes()).collect();
+
+        for metric in [Metric::L2, Metric::Cosine, Metric::InnerProduct] {
+            let expected = metric.distance(&a, &b);
+            let actual = metric.distance_be(&a, &b_bytes);
+            assert!((expected - actual).abs() <= 10, "{:?}", metric);
+        }
+        assert_eq!(0, Metric::Cosine.distance_be(&[0.0], &0f32.to_be_bytes()));
+    }
+
+    #[test]
+    fn test_metric_round_trips_through_u8() {
+        for metric in [Metric::L2, Metric::Cosine, Metric::InnerProduct] {
+            assert_eq!(metric, Metric::try_from(metric as u8).unwrap());
+        }
+        assert!(Metric::try_from(3).is_err());
+    }
+}
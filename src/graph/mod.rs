@@ -1,6 +1,14 @@
+pub mod filter;
 pub mod graph;
+pub mod metric;
 pub mod plotter;
+pub mod pq;
+pub mod sq;
 pub mod vector;
 
+pub use filter::{Label, LabelIndex};
+pub use graph::Entry;
 pub use graph::Graph;
 pub use graph::Node;
+pub use graph::SearchHit;
+pub use metric::Metric;
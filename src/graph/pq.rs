@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+
+use rand::{seq::SliceRandom, thread_rng};
+
+use crate::{prelude::*, Metric};
+
+// every subspace is quantized to one of at most MAX_CENTROIDS centroids, so that a code fits in a u8
+const MAX_CENTROIDS: usize = 256;
+
+// ProductQuantizer compresses vectors by splitting them into subspace_count subspaces, and replacing each subvector
+// with the index of its closest centroid in that subspace. A vector of d f32 becomes subspace_count bytes
+#[derive(Debug, Clone)]
+pub struct ProductQuantizer {
+    metric: Metric,
+    // subspace i covers dimensions offsets[i]..offsets[i + 1]
+    offsets: Vec<usize>,
+    centroid_count: usize,
+    // centroids[i][c] is the subvector of centroid c of subspace i
+    centroids: Vec<Vec<Vec<f32>>>,
+}
+
+impl ProductQuantizer {
+    // train learns the centroids of every subspace with k-means over vectors. Cosine vectors are normalized first,
+    // so that the cosine distance becomes an inner product that can be split over subspaces
+    pub fn train(
+        vectors: &[Vec<f32>],
+        subspace_count: usize,
+        iterations: usize,
+        metric: Metric,
+    ) -> Result<Self> {
+        let dimensions = vectors.first().map(Vec::len).unwrap_or(0);
+        if subspace_count == 0 || subspace_count > dimensions {
+            return Err(Error::InvalidInput(format!(
+                "cannot split {} dimensions into {} subspaces",
+                dimensions, subspace_count
+            )));
+        }
+        if let Some(vector) = vectors.iter().find(|vector| vector.len() != dimensions) {
+            return Err(Error::InvalidInput(format!(
+                "training vector of {} dimensions among vectors of {} dimensions",
+                vector.len(),
+                dimensions
+            )));
+        }
+
+        // the first dimensions % subspace_count subspaces get one extra dimension
+        let mut offsets = vec![0];
+        for i in 0..subspace_count {
+            let size = dimensions / subspace_count + usize::from(i < dimensions % subspace_count);
+            offsets.push(offsets[i] + size);
+        }
+
+        let vectors: Vec<Vec<f32>> = vectors
+            .iter()
+            .map(|vector| prepare(metric, vector))
+            .collect();
+        let centroid_count = vectors.len().min(MAX_CENTROIDS);
+        let centroids = offsets
+            .windows(2)
+            .map(|bounds| {
+                let subvectors: Vec<&[f32]> = vectors
+                    .iter()
+                    .map(|vector| &vector[bounds[0]..bounds[1]])
+                    .collect();
+                kmeans(&subvectors, centroid_count, iterations)
+            })
+            .collect();
+
+        Ok(ProductQuantizer {
+            metric,
+            offsets,
+            centroid_count,
+            centroids,
+        })
+    }
+
+    pub fn subspace_count(&self) -> usize {
+        self.centroids.len()
+    }
+
+    // encode returns the closest centroid of every subspace, for a vector of the dimensions the quantizer was trained on
+    pub fn encode(&self, vector: &[f32]) -> Result<Vec<u8>> {
+        let dimensions = self.offsets.last().copied().unwrap_or(0);
+        if vector.len() != dimensions {
+            return Err(Error::InvalidInput(format!(
+                "cannot encode a vector of {} dimensions with a quantizer of {} dimensions",
+                vector.len(),
+                dimensions
+            )));
+        }
+        let vector = prepare(self.metric, vector);
+        Ok(self
+            .offsets
+            .windows(2)
+            .zip(&self.centroids)
+            .map(|(bounds, centroids)| closest(centroids, &vector[bounds[0]..bounds[1]]) as u8)
+            .collect())
+    }
+
+    // distance_table precomputes the distance from query to every centroid, so that the asymmetric distance from
+    // query to an encoded vector is a sum of one table lookup per subspace
+    pub fn distance_table(&self, query: &[f32]) -> DistanceTable {
+        let query = match self.metric {
+            Metric::Cosine => normalize(query),
+            _ => query.to_vec(),
+        };
+
+        let mut distances = Vec::with_capacity(self.subspace_count() * self.centroid_count);
+        for (bounds, centroids) in self.offsets.windows(2).zip(&self.centroids) {
+            let subquery = &query[bounds[0]..bounds[1]];
+            for centroid in centroids {
+                distances.push(match self.metric {
+                    Metric::L2 => l2sq(subquery, centroid),
+                    Metric::Cosine | Metric::InnerProduct => -dot(subquery, centroid),
+                });
+            }
+        }
+
+        DistanceTable {
+            distances,
+            centroid_count: self.centroid_count,
+            // 1 - cosine similarity
+            offset: if self.metric == Metric::Cosine {
+                1.0
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+pub struct DistanceTable {
+    distances: Vec<f32>,
+    centroid_count: usize,
+    offset: f32,
+}
+
+impl DistanceTable {
+    // distance approximates metric.distance(query, vector), unscaled, from the code of vector
+    pub fn distance(&self, code: &[u8]) -> f32 {
+        code.iter()
+            .enumerate()
+            .map(|(subspace, centroid)| {
+                self.distances[subspace * self.centroid_count + *centroid as usize]
+            })
+            .sum::<f32>()
+            + self.offset
+    }
+}
+
+// PqIndex holds the quantizer of a graph and the code of every node, in RAM
+pub(crate) struct PqIndex {
+    pub(crate) quantizer: ProductQuantizer,
+    pub(crate) codes: HashMap<u32, Vec<u8>>,
+}
+
+fn prepare(metric: Metric, vector: &[f32]) -> Vec<f32> {
+    match metric {
+        Metric::Cosine => normalize(vector),
+        _ => vector.to_vec(),
+    }
+}
+
+fn normalize(vector: &[f32]) -> Vec<f32> {
+    let norm = dot(vector, vector).sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+fn l2sq(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// closest returns the index of the centroid closest to point
+fn closest(centroids: &[Vec<f32>], point: &[f32]) -> usize {
+    centroids
+        .iter()
+        .map(|centroid| l2sq(centroid, point))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+// kmeans clusters points into k centroids with Lloyd's algorithm, starting from k random points
+fn kmeans(points: &[&[f32]], k: usize, iterations: usize) -> Vec<Vec<f32>> {
+    let mut centroids: Vec<Vec<f32>> = points
+        .choose_multiple(&mut thread_rng(), k)
+        .map(|point| point.to_vec())
+        .collect();
+
+    for _ in 0..iterations {
+        let mut sums = vec![vec![0.0f32; centroids[0].len()]; k];
+        let mut counts = vec![0usize; k];
+        for point in points {
+            let cluster = closest(&centroids, point);
+            counts[cluster] += 1;
+            for (sum, value) in sums[cluster].iter_mut().zip(point.iter()) {
+                *sum += value;
+            }
+        }
+
+        // an empty cluster keeps its previous centroid
+        for ((centroid, sum), count) in centroids.iter_mut().zip(sums).zip(counts) {
+            if count > 0 {
+                *centroid = sum.into_iter().map(|x| x / count as f32).collect();
+            }
+        }
+    }
+    centroids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metric::DISTANCE_SCALE, vector::generate_random_vectors};
+
+    #[test]
+    fn test_distance_table_approximates_distance() {
+        let vectors: Vec<Vec<f32>> = generate_random_vectors(1000, &(0.0..100.0), 8)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+
+        for metric in [Metric::L2, Metric::Cosine, Metric::InnerProduct] {
+            let quantizer = ProductQuantizer::train(&vectors, 4, 10, metric).unwrap();
+            let query = &vectors[0];
+            let table = quantizer.distance_table(query);
+
+            let mut error = 0.0;
+            let mut spread = 0.0;
+            for vector in vectors.iter().take(100) {
+                let code = quantizer.encode(vector).unwrap();
+                assert_eq!(4, code.len());
+                let exact = metric.distance(query, vector) as f64 / DISTANCE_SCALE;
+                error += (table.distance(&code) as f64 - exact).abs();
+                spread +=
+                    (exact - metric.distance(query, &vectors[1]) as f64 / DISTANCE_SCALE).abs();
+            }
+            // quantization errors are small compared to how far apart vectors are
+            assert!(
+                error < spread * 0.2,
+                "{:?} error {} spread {}",
+                metric,
+                error,
+                spread
+            );
+        }
+    }
+
+    #[test]
+    fn test_train_rejects_too_many_subspaces() {
+        let vectors = vec![vec![1.0, 2.0]];
+        assert!(ProductQuantizer::train(&vectors, 3, 1, Metric::L2).is_err());
+        assert!(ProductQuantizer::train(&vectors, 0, 1, Metric::L2).is_err());
+    }
+
+    #[test]
+    fn test_rejects_vectors_of_other_dimensions() {
+        let vectors = vec![vec![1.0, 2.0, 3.0, 4.0], vec![5.0, 6.0]];
+        assert!(matches!(
+            ProductQuantizer::train(&vectors, 2, 1, Metric::L2),
+            Err(Error::InvalidInput(_))
+        ));
+
+        let quantizer = ProductQuantizer::train(&vectors[..1], 2, 1, Metric::L2).unwrap();
+        assert_eq!(2, quantizer.encode(&vectors[0]).unwrap().len());
+        assert!(matches!(
+            quantizer.encode(&vectors[1]),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            quantizer.encode(&[1.0, 2.0, 3.0, 4.0, 5.0]),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
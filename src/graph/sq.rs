@@ -0,0 +1,102 @@
+use simsimd::SpatialSimilarity;
+
+use crate::{metric::DISTANCE_SCALE, prelude::*, Metric};
+
+// ScalarQuantizer maps every f32 value x to the i8 round(x / scale), with a single scale for all dimensions.
+// The scale is symmetric around 0, so that distances between quantized vectors are the distances between the original
+// vectors up to a factor of scale^2, and can be computed with i8 kernels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScalarQuantizer {
+    scale: f32,
+}
+
+impl ScalarQuantizer {
+    pub fn new(scale: f32) -> Result<Self> {
+        if !scale.is_finite() || scale <= 0.0 {
+            return Err(Error::InvalidInput(format!(
+                "quantization scale must be positive, got {}",
+                scale
+            )));
+        }
+        Ok(ScalarQuantizer { scale })
+    }
+
+    // fit picks the scale that maps the largest absolute value of vectors to 127
+    pub fn fit(vectors: &[Vec<f32>]) -> Result<Self> {
+        let max = vectors
+            .iter()
+            .flatten()
+            .fold(0f32, |max, value| max.max(value.abs()));
+        Self::new(max / i8::MAX as f32)
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    // values beyond the fitted range are clamped
+    pub fn quantize(&self, vector: &[f32]) -> Vec<i8> {
+        vector
+            .iter()
+            .map(|value| (value / self.scale).round().clamp(-127.0, 127.0) as i8)
+            .collect()
+    }
+
+    pub fn dequantize(&self, quantized: &[i8]) -> Vec<f32> {
+        quantized
+            .iter()
+            .map(|value| *value as f32 * self.scale)
+            .collect()
+    }
+
+    // distance is Metric::distance of the dequantized vectors, computed on the quantized ones
+    pub fn distance(&self, metric: Metric, a: &[i8], b: &[i8]) -> i64 {
+        let squared_scale = self.scale as f64 * self.scale as f64;
+        let distance = match metric {
+            Metric::L2 => SpatialSimilarity::l2sq(a, b).unwrap() * squared_scale,
+            Metric::Cosine => SpatialSimilarity::cos(a, b).unwrap(),
+            // simsimd's i8 dot product computes the cosine distance instead, so it is summed here
+            Metric::InnerProduct => {
+                let dot: i64 = a.iter().zip(b).map(|(x, y)| *x as i64 * *y as i64).sum();
+                -(dot as f64) * squared_scale
+            }
+        };
+        (distance * DISTANCE_SCALE) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantize_round_trip() {
+        let quantizer = ScalarQuantizer::fit(&[vec![0.5, -1.27], vec![1.0, 0.0]]).unwrap();
+        assert_eq!(0.01, quantizer.scale());
+
+        let quantized = quantizer.quantize(&[0.5, -1.27, 2.0]);
+        assert_eq!(vec![50, -127, 127], quantized);
+        assert_eq!(vec![0.5, -1.27, 1.27], quantizer.dequantize(&quantized));
+
+        assert!(ScalarQuantizer::fit(&[vec![0.0]]).is_err());
+    }
+
+    #[test]
+    fn test_distance_matches_dequantized_distance() {
+        let quantizer = ScalarQuantizer::new(0.5).unwrap();
+        let a = quantizer.quantize(&[1.0, 0.0, 2.5]);
+        let b = quantizer.quantize(&[0.0, 3.0, -1.0]);
+
+        for metric in [Metric::L2, Metric::Cosine, Metric::InnerProduct] {
+            let expected = metric.distance(&quantizer.dequantize(&a), &quantizer.dequantize(&b));
+            let actual = quantizer.distance(metric, &a, &b);
+            assert!(
+                (expected - actual).abs() <= DISTANCE_SCALE as i64 / 1000,
+                "{:?} expected {} got {}",
+                metric,
+                expected,
+                actual
+            );
+        }
+    }
+}
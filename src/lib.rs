@@ -1,4 +1,6 @@
+pub mod dataset;
 pub mod error;
+pub mod eval;
 pub mod graph;
 pub mod prelude;
 pub mod storage;
@@ -2,12 +2,14 @@
 
 use clap::Parser;
 use cli::{Args, Dataset, Storage};
-use vdb::storage;
+use vdb::{dataset::VectorFile, storage};
 
 mod cli;
 mod data;
 mod dbpedia;
 mod debug;
+mod files;
+mod recall_cli;
 
 const MAX_NEIGHBOUR_COUNT: u8 = 5;
 const DBPEDIA_DIMENSIONS: usize = 1536;
@@ -21,8 +23,13 @@ fn main() {
                 args.storage_type,
                 DBPEDIA_DIMENSIONS as u16,
                 MAX_NEIGHBOUR_COUNT,
+                args.mmap,
+                args.fresh_disk_config(),
             );
-            let graph = dbpedia::index_dbpedia(storage, -1);
+            let mut graph =
+                dbpedia::index_dbpedia(storage, new_data_storage(args.storage_type), -1, &args);
+            train_pq(&mut graph, &args);
+            flush(&mut graph);
 
             let test_query_vec: [f32; DBPEDIA_DIMENSIONS] = data::read_query_vector()
                 .expect("Failed to read query vector")
@@ -32,40 +39,117 @@ fn main() {
 
             let similar_docs = dbpedia::query_dbpedia_index(&graph, &test_query_vec, 5);
             for doc in similar_docs {
-                println!("{}\n", doc);
+                println!(
+                    "[{:.6}] {}\n",
+                    doc.distance,
+                    String::from_utf8_lossy(&doc.data.unwrap_or_default())
+                );
+            }
+
+            if args.eval {
+                recall_cli::evaluate_graph(&graph, &args);
             }
         }
         Dataset::Debug => {
-            debug::debug(
+            let mut graph = debug::debug(
                 200,
                 std::ops::Range {
                     start: 0.0,
                     end: 2000.0,
                 },
                 args.storage_type,
+                args.metric.into(),
+                args.mmap,
+                args.fresh_disk_config(),
             );
+            flush(&mut graph);
 
-            if args.storage_type == Storage::FreshDisk {
-                std::thread::sleep(std::time::Duration::from_secs(10));
+            if args.eval {
+                recall_cli::evaluate_graph(&graph, &args);
             }
         }
+        Dataset::Files => {
+            let base =
+                VectorFile::open(args.base.as_ref().unwrap()).expect("Failed to open base vectors");
+            let storage = new_index_storage(
+                args.storage_type,
+                base.dimensions() as u16,
+                MAX_NEIGHBOUR_COUNT,
+                args.mmap,
+                args.fresh_disk_config(),
+            );
+            let mut graph =
+                files::index_files(base, storage, new_data_storage(args.storage_type), &args);
+            train_pq(&mut graph, &args);
+            flush(&mut graph);
+
+            if args.eval {
+                match &args.queries {
+                    Some(queries) => {
+                        files::evaluate_files(&graph, queries, args.ground_truth.as_deref(), &args)
+                    }
+                    None => recall_cli::evaluate_graph(&graph, &args),
+                }
+            }
+        }
+    }
+}
+
+fn train_pq(graph: &mut vdb::Graph, args: &Args) {
+    if let Some(subspace_count) = args.pq_subspaces {
+        let start = std::time::Instant::now();
+        graph.train_pq(subspace_count).unwrap();
+        println!("graph::train_pq took {:?}", start.elapsed());
     }
 }
 
+// persist the graph before the process exits, as FreshDisk merges updates into its long term index in the background
+fn flush(graph: &mut vdb::Graph) {
+    let start = std::time::Instant::now();
+    graph.flush().expect("Failed to flush the graph");
+    println!("graph::flush took {:?}", start.elapsed());
+}
+
 fn new_index_storage(
     storage_type: Storage,
     dimensions: u16,
     max_neighbour_count: u8,
+    mmap: bool,
+    fresh_disk_config: storage::FreshDiskConfig,
 ) -> Box<dyn storage::IndexStore> {
+    let mut disk = match storage_type {
+        Storage::InMem => return Box::new(storage::InMemStorage::default()),
+        Storage::FreshDisk => {
+            let mut disk =
+                storage::FreshDisk::new(dimensions, max_neighbour_count, "disk.index", "disk.free")
+                    .unwrap();
+            disk.set_config(fresh_disk_config);
+            return Box::new(disk);
+        }
+        Storage::PureDisk => {
+            storage::NaiveDisk::new(dimensions, max_neighbour_count, "disk.index", "disk.free")
+                .unwrap()
+        }
+        Storage::PureDiskInt8 => storage::NaiveDisk::new_quantized(
+            dimensions,
+            max_neighbour_count,
+            "disk.index",
+            "disk.free",
+        )
+        .unwrap(),
+    };
+    if mmap {
+        disk.set_read_mode(storage::ReadMode::Mmap).unwrap();
+    }
+    Box::new(disk)
+}
+
+// the data of nodes is kept next to the index, on disk unless the index is in RAM
+fn new_data_storage(storage_type: Storage) -> Box<dyn storage::DataStore> {
     match storage_type {
         Storage::InMem => Box::new(storage::InMemStorage::default()),
-        Storage::PureDisk => Box::new(
-            storage::NaiveDisk::new(dimensions, max_neighbour_count, "disk.index", "disk.free")
-                .unwrap(),
-        ),
-        Storage::FreshDisk => Box::new(
-            storage::FreshDisk::new(dimensions, max_neighbour_count, "disk.index", "disk.free")
-                .unwrap(),
-        ),
+        Storage::PureDisk | Storage::PureDiskInt8 | Storage::FreshDisk => {
+            Box::new(storage::DiskDataStore::new("disk.data", "disk.offsets").unwrap())
+        }
     }
 }
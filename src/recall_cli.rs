@@ -0,0 +1,85 @@
+use rand::seq::SliceRandom;
+use vdb::{
+    eval::{self, EvalReport},
+    Graph,
+};
+
+use crate::cli::Args;
+
+// evaluate_graph prints recall@k, latency and nodes visited of graph for each search list size, using the vectors of random
+// indexed nodes as queries. Each query node is held out of its own ground truth and results, see eval::evaluate_held_out
+pub(super) fn evaluate_graph(graph: &Graph, args: &Args) {
+    let mut node_indexes = graph
+        .index_store
+        .get_all_node_indexes()
+        .expect("Failed to read node indexes");
+    node_indexes.retain(|node_index| !graph.index_store.is_deleted(*node_index));
+    let query_node_indexes: Vec<u32> = node_indexes
+        .choose_multiple(&mut rand::thread_rng(), args.eval_queries)
+        .copied()
+        .collect();
+
+    let start = std::time::Instant::now();
+    let reports = eval::evaluate_held_out(
+        graph,
+        &query_node_indexes,
+        args.eval_k,
+        &args.eval_search_list_sizes,
+    )
+    .unwrap();
+    println!("held out evaluation took {:?}", start.elapsed());
+    print_reports(&reports, args);
+}
+
+// evaluate_queries is evaluate_graph for the given queries. ground_truth holds the node indexes of the nearest neighbours of
+// every query, and is computed by brute force when missing
+pub(super) fn evaluate_queries(
+    graph: &Graph,
+    queries: &[Vec<f32>],
+    ground_truth: Option<Vec<Vec<u32>>>,
+    args: &Args,
+) {
+    let ground_truth = ground_truth.unwrap_or_else(|| {
+        let start = std::time::Instant::now();
+        let ground_truth = eval::brute_force_knn(
+            &*graph.index_store,
+            graph.index_store.get_metric(),
+            queries,
+            args.eval_k,
+        )
+        .unwrap();
+        println!("brute force ground truth took {:?}", start.elapsed());
+        ground_truth
+    });
+
+    let reports = eval::evaluate(
+        graph,
+        queries,
+        &ground_truth,
+        args.eval_k,
+        &args.eval_search_list_sizes,
+    )
+    .unwrap();
+    print_reports(&reports, args);
+}
+
+fn print_reports(reports: &[EvalReport], args: &Args) {
+    println!(
+        "{:>6} {:>10} {:>14} {:>14} {:>10}",
+        "L",
+        format!("recall@{}", args.eval_k),
+        "mean latency",
+        "p99 latency",
+        "visited"
+    );
+    for report in reports {
+        println!(
+            "{:>6} {:>10.4} {:>14} {:>14} {:>10.1}",
+            report.search_list_size,
+            report.recall,
+            format!("{:?}", report.mean_latency),
+            format!("{:?}", report.p99_latency),
+            report.mean_visited
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+use crate::prelude::*;
+use crate::Error;
+
+use super::storage::DataStore;
+
+// disk layout
+// key principle: like the index, the payload of each node is found in O(1) from its node id
+//
+// .data file, append-only:
+// [payload][payload]...
+// [u8 * length of the payload]
+//
+// .offsets file, one slot per node id, at node_id * slot size:
+// [present][offset][length]
+// [  u8   ][ u64  ][ u32  ]
+//
+// a slot is all zeros, as are holes in the file, when its node has no payload. Payloads are appended to the .data file
+// before their slot is written, so a crash in between only leaves unreferenced bytes at the end of the .data file
+//
+// TODO:
+// 1. payloads that were overwritten or deleted stay in the .data file, compact it
+pub struct DiskDataStore {
+    data_file: File,
+    offsets_file: File,
+    // (offset, length) of the payload of every node, as in the .offsets file
+    offsets: HashMap<u32, (u64, u32)>,
+    // where the next payload is appended
+    data_len: u64,
+}
+
+const SLOT_SIZE: u64 = 1 + 8 + 4;
+
+impl DiskDataStore {
+    // initialise a new data store, truncating any existing store at data_path and offsets_path
+    pub fn new(data_path: &str, offsets_path: &str) -> Result<Self> {
+        let data_file = Self::open_file(data_path, true)?;
+        let offsets_file = Self::open_file(offsets_path, true)?;
+        Ok(DiskDataStore {
+            data_file,
+            offsets_file,
+            offsets: HashMap::new(),
+            data_len: 0,
+        })
+    }
+
+    // reopen a data store previously created with DiskDataStore::new, keeping its payloads
+    pub fn open(data_path: &str, offsets_path: &str) -> Result<Self> {
+        let data_file = Self::open_file(data_path, false)?;
+        let offsets_file = Self::open_file(offsets_path, false)?;
+        let data_len = data_file.metadata()?.len();
+
+        let offsets_len = offsets_file.metadata()?.len();
+        if offsets_len % SLOT_SIZE != 0 {
+            return Err(Error::InvalidInput(format!(
+                "offsets file {} is not a list of slots",
+                offsets_path
+            )));
+        }
+        let mut buffer = vec![0u8; offsets_len as usize];
+        offsets_file.read_exact_at(&mut buffer, 0)?;
+
+        let mut offsets = HashMap::new();
+        for (node_id, slot) in buffer.chunks_exact(SLOT_SIZE as usize).enumerate() {
+            let Some((offset, length)) = Self::decode_slot(slot) else {
+                continue;
+            };
+            if offset + length as u64 > data_len {
+                return Err(Error::InvalidInput(format!(
+                    "offsets file {} points past the end of data file {} for node {}",
+                    offsets_path, data_path, node_id
+                )));
+            }
+            offsets.insert(node_id as u32, (offset, length));
+        }
+
+        Ok(DiskDataStore {
+            data_file,
+            offsets_file,
+            offsets,
+            data_len,
+        })
+    }
+
+    fn open_file(path: &str, truncate: bool) -> Result<File> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(truncate)
+            .truncate(truncate)
+            .open(path)?)
+    }
+
+    // fsync the data and offsets files
+    pub fn sync(&self) -> Result<()> {
+        self.data_file.sync_all()?;
+        self.offsets_file.sync_all()?;
+        Ok(())
+    }
+
+    fn encode_slot(slot: Option<(u64, u32)>) -> [u8; SLOT_SIZE as usize] {
+        let mut bytes = [0u8; SLOT_SIZE as usize];
+        if let Some((offset, length)) = slot {
+            bytes[0] = 1;
+            bytes[1..9].copy_from_slice(&offset.to_be_bytes());
+            bytes[9..13].copy_from_slice(&length.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn decode_slot(bytes: &[u8]) -> Option<(u64, u32)> {
+        if bytes[0] == 0 {
+            return None;
+        }
+        let offset = u64::from_be_bytes(bytes[1..9].try_into().unwrap());
+        let length = u32::from_be_bytes(bytes[9..13].try_into().unwrap());
+        Some((offset, length))
+    }
+
+    fn write_slot(&self, node_id: u32, slot: Option<(u64, u32)>) -> Result<()> {
+        self.offsets_file
+            .write_all_at(&Self::encode_slot(slot), node_id as u64 * SLOT_SIZE)?;
+        Ok(())
+    }
+}
+
+impl DataStore for DiskDataStore {
+    fn add_data(&mut self, node_id: u32, data: &[u8]) -> Result<()> {
+        let length = u32::try_from(data.len()).map_err(|_| {
+            Error::InvalidInput(format!(
+                "payload of node {} is {} bytes, at most {} bytes are supported",
+                node_id,
+                data.len(),
+                u32::MAX
+            ))
+        })?;
+
+        let offset = self.data_len;
+        self.data_file.write_all_at(data, offset)?;
+        self.data_len += length as u64;
+        self.write_slot(node_id, Some((offset, length)))?;
+        self.offsets.insert(node_id, (offset, length));
+        Ok(())
+    }
+
+    fn get_data(&self, node_id: u32) -> Result<Option<Vec<u8>>> {
+        let Some(&(offset, length)) = self.offsets.get(&node_id) else {
+            return Ok(None);
+        };
+        let mut data = vec![0u8; length as usize];
+        self.data_file.read_exact_at(&mut data, offset)?;
+        Ok(Some(data))
+    }
+
+    fn delete_data(&mut self, node_id: u32) -> Result<()> {
+        if self.offsets.remove(&node_id).is_some() {
+            self.write_slot(node_id, None)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+
+    fn as_sync(&self) -> Option<&(dyn DataStore + Sync)> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn paths(name: &str) -> (String, String) {
+        let temp_dir = env::temp_dir();
+        (
+            temp_dir
+                .join(format!("{}.data", name))
+                .to_str()
+                .unwrap()
+                .to_string(),
+            temp_dir
+                .join(format!("{}.offsets", name))
+                .to_str()
+                .unwrap()
+                .to_string(),
+        )
+    }
+
+    #[test]
+    fn test_add_get_and_delete_data() {
+        let (data_path, offsets_path) = paths("test_data_store");
+        let mut store = DiskDataStore::new(&data_path, &offsets_path).unwrap();
+
+        store.add_data(1, b"first").unwrap();
+        store.add_data(7, br#"{"title": "seventh"}"#).unwrap();
+        store.add_data(2, b"").unwrap();
+        assert_eq!(Some(b"first".to_vec()), store.get_data(1).unwrap());
+        assert_eq!(
+            Some(br#"{"title": "seventh"}"#.to_vec()),
+            store.get_data(7).unwrap()
+        );
+        assert_eq!(Some(Vec::new()), store.get_data(2).unwrap());
+        assert_eq!(None, store.get_data(3).unwrap());
+
+        // overwriting appends the new payload, deleting only clears the slot
+        store.add_data(1, b"overwritten").unwrap();
+        store.delete_data(7).unwrap();
+        store.delete_data(8).unwrap();
+        assert_eq!(Some(b"overwritten".to_vec()), store.get_data(1).unwrap());
+        assert_eq!(None, store.get_data(7).unwrap());
+    }
+
+    #[test]
+    fn test_open_existing_data_store() {
+        let (data_path, offsets_path) = paths("test_data_store_open");
+        let mut store = DiskDataStore::new(&data_path, &offsets_path).unwrap();
+        store.add_data(1, b"first").unwrap();
+        store.add_data(2, b"second").unwrap();
+        store.add_data(3, b"third").unwrap();
+        store.delete_data(2).unwrap();
+        store.add_data(3, b"third again").unwrap();
+        drop(store);
+
+        let mut reopened = DiskDataStore::open(&data_path, &offsets_path).unwrap();
+        assert_eq!(Some(b"first".to_vec()), reopened.get_data(1).unwrap());
+        assert_eq!(None, reopened.get_data(2).unwrap());
+        assert_eq!(Some(b"third again".to_vec()), reopened.get_data(3).unwrap());
+
+        // appends continue after the existing payloads
+        reopened.add_data(4, b"fourth").unwrap();
+        assert_eq!(Some(b"first".to_vec()), reopened.get_data(1).unwrap());
+        assert_eq!(Some(b"fourth".to_vec()), reopened.get_data(4).unwrap());
+
+        // a slot pointing past the end of the data file means that the files don't belong together
+        drop(reopened);
+        OpenOptions::new()
+            .write(true)
+            .open(&data_path)
+            .unwrap()
+            .set_len(3)
+            .unwrap();
+        assert!(DiskDataStore::open(&data_path, &offsets_path).is_err());
+    }
+}
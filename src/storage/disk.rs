@@ -1,18 +1,83 @@
+use memmap2::Mmap;
 use rand::Rng;
 
 use crate::error;
-use crate::graph::Node;
+use crate::graph::{sq::ScalarQuantizer, Metric, Node};
 use crate::prelude::Result;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::OpenOptions;
 use std::io::{self, Error, ErrorKind};
 use std::{
     collections::HashSet,
     fs::File,
     io::{BufWriter, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
 };
 
-use super::storage::IndexStore;
+use super::io::{DiskIo, IoBackend};
+use super::storage::{IndexStore, MAX_ENTRY_POINTS};
+
+// ReadMode is how NaiveDisk reads node records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    // open, seek and read the index file on every read
+    File,
+    // look records up in a memory map of the index file, which is remapped when the file grows
+    Mmap,
+}
+
+// VectorRef is a node's vector borrowed from the memory map of a NaiveDisk, in its on-disk encoding
+#[derive(Debug, Clone, Copy)]
+pub enum VectorRef<'a> {
+    // big-endian f32 values
+    F32(&'a [u8]),
+    // values of a quantized index, with the quantizer that maps them back to f32
+    I8(&'a [i8], ScalarQuantizer),
+}
+
+impl VectorRef<'_> {
+    pub fn len(&self) -> usize {
+        match self {
+            VectorRef::F32(bytes) => bytes.len() / std::mem::size_of::<f32>(),
+            VectorRef::I8(values, _) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_vec(&self) -> Vec<f32> {
+        match self {
+            VectorRef::F32(bytes) => bytes
+                .chunks_exact(std::mem::size_of::<f32>())
+                .map(|chunk| f32::from_be_bytes(chunk.try_into().unwrap()))
+                .collect(),
+            VectorRef::I8(values, quantizer) => quantizer.dequantize(values),
+        }
+    }
+
+    // distance from query under metric, without copying the vector out of the map.
+    // Quantized vectors are compared with query_quantized, the query quantized with the index's quantizer
+    pub fn distance(&self, metric: Metric, query: &[f32], query_quantized: Option<&[i8]>) -> i64 {
+        match (self, query_quantized) {
+            (VectorRef::F32(bytes), _) => metric.distance_be(query, bytes),
+            (VectorRef::I8(values, quantizer), Some(query_quantized)) => {
+                quantizer.distance(metric, query_quantized, values)
+            }
+            (VectorRef::I8(values, quantizer), None) => {
+                metric.distance(query, &quantizer.dequantize(values))
+            }
+        }
+    }
+}
+
+// get_nodes reads through gaps of up to COALESCE_GAP slots, as reading a few unneeded records is cheaper than another seek
+const COALESCE_GAP: u32 = 4;
+
+// records re-quantized per read and write when the quantization scale grows, see fit_quantizer
+const REQUANTIZE_BATCH_SIZE: usize = 1024;
+
 // disk layout
 // key principle: lookup for each node index must be O(1)
 //
@@ -20,60 +85,415 @@ use super::storage::IndexStore;
 // [free indices]
 // [ u32 * as many]
 //
+// .deleted file, named after the index file (e.g. disk.index.deleted):
+// [tombstoned indices]
+// [ u32 * as many   ]
+//
 // .index file:
 // [metadata][nodes]
 //
 // where [metadata]:
-// [dim][max_neighbor_count][next node index]
-// [u16][      u8          ][u32            ]
+// [dim][max_neighbor_count][next node index][metric][entry point count][entry points (padded)   ][quantized][quantization scale]
+// [u16][      u8          ][u32            ][ u8   ][       u8        ][u32 * MAX_ENTRY_POINTS ][   u8    ][      f32         ]
 //
 // where [nodes]:
-// [node_id][vector      ][    neighbor indexes                  ]
-// [ u32   ][ f32 * dim  ][  u32 * max_neighbor_count (padded)   ]
+// [node_id][vector            ][    neighbor indexes                  ]
+// [ u32   ][ f32 or i8 * dim  ][  u32 * max_neighbor_count (padded)   ]
+//
+// node_id is 0 for free slots, which are listed in the .free file and reused by add_nodes
+//
+// vectors are stored as i8 when quantized is 1. The quantization scale is fitted on the first batch of add_nodes, and 0 until then.
+// It grows when a later batch holds larger values, and the stored vectors are then re-quantized with it into a copy of the
+// index file (e.g. disk.index.requantize) that replaces it. open removes a copy left by a crash before the replacement
+//
+// callers that pick their own ids map them onto node indexes with an IdMap, see Graph::upsert
 //
 // TODO:
 // 1. log based input instead
-// 2. decouple node_id from node index
 pub struct NaiveDisk {
     dimensions: u16,
     max_neighbour_count: u8,
     next_node_index: u32,
+    metric: Metric,
+    entry_points: Vec<u32>,
     index_path: String,
     free_path: String,
+    deleted_path: String,
+    // slots of removed nodes, reused by add_nodes before the index file is grown
+    free_list: BTreeSet<u32>,
+    // tombstoned nodes, logged to the .deleted file until they are removed by Graph::consolidate_deletes
+    deleted: HashSet<u32>,
+    quantized: bool,
+    quantizer: Option<ScalarQuantizer>,
+    // batched reads and writes of node records go through io
+    io: DiskIo,
+    // the index file mapped into memory, in ReadMode::Mmap
+    mmap: Option<Mmap>,
 }
 
 impl NaiveDisk {
-    // initialise a new disk backend
-    // TODO: allow reading old copy
+    // initialise a new disk backend, truncating any existing index at index_path
     pub fn new(
         dimensions: u16,
         max_neighbor_count: u8,
         index_path: &str,
         free_path: &str,
+    ) -> Result<Self> {
+        Self::create(dimensions, max_neighbor_count, false, index_path, free_path)
+    }
+
+    // new_quantized is new for an index that stores vectors as i8, about 4 times smaller than f32.
+    // The range of the quantizer grows with the values added, so that none are clamped
+    pub fn new_quantized(
+        dimensions: u16,
+        max_neighbor_count: u8,
+        index_path: &str,
+        free_path: &str,
+    ) -> Result<Self> {
+        Self::create(dimensions, max_neighbor_count, true, index_path, free_path)
+    }
+
+    fn create(
+        dimensions: u16,
+        max_neighbor_count: u8,
+        quantized: bool,
+        index_path: &str,
+        free_path: &str,
     ) -> Result<Self> {
         let mut index_file = BufWriter::new(File::create(index_path)?);
 
         // Write metadata to index file
         index_file.write_all(&dimensions.to_be_bytes())?;
         index_file.write_all(&max_neighbor_count.to_be_bytes())?;
-        index_file.write_all(&(0u32).to_be_bytes())?;
+        index_file.write_all(&(1u32).to_be_bytes())?;
+        index_file.write_all(&[Metric::default() as u8])?;
+        index_file.write_all(&[0u8])?;
+        index_file.write_all(&[0u8; MAX_ENTRY_POINTS * 4])?;
+        index_file.write_all(&[quantized as u8])?;
+        index_file.write_all(&0f32.to_be_bytes())?;
+        index_file.flush()?;
+
+        File::create(free_path)?;
+        File::create(Self::deleted_path(index_path))?;
 
         Ok(NaiveDisk {
-            dimensions: dimensions,
+            dimensions,
             max_neighbour_count: max_neighbor_count,
             next_node_index: 1,
+            metric: Metric::default(),
+            entry_points: Vec::new(),
             index_path: index_path.to_string(),
             free_path: free_path.to_string(),
+            deleted_path: Self::deleted_path(index_path),
+            free_list: BTreeSet::new(),
+            deleted: HashSet::new(),
+            quantized,
+            quantizer: None,
+            io: DiskIo::new(IoBackend::IoUring),
+            mmap: None,
         })
     }
 
+    // reopen a disk backend previously created with NaiveDisk::new, keeping its nodes and connections
+    pub fn open(index_path: &str, free_path: &str) -> Result<Self> {
+        // a copy left by a crash during requantize was never renamed over the index, which still holds the old scale
+        match std::fs::remove_file(Self::requantize_path(index_path)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let mut index_file = File::open(index_path)?;
+
+        let mut metadata = [0u8; 8];
+        index_file.read_exact(&mut metadata)?;
+        let dimensions = u16::from_be_bytes(metadata[0..2].try_into().unwrap());
+        let max_neighbour_count = metadata[2];
+        let next_node_index = u32::from_be_bytes(metadata[3..7].try_into().unwrap());
+        let metric = Metric::try_from(metadata[7])?;
+
+        let mut entry_points_metadata = [0u8; 1 + MAX_ENTRY_POINTS * 4];
+        index_file.read_exact(&mut entry_points_metadata)?;
+        let entry_point_count = entry_points_metadata[0] as usize;
+        let entry_points: Vec<u32> = entry_points_metadata[1..]
+            .chunks_exact(4)
+            .take(entry_point_count)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        let mut quantization_metadata = [0u8; 5];
+        index_file.read_exact(&mut quantization_metadata)?;
+        let quantized = quantization_metadata[0] == 1;
+        let scale = f32::from_be_bytes(quantization_metadata[1..5].try_into().unwrap());
+        let quantizer = if quantized && scale != 0.0 {
+            Some(ScalarQuantizer::new(scale)?)
+        } else {
+            None
+        };
+
+        if dimensions == 0
+            || max_neighbour_count == 0
+            || quantization_metadata[0] > 1
+            || next_node_index == 0
+            || entry_point_count > MAX_ENTRY_POINTS
+            || entry_points.contains(&0)
+        {
+            return Err(error::Error::InvalidInput(format!(
+                "invalid index metadata in {}",
+                index_path
+            )));
+        }
+
+        let mut disk = NaiveDisk {
+            dimensions,
+            max_neighbour_count,
+            next_node_index,
+            metric,
+            entry_points,
+            index_path: index_path.to_string(),
+            free_path: free_path.to_string(),
+            deleted_path: Self::deleted_path(index_path),
+            free_list: BTreeSet::new(),
+            deleted: HashSet::new(),
+            quantized,
+            quantizer,
+            io: DiskIo::new(IoBackend::IoUring),
+            mmap: None,
+        };
+
+        // every node slot before next_node_index must be fully written
+        let expected_len = disk.node_offset(next_node_index);
+        let actual_len = index_file.metadata()?.len();
+        if actual_len != expected_len {
+            return Err(error::Error::InvalidInput(format!(
+                "index file {} is {} bytes, expected {} bytes for {} nodes",
+                index_path,
+                actual_len,
+                expected_len,
+                next_node_index - 1
+            )));
+        }
+
+        disk.free_list = disk.read_node_list(&disk.free_path)?.into_iter().collect();
+        // a tombstone of a freed slot was removed before the .deleted file was rewritten
+        disk.deleted = disk
+            .read_node_list(&disk.deleted_path)?
+            .into_iter()
+            .filter(|node_index| !disk.free_list.contains(node_index))
+            .collect();
+
+        Ok(disk)
+    }
+
+    fn deleted_path(index_path: &str) -> String {
+        format!("{}.deleted", index_path)
+    }
+
+    fn requantize_path(index_path: &str) -> String {
+        format!("{}.requantize", index_path)
+    }
+
+    // read a list of node indexes from the .free or .deleted file. A missing file means an empty list
+    fn read_node_list(&self, path: &str) -> Result<Vec<u32>> {
+        let mut file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        if buffer.len() % self.index_node_id_size() != 0 {
+            return Err(error::Error::InvalidInput(format!(
+                "{} is not a list of node indexes",
+                path
+            )));
+        }
+
+        let mut node_indexes = Vec::new();
+        for chunk in buffer.chunks_exact(self.index_node_id_size()) {
+            let node_index = u32::from_be_bytes(chunk.try_into().unwrap());
+            if node_index == 0 || node_index >= self.next_node_index {
+                return Err(error::Error::InvalidInput(format!(
+                    "{} contains invalid node index {}",
+                    path, node_index
+                )));
+            }
+            node_indexes.push(node_index);
+        }
+        Ok(node_indexes)
+    }
+
+    // rewrite the .free file with the current free list
+    fn write_free_list(&self) -> io::Result<()> {
+        let mut free_file = BufWriter::new(File::create(&self.free_path)?);
+        for node_index in &self.free_list {
+            free_file.write_all(&node_index.to_be_bytes())?;
+        }
+        free_file.flush()
+    }
+
+    // rewrite the .deleted file with the current tombstones
+    fn write_deleted_list(&self) -> io::Result<()> {
+        let mut deleted_file = BufWriter::new(File::create(&self.deleted_path)?);
+        for node_index in &self.deleted {
+            deleted_file.write_all(&node_index.to_be_bytes())?;
+        }
+        deleted_file.flush()
+    }
+
+    pub fn dimensions(&self) -> u16 {
+        self.dimensions
+    }
+
+    pub fn max_neighbour_count(&self) -> u8 {
+        self.max_neighbour_count
+    }
+
+    // set_io_backend switches how batches of node records are read and written, and returns the backend in use,
+    // which is Pread when io_uring isn't available
+    pub fn set_io_backend(&mut self, backend: IoBackend) -> IoBackend {
+        self.io = DiskIo::new(backend);
+        self.io.backend()
+    }
+
+    pub fn io_backend(&self) -> IoBackend {
+        self.io.backend()
+    }
+
+    pub fn set_read_mode(&mut self, mode: ReadMode) -> Result<()> {
+        self.mmap = match mode {
+            ReadMode::File => None,
+            ReadMode::Mmap => Some(self.map_index_file()?),
+        };
+        Ok(())
+    }
+
+    pub fn read_mode(&self) -> ReadMode {
+        match self.mmap {
+            Some(_) => ReadMode::Mmap,
+            None => ReadMode::File,
+        }
+    }
+
+    // vector returns the vector of node_index borrowed from the memory map. Only available in ReadMode::Mmap
+    pub fn vector(&self, node_index: u32) -> Result<VectorRef<'_>> {
+        if node_index == 0 {
+            return Err(error::Error::InvalidInput("node id cannot be 0".to_owned()));
+        }
+        let record = self
+            .mapped_record(node_index)
+            .ok_or_else(|| error::Error::InvalidInput("index is not memory mapped".to_owned()))??;
+
+        let node_id = u32::from_be_bytes(record[0..self.index_node_id_size()].try_into().unwrap());
+        // node_id = 0 is reserved for empty
+        if node_id == 0 {
+            return Err(error::Error::InvalidInput("node not found".to_owned()));
+        }
+
+        let bytes = &record[self.index_node_id_size()
+            ..self.index_node_id_size()
+                + self.dimensions as usize * self.index_node_vector_element_size()];
+        match &self.quantizer {
+            Some(quantizer) if self.quantized => {
+                // SAFETY: i8 has the size and alignment of u8
+                let values =
+                    unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const i8, bytes.len()) };
+                Ok(VectorRef::I8(values, *quantizer))
+            }
+            _ => Ok(VectorRef::F32(bytes)),
+        }
+    }
+
+    fn map_index_file(&self) -> io::Result<Mmap> {
+        let index_file = File::open(&self.index_path)?;
+        // SAFETY: the index file is only written through this NaiveDisk, which never truncates it once created.
+        // Writes to the file show up in the shared mapping, and the mapping is remapped when the file grows
+        unsafe { Mmap::map(&index_file) }
+    }
+
+    // remap the index file after it has grown, so that records of new slots can be looked up
+    fn remap(&mut self) -> io::Result<()> {
+        if let Some(mmap) = &self.mmap {
+            if (mmap.len() as u64) < self.node_offset(self.next_node_index) {
+                self.mmap = Some(self.map_index_file()?);
+            }
+        }
+        Ok(())
+    }
+
+    // mapped_record returns the record of node_index from the memory map, or None in ReadMode::File
+    fn mapped_record(&self, node_index: u32) -> Option<Result<&[u8]>> {
+        let mmap = self.mmap.as_ref()?;
+        let offset = self.node_offset(node_index) as usize;
+        Some(
+            mmap.get(offset..offset + self.index_node_size())
+                .ok_or_else(|| error::Error::InvalidInput("node not found".to_owned())),
+        )
+    }
+
+    pub(crate) fn next_node_index(&self) -> u32 {
+        self.next_node_index
+    }
+
+    // has_node tells whether the slot of node_index holds a node, without reading it
+    pub(crate) fn has_node(&self, node_index: u32) -> bool {
+        node_index != 0
+            && node_index < self.next_node_index
+            && !self.free_list.contains(&node_index)
+    }
+
+    // free_slots are the freed slots that add_nodes fills before growing the index
+    pub(crate) fn free_slots(&self) -> &BTreeSet<u32> {
+        &self.free_list
+    }
+
+    // fsync the index, free and deleted files
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.index_path)?
+            .sync_all()?;
+        OpenOptions::new()
+            .write(true)
+            .open(&self.free_path)?
+            .sync_all()?;
+        // indexes created before tombstones were persisted have no .deleted file
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.deleted_path)?
+            .sync_all()
+    }
+
+    // persist next_node_index into the metadata header
+    fn write_next_node_index(&self, index_file: &mut File) -> io::Result<()> {
+        index_file.seek(SeekFrom::Start(self.next_node_index_offset()))?;
+        index_file.write_all(&self.next_node_index.to_be_bytes())
+    }
+
+    fn next_node_index_offset(&self) -> u64 {
+        (std::mem::size_of_val(&self.dimensions) + std::mem::size_of_val(&self.max_neighbour_count))
+            as u64
+    }
+
+    fn metric_offset(&self) -> u64 {
+        self.next_node_index_offset() + std::mem::size_of_val(&self.next_node_index) as u64
+    }
+
+    fn entry_points_offset(&self) -> u64 {
+        self.metric_offset() + std::mem::size_of::<u8>() as u64
+    }
+
+    fn quantization_offset(&self) -> u64 {
+        self.entry_points_offset()
+            + (std::mem::size_of::<u8>() + MAX_ENTRY_POINTS * self.index_node_id_size()) as u64
+    }
+
     fn index_metadata_size(&self) -> usize {
-        std::mem::size_of_val(&self.dimensions)
-            + std::mem::size_of_val(&self.max_neighbour_count)
-            + std::mem::size_of_val(&self.next_node_index)
+        self.quantization_offset() as usize + std::mem::size_of::<u8>() + std::mem::size_of::<f32>()
     }
 
-    fn index_node_size(&self) -> usize {
+    pub(crate) fn index_node_size(&self) -> usize {
         self.index_node_id_size()
             + (self.dimensions as usize * self.index_node_vector_element_size())
             + (self.max_neighbour_count as usize * self.index_node_id_size())
@@ -84,7 +504,138 @@ impl NaiveDisk {
     }
 
     fn index_node_vector_element_size(&self) -> usize {
-        std::mem::size_of::<f32>()
+        if self.quantized {
+            std::mem::size_of::<i8>()
+        } else {
+            std::mem::size_of::<f32>()
+        }
+    }
+
+    // fit the quantizer of a quantized index on the first vectors added to it, and persist its scale. Later vectors
+    // with larger values grow the scale, and the stored vectors, which all lie within the old range, are re-quantized.
+    // Growing the scale over the batches keeps the quantizer fitted on every vector without reading the input twice
+    fn fit_quantizer(&mut self, data: &[Vec<f32>]) -> Result<()> {
+        if !self.quantized || data.is_empty() {
+            return Ok(());
+        }
+        let max = data
+            .iter()
+            .flatten()
+            .fold(0f32, |max, value| max.max(value.abs()));
+        if self
+            .quantizer
+            .is_some_and(|quantizer| max <= quantizer.scale() * i8::MAX as f32)
+        {
+            return Ok(());
+        }
+
+        let quantizer = ScalarQuantizer::fit(data)?;
+        match self.quantizer {
+            Some(previous) => self.requantize(previous, quantizer)?,
+            None => {
+                let mut index_file = OpenOptions::new().write(true).open(&self.index_path)?;
+                index_file.seek(SeekFrom::Start(
+                    self.quantization_offset() + std::mem::size_of::<u8>() as u64,
+                ))?;
+                index_file.write_all(&quantizer.scale().to_be_bytes())?;
+            }
+        }
+        self.quantizer = Some(quantizer);
+        Ok(())
+    }
+
+    // requantize rewrites every stored vector, quantized with previous, with quantizer.
+    // The records and the new scale are written to a copy of the index file, which is renamed over it once synced,
+    // so that a crash leaves either the old or the new file and never vectors at two scales
+    fn requantize(
+        &mut self,
+        previous: ScalarQuantizer,
+        quantizer: ScalarQuantizer,
+    ) -> io::Result<()> {
+        let index_file = File::open(&self.index_path)?;
+        let requantize_path = Self::requantize_path(&self.index_path);
+        let requantize_file = File::create(&requantize_path)?;
+
+        let mut metadata = vec![0u8; self.index_metadata_size()];
+        index_file.read_exact_at(&mut metadata, 0)?;
+        let scale_offset = self.quantization_offset() as usize + std::mem::size_of::<u8>();
+        metadata[scale_offset..scale_offset + 4].copy_from_slice(&quantizer.scale().to_be_bytes());
+        requantize_file.write_all_at(&metadata, 0)?;
+
+        let node_size = self.index_node_size();
+        let vector_offset = self.index_node_id_size();
+        let vector_end = vector_offset + self.dimensions as usize;
+
+        let mut buffer = vec![0u8; node_size * REQUANTIZE_BATCH_SIZE];
+        let mut node_index = 1;
+        while node_index < self.next_node_index {
+            let count = ((self.next_node_index - node_index) as usize).min(REQUANTIZE_BATCH_SIZE);
+            let records = &mut buffer[..count * node_size];
+            let offset = self.node_offset(node_index);
+            index_file.read_exact_at(records, offset)?;
+            for record in records.chunks_exact_mut(node_size) {
+                let vector = &mut record[vector_offset..vector_end];
+                let values: Vec<i8> = vector.iter().map(|value| *value as i8).collect();
+                let requantized = quantizer.quantize(&previous.dequantize(&values));
+                for (byte, value) in vector.iter_mut().zip(requantized) {
+                    *byte = value as u8;
+                }
+            }
+            requantize_file.write_all_at(records, offset)?;
+            node_index += count as u32;
+        }
+        requantize_file.sync_all()?;
+        std::fs::rename(&requantize_path, &self.index_path)?;
+
+        // the old mapping still points at the replaced file
+        if self.mmap.is_some() {
+            self.mmap = Some(self.map_index_file()?);
+        }
+        Ok(())
+    }
+
+    fn write_vector(&self, index_file: &mut impl Write, vector: &[f32]) -> io::Result<()> {
+        if vector.len() != self.dimensions as usize {
+            return Err(Error::other(format!(
+                "vector of {} dimensions in an index of {} dimensions",
+                vector.len(),
+                self.dimensions
+            )));
+        }
+        match (self.quantized, &self.quantizer) {
+            (false, _) => {
+                for value in vector {
+                    index_file.write_all(&value.to_be_bytes())?;
+                }
+                Ok(())
+            }
+            (true, Some(quantizer)) => {
+                let quantized: Vec<u8> = quantizer
+                    .quantize(vector)
+                    .into_iter()
+                    .map(|value| value as u8)
+                    .collect();
+                index_file.write_all(&quantized)
+            }
+            (true, None) => Err(Error::other("quantizer of the index is not fitted")),
+        }
+    }
+
+    // read_vector returns the f32 vector, and the i8 vector too if the index is quantized
+    fn read_vector(&self, bytes: &[u8]) -> (Vec<f32>, Option<Vec<i8>>) {
+        match &self.quantizer {
+            Some(quantizer) if self.quantized => {
+                let quantized: Vec<i8> = bytes.iter().map(|value| *value as i8).collect();
+                (quantizer.dequantize(&quantized), Some(quantized))
+            }
+            _ => {
+                let vector = bytes
+                    .chunks_exact(self.index_node_vector_element_size())
+                    .map(|chunk| f32::from_be_bytes(chunk.try_into().unwrap()))
+                    .collect();
+                (vector, None)
+            }
+        }
     }
 
     fn node_offset(&self, node_index: u32) -> u64 {
@@ -99,32 +650,152 @@ impl NaiveDisk {
             + (self.dimensions as usize * self.index_node_vector_element_size()) as u64
     }
 
-    pub(crate) fn set_node(&mut self, node: &Node) -> io::Result<()> {
-        if node.id == 0 {
-            return Err(Error::new(ErrorKind::Other, "node id cannot be 0"));
+    // decode a node record read from the index file
+    fn decode_node(&self, record: &[u8]) -> Result<Node> {
+        let node_id = u32::from_be_bytes(record[0..self.index_node_id_size()].try_into().unwrap());
+        // node_id = 0 is reserved for empty
+        if node_id == 0 {
+            return Err(error::Error::InvalidInput("node not found".to_owned()));
         }
-        let mut f = OpenOptions::new().write(true).open(&self.index_path)?;
-        f.seek(SeekFrom::Current(self.node_offset(node.id) as i64))?;
-        let mut index_file = BufWriter::new(f);
 
-        // write node id
-        index_file.write_all(&(node.id).to_be_bytes())?;
+        let vector_offset = self.index_node_id_size();
+        let connections_offset =
+            vector_offset + self.dimensions as usize * self.index_node_vector_element_size();
+        let (vector, quantized) = self.read_vector(&record[vector_offset..connections_offset]);
+
+        // Read neighbor indices
+        let mut connected: HashSet<u32> = HashSet::new();
+        for chunk in record[connections_offset..].chunks_exact(self.index_node_id_size()) {
+            let neighbor_index = u32::from_be_bytes(chunk.try_into().unwrap());
+
+            // Ignore padding
+            if neighbor_index != 0 {
+                connected.insert(neighbor_index);
+            }
+        }
 
-        // write vector
-        for value in &node.vector {
-            index_file.write_all(&value.to_be_bytes())?;
+        Ok(Node {
+            id: node_id,
+            vector,
+            connected,
+            quantized,
+        })
+    }
+
+    // encode a node record to be written to the index file
+    fn encode_node(&self, node: &Node) -> io::Result<Vec<u8>> {
+        if node.connected.len() > self.max_neighbour_count as usize {
+            return Err(Error::other("max connections reached"));
         }
+        let mut record = Vec::with_capacity(self.index_node_size());
+
+        // write node id
+        record.write_all(&(node.id).to_be_bytes())?;
+
+        self.write_vector(&mut record, &node.vector)?;
 
         // Set neighbors
         for neighbor in &node.connected {
-            index_file.write_all(&neighbor.to_be_bytes())?;
+            record.write_all(&neighbor.to_be_bytes())?;
         }
 
         // Pad neighbor indices
-        let padding = self.max_neighbour_count as usize - node.connected.len();
-        for _ in 0..padding {
-            index_file.write_all(&0_u32.to_be_bytes())?;
+        record.resize(self.index_node_size(), 0);
+        Ok(record)
+    }
+
+    // set_nodes writes the records of nodes in one batch, growing the index file and taking slots off the free
+    // list as needed
+    pub(crate) fn set_nodes(&mut self, nodes: &[&Node]) -> io::Result<()> {
+        if nodes.iter().any(|node| node.id == 0) {
+            return Err(Error::other("node id cannot be 0"));
+        }
+        let records = nodes
+            .iter()
+            .map(|node| self.encode_node(node))
+            .collect::<io::Result<Vec<_>>>()?;
+        let writes: Vec<(u64, &[u8])> = nodes
+            .iter()
+            .zip(&records)
+            .map(|(node, record)| (self.node_offset(node.id), record.as_slice()))
+            .collect();
+
+        let mut f = OpenOptions::new().write(true).open(&self.index_path)?;
+        self.io.write_many(&f, &writes)?;
+
+        let free_list_len = self.free_list.len();
+        for node in nodes {
+            self.free_list.remove(&node.id);
+        }
+        let set: HashSet<u32> = nodes.iter().map(|node| node.id).collect();
+        let next_node_index = nodes.iter().map(|node| node.id + 1).max().unwrap_or(0);
+        let grown = self.grow(&mut f, next_node_index, &set)?;
+        if grown || self.free_list.len() != free_list_len {
+            self.write_free_list()?;
+        }
+        self.remap()
+    }
+
+    // grow_to grows the index to next_node_index slots. The new slots are free, like those set_nodes skips over
+    pub(crate) fn grow_to(&mut self, next_node_index: u32) -> io::Result<()> {
+        let mut f = OpenOptions::new().write(true).open(&self.index_path)?;
+        if self.grow(&mut f, next_node_index, &HashSet::new())? {
+            self.write_free_list()?;
+        }
+        self.remap()
+    }
+
+    // grow raises next_node_index, and frees the slots it adds that aren't in set. Those are the slots of nodes an index
+    // built on top of this one (FreshDisk) added and removed before they were ever set, which are reused this way.
+    // It returns whether the free list grew, for the caller to write it
+    fn grow(&mut self, f: &mut File, next_node_index: u32, set: &HashSet<u32>) -> io::Result<bool> {
+        if next_node_index <= self.next_node_index {
+            return Ok(false);
+        }
+        let skipped: Vec<u32> = (self.next_node_index..next_node_index)
+            .filter(|node_index| !set.contains(node_index))
+            .collect();
+        let writes: Vec<(u64, &[u8])> = skipped
+            .iter()
+            .map(|node_index| (self.node_offset(*node_index), &[0u8; 4][..]))
+            .collect();
+        self.io.write_many(f, &writes)?;
+
+        self.next_node_index = next_node_index;
+        self.write_next_node_index(f)?;
+        self.free_list.extend(&skipped);
+        Ok(!skipped.is_empty())
+    }
+
+    // remove_node frees the node's slot, and drops its tombstone if it has one
+    pub(crate) fn remove_node(&mut self, node_index: u32) -> io::Result<()> {
+        self.free_slot(node_index)?;
+        if self.deleted.remove(&node_index) {
+            self.write_deleted_list()?;
+        }
+        Ok(())
+    }
+
+    // mark the node's slot as empty by zeroing its node id, and add the slot to the free list
+    fn free_slot(&mut self, node_index: u32) -> io::Result<()> {
+        if node_index == 0 {
+            return Err(Error::other("node id cannot be 0"));
         }
+        if node_index >= self.next_node_index || self.free_list.contains(&node_index) {
+            return Ok(());
+        }
+
+        let mut f = OpenOptions::new().write(true).open(&self.index_path)?;
+        f.seek(SeekFrom::Start(self.node_offset(node_index)))?;
+        f.write_all(&0_u32.to_be_bytes())?;
+
+        // append instead of rewriting the whole free list
+        let mut free_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.free_path)?;
+        free_file.write_all(&node_index.to_be_bytes())?;
+        self.free_list.insert(node_index);
         Ok(())
     }
 }
@@ -159,35 +830,65 @@ impl IndexStore for NaiveDisk {
     }
 
     fn add_nodes(&mut self, data: &[Vec<f32>]) -> Result<Vec<u32>> {
+        // every vector is checked before the quantizer is fitted or a slot is claimed, so a rejected batch changes nothing
+        if let Some(vector) = data
+            .iter()
+            .find(|vector| vector.len() != self.dimensions as usize)
+        {
+            return Err(error::Error::InvalidInput(format!(
+                "vector of {} dimensions in an index of {} dimensions",
+                vector.len(),
+                self.dimensions
+            )));
+        }
+        self.fit_quantizer(data)?;
         let mut created_node_indices: Vec<u32> = Vec::new();
 
-        // jump to next node offset
         let mut f = OpenOptions::new().write(true).open(&self.index_path)?;
-        f.seek(SeekFrom::Current(
-            self.node_offset(self.next_node_index) as i64
-        ))?;
-
-        let mut index_file = BufWriter::new(f);
+        let mut index_file = BufWriter::new(&mut f);
+        let mut position: Option<u64> = None;
+        let mut reused_free_slots = false;
 
         // write nodes to index file
         for datum in data {
-            let node_index = self.next_node_index;
+            // reuse freed slots before growing the index file
+            let node_index = match self.free_list.pop_first() {
+                Some(free_index) => {
+                    reused_free_slots = true;
+                    free_index
+                }
+                None => {
+                    self.next_node_index += 1;
+                    self.next_node_index - 1
+                }
+            };
+
+            // only seek when the slot doesn't follow the previous one, so that appends stay buffered
+            let offset = self.node_offset(node_index);
+            if position != Some(offset) {
+                index_file.seek(SeekFrom::Start(offset))?;
+            }
+            position = Some(offset + self.index_node_size() as u64);
 
             // write node id
             index_file.write_all(&(node_index).to_be_bytes())?;
-            for &value in datum {
-                index_file.write_all(&value.to_be_bytes())?;
-            }
+            self.write_vector(&mut index_file, datum)?;
 
             // Pad neighbor indices
-            let neighbor_indices: Vec<u32> = vec![u32::MAX; self.max_neighbour_count as usize];
-            for &id in &neighbor_indices {
-                index_file.write_all(&id.to_be_bytes())?;
+            for _ in 0..self.max_neighbour_count {
+                index_file.write_all(&0_u32.to_be_bytes())?;
             }
 
             created_node_indices.push(node_index);
-            self.next_node_index += 1
         }
+        index_file.flush()?;
+        drop(index_file);
+
+        self.write_next_node_index(&mut f)?;
+        if reused_free_slots {
+            self.write_free_list()?;
+        }
+        self.remap()?;
         Ok(created_node_indices)
     }
 
@@ -196,62 +897,115 @@ impl IndexStore for NaiveDisk {
             return Err(error::Error::InvalidInput("node id cannot be 0".to_owned()));
         }
 
+        if let Some(record) = self.mapped_record(node_index) {
+            return self.decode_node(record?);
+        }
+
         let mut index_file = File::open(&self.index_path)?;
         index_file.seek(SeekFrom::Current(self.node_offset(node_index) as i64))?;
 
         let mut buffer = vec![0u8; self.index_node_size()];
         index_file.read_exact(&mut buffer)?;
-        let node_id = u32::from_be_bytes(buffer[0..self.index_node_id_size()].try_into().unwrap());
-
-        // Read vector
-        let mut vector: Vec<f32> = Vec::new();
-        for i in 0..self.dimensions {
-            let vector_offset_start =
-                self.index_node_id_size() + (i as usize * self.index_node_vector_element_size());
-            let vector_offset_end = vector_offset_start + self.index_node_vector_element_size();
-            let vector_val = f32::from_be_bytes(
-                buffer[vector_offset_start..vector_offset_end]
-                    .try_into()
-                    .unwrap(),
-            );
-            vector.push(vector_val);
+        self.decode_node(&buffer)
+    }
+
+    // get_nodes reads the records of node_indexes in slot order, coalescing records that are at most COALESCE_GAP
+    // slots apart into a single read. All reads of a call are submitted together when io_uring is in use
+    fn get_nodes(&self, node_indexes: &[u32]) -> Result<Vec<Node>> {
+        if node_indexes.contains(&0) {
+            return Err(error::Error::InvalidInput("node id cannot be 0".to_owned()));
+        }
+        // mapped records are slice lookups, which coalescing can't improve on
+        if self.mmap.is_some() {
+            return node_indexes
+                .iter()
+                .map(|node_index| self.get_node(*node_index))
+                .collect();
         }
 
-        // Read neighbor indices
-        let mut connected: HashSet<u32> = HashSet::new();
-        for i in 0..self.max_neighbour_count {
-            let neighbor_offset_start = 4 + self.dimensions as usize * 4 + i as usize * 4;
-            let neighbor_offset_end = neighbor_offset_start + 4; // u32 = 4 bytes
-            let neighbor_index = u32::from_be_bytes(
-                buffer[neighbor_offset_start..neighbor_offset_end]
-                    .try_into()
-                    .unwrap(),
-            );
+        let mut sorted: Vec<u32> = node_indexes.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
 
-            // Ignore padding
-            if neighbor_index != 0 {
-                connected.insert(neighbor_index);
+        // split the sorted indexes into runs of nearby slots, each read into its own buffer
+        let mut runs: Vec<&[u32]> = Vec::new();
+        let mut run_start = 0;
+        while run_start < sorted.len() {
+            let mut run_end = run_start + 1;
+            while run_end < sorted.len() && sorted[run_end] - sorted[run_end - 1] <= COALESCE_GAP {
+                run_end += 1;
             }
+            runs.push(&sorted[run_start..run_end]);
+            run_start = run_end;
         }
 
-        Ok(Node {
-            id: node_id,
-            vector: vector,
-            connected: connected,
-        })
+        let mut buffers: Vec<Vec<u8>> = runs
+            .iter()
+            .map(|run| {
+                vec![0u8; (run[run.len() - 1] - run[0] + 1) as usize * self.index_node_size()]
+            })
+            .collect();
+        let mut reads: Vec<(u64, &mut [u8])> = runs
+            .iter()
+            .zip(buffers.iter_mut())
+            .map(|(run, buffer)| (self.node_offset(run[0]), buffer.as_mut_slice()))
+            .collect();
+        let index_file = File::open(&self.index_path)?;
+        self.io.read_many(&index_file, &mut reads)?;
+
+        let mut nodes: HashMap<u32, Node> = HashMap::with_capacity(sorted.len());
+        for (run, buffer) in runs.iter().zip(&buffers) {
+            for node_index in *run {
+                let offset = (node_index - run[0]) as usize * self.index_node_size();
+                let node = self.decode_node(&buffer[offset..offset + self.index_node_size()])?;
+                nodes.insert(*node_index, node);
+            }
+        }
+
+        Ok(node_indexes
+            .iter()
+            .map(|node_index| nodes[node_index].clone())
+            .collect())
+    }
+
+    // distances are computed on vectors borrowed from the memory map, so they are only offered in ReadMode::Mmap
+    fn distances_to_query(
+        &self,
+        metric: Metric,
+        query: &[f32],
+        query_quantized: Option<&[i8]>,
+        node_ids: &[u32],
+    ) -> Option<Result<Vec<i64>>> {
+        self.mmap.as_ref()?;
+        Some(
+            node_ids
+                .iter()
+                .map(|node_id| {
+                    Ok(self
+                        .vector(*node_id)?
+                        .distance(metric, query, query_quantized))
+                })
+                .collect(),
+        )
     }
 
     fn get_random_node(&self) -> Option<Node> {
+        if self.next_node_index <= 1 {
+            return None;
+        }
         let mut rng = rand::thread_rng();
-        let node_index: u32 = rng.gen_range(0..self.next_node_index);
-        self.get_node(node_index).ok()
+        let node_index: u32 = rng.gen_range(1..self.next_node_index);
+        self.get_node(node_index).ok().or_else(|| {
+            // landed on a free slot, fall back to the first node
+            let node_index = *self.get_all_node_indexes().ok()?.first()?;
+            self.get_node(node_index).ok()
+        })
     }
 
-    // scan the index file and return all node indexes
+    // scan the index file and return all node indexes, excluding free slots
     fn get_all_node_indexes(&self) -> Result<Vec<u32>> {
         let mut node_indexes = Vec::new();
 
-        // TODO: need to exclude free list nodes
         let mut index_file = File::open(&self.index_path)?;
         index_file.seek(SeekFrom::Current((self.index_metadata_size()) as i64))?;
 
@@ -265,18 +1019,16 @@ impl IndexStore for NaiveDisk {
             let node_id =
                 u32::from_be_bytes(buffer[0..self.index_node_id_size()].try_into().unwrap());
 
+            index_file.seek(SeekFrom::Current(
+                (self.index_node_size() - self.index_node_id_size()) as i64,
+            ))?;
+
             // node_id = 0 is reserved for empty
-            if node_id == 0 {
+            if node_id == 0 || self.free_list.contains(&node_id) {
                 continue;
             }
 
             node_indexes.push(node_id);
-
-            index_file
-                .seek(SeekFrom::Current(
-                    (self.index_node_size() - self.index_node_id_size()) as i64,
-                ))
-                .unwrap();
         }
 
         Ok(node_indexes)
@@ -293,6 +1045,97 @@ impl IndexStore for NaiveDisk {
     fn get_name(&self) -> String {
         "NaiveDisk".into()
     }
+
+    fn get_metric(&self) -> Metric {
+        self.metric
+    }
+
+    fn set_metric(&mut self, metric: Metric) -> Result<()> {
+        let mut index_file = OpenOptions::new().write(true).open(&self.index_path)?;
+        index_file.seek(SeekFrom::Start(self.metric_offset()))?;
+        index_file.write_all(&[metric as u8])?;
+        self.metric = metric;
+        Ok(())
+    }
+
+    fn get_entry_points(&self) -> Vec<u32> {
+        self.entry_points.clone()
+    }
+
+    fn set_entry_points(&mut self, entry_points: &[u32]) -> Result<()> {
+        if entry_points.len() > MAX_ENTRY_POINTS {
+            return Err(error::Error::InvalidInput(format!(
+                "at most {} entry points are supported",
+                MAX_ENTRY_POINTS
+            )));
+        }
+
+        let mut metadata = vec![entry_points.len() as u8];
+        for entry_point in entry_points {
+            metadata.extend_from_slice(&entry_point.to_be_bytes());
+        }
+        metadata.resize(1 + MAX_ENTRY_POINTS * self.index_node_id_size(), 0);
+
+        let mut index_file = OpenOptions::new().write(true).open(&self.index_path)?;
+        index_file.seek(SeekFrom::Start(self.entry_points_offset()))?;
+        index_file.write_all(&metadata)?;
+        self.entry_points = entry_points.to_vec();
+        Ok(())
+    }
+
+    // delete_node appends the tombstone to the .deleted file, so that it survives a reopen
+    fn delete_node(&mut self, node_index: u32) -> Result<()> {
+        self.get_node(node_index)?;
+        if self.deleted.insert(node_index) {
+            let mut deleted_file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.deleted_path)?;
+            deleted_file.write_all(&node_index.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn is_deleted(&self, node_index: u32) -> bool {
+        self.deleted.contains(&node_index)
+    }
+
+    fn get_deleted_node_indexes(&self) -> Result<HashSet<u32>> {
+        Ok(self.deleted.clone())
+    }
+
+    // every slot before next_node_index holds a node unless it is free
+    fn node_counts(&self) -> Result<(usize, usize)> {
+        Ok((
+            (self.next_node_index - 1) as usize - self.free_list.len(),
+            self.deleted.len(),
+        ))
+    }
+
+    // remove_nodes rewrites the .deleted file once for all the tombstones it drops
+    fn remove_nodes(&mut self, node_indexes: &HashSet<u32>) -> Result<()> {
+        let deleted_count = self.deleted.len();
+        for node_index in node_indexes {
+            self.free_slot(*node_index)?;
+            self.deleted.remove(node_index);
+        }
+        if self.deleted.len() != deleted_count {
+            self.write_deleted_list()?;
+        }
+        Ok(())
+    }
+
+    fn get_scalar_quantizer(&self) -> Option<ScalarQuantizer> {
+        self.quantizer
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.sync()?)
+    }
+
+    fn as_sync(&self) -> Option<&(dyn IndexStore + Sync)> {
+        Some(self)
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -340,6 +1183,198 @@ mod tests {
         assert_eq!(HashSet::from([1]), retrieved_node2.connected);
     }
 
+    #[test]
+    fn test_quantized_index() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_quantized.index");
+        let free_path = temp_dir.as_path().join("test_quantized.free");
+        let index_path = index_path.to_str().unwrap();
+        let free_path = free_path.to_str().unwrap();
+
+        let mut disk_storage = NaiveDisk::new_quantized(4, 3, index_path, free_path).unwrap();
+        assert_eq!(None, disk_storage.get_scalar_quantizer());
+
+        // the quantizer is fitted on the first batch, and later values within its range keep it
+        disk_storage
+            .add_nodes(&[vec![1.27, 0.0, -0.5, 0.25], vec![0.0, 1.0, 0.0, 0.0]])
+            .unwrap();
+        let first_quantizer = disk_storage.get_scalar_quantizer();
+        disk_storage.add_nodes(&[vec![0.0, 0.5, 0.0, 0.0]]).unwrap();
+        assert_eq!(first_quantizer, disk_storage.get_scalar_quantizer());
+        assert_eq!(
+            Some(vec![127, 0, -50, 25]),
+            disk_storage.get_node(1).unwrap().quantized
+        );
+        disk_storage
+            .set_connections(1, &HashSet::from([2u32, 3]))
+            .unwrap();
+
+        // larger values grow the scale instead of being clamped, and the stored vectors are re-quantized with it
+        disk_storage
+            .add_nodes(&[vec![5.08, 0.0, 0.0, 0.0]])
+            .unwrap();
+        let scale = disk_storage.get_scalar_quantizer().unwrap().scale();
+        assert!((scale - 0.04).abs() < 1e-6);
+
+        // vectors take one byte per dimension
+        let node_size = 4 + 4 + 3 * 4;
+        let file_size = std::fs::metadata(index_path).unwrap().len();
+        assert_eq!(
+            disk_storage.index_metadata_size() + 4 * node_size,
+            file_size as usize
+        );
+
+        let reopened = NaiveDisk::open(index_path, free_path).unwrap();
+        assert_eq!(
+            disk_storage.get_scalar_quantizer(),
+            reopened.get_scalar_quantizer()
+        );
+
+        let node = reopened.get_node(1).unwrap();
+        assert_eq!(Some(vec![32, 0, -13, 6]), node.quantized);
+        for (expected, actual) in [1.27, 0.0, -0.5, 0.25].iter().zip(node.vector.iter()) {
+            assert!((expected - actual).abs() <= scale);
+        }
+        assert_eq!(HashSet::from([2, 3]), node.connected);
+        assert_eq!(
+            Some(vec![127, 0, 0, 0]),
+            reopened.get_node(4).unwrap().quantized
+        );
+    }
+
+    #[test]
+    fn test_open_after_interrupted_requantize() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_interrupted_requantize.index");
+        let free_path = temp_dir.as_path().join("test_interrupted_requantize.free");
+        let index_path = index_path.to_str().unwrap();
+        let free_path = free_path.to_str().unwrap();
+        let requantize_path = NaiveDisk::requantize_path(index_path);
+
+        let mut disk_storage = NaiveDisk::new_quantized(4, 3, index_path, free_path).unwrap();
+        disk_storage
+            .add_nodes(&[vec![1.27, 0.0, -0.5, 0.25], vec![0.0, 1.0, 0.0, 0.0]])
+            .unwrap();
+        let quantizer = disk_storage.get_scalar_quantizer();
+
+        // a crash during requantize leaves a partly written copy next to the untouched index
+        let index = std::fs::read(index_path).unwrap();
+        std::fs::write(&requantize_path, &index[..index.len() - 4]).unwrap();
+
+        let mut reopened = NaiveDisk::open(index_path, free_path).unwrap();
+        assert!(!std::path::Path::new(&requantize_path).exists());
+        assert_eq!(quantizer, reopened.get_scalar_quantizer());
+        assert_eq!(
+            Some(vec![127, 0, -50, 25]),
+            reopened.get_node(1).unwrap().quantized
+        );
+
+        // the next rescale replaces the index with the re-quantized copy
+        reopened.add_nodes(&[vec![5.08, 0.0, 0.0, 0.0]]).unwrap();
+        assert!(!std::path::Path::new(&requantize_path).exists());
+        let reopened = NaiveDisk::open(index_path, free_path).unwrap();
+        assert!((reopened.get_scalar_quantizer().unwrap().scale() - 0.04).abs() < 1e-6);
+        assert_eq!(
+            Some(vec![32, 0, -13, 6]),
+            reopened.get_node(1).unwrap().quantized
+        );
+    }
+
+    #[test]
+    fn test_mmap_read_mode() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_mmap.index");
+        let free_path = temp_dir.as_path().join("test_mmap.free");
+
+        let mut disk_storage = NaiveDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        disk_storage
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0]])
+            .unwrap();
+        assert!(disk_storage.vector(1).is_err());
+
+        disk_storage.set_read_mode(ReadMode::Mmap).unwrap();
+        assert_eq!(ReadMode::Mmap, disk_storage.read_mode());
+
+        // writes to mapped slots are visible, and growing the file remaps it
+        disk_storage
+            .set_connections(1, &HashSet::from([2u32]))
+            .unwrap();
+        disk_storage.add_nodes(&[vec![5.0, 6.0]]).unwrap();
+        let node = disk_storage.get_node(1).unwrap();
+        assert_eq!(vec![1.0, 2.0], node.vector);
+        assert_eq!(HashSet::from([2]), node.connected);
+        assert_eq!(vec![5.0, 6.0], disk_storage.vector(3).unwrap().to_vec());
+        assert_eq!(3, disk_storage.get_nodes(&[3, 1, 2]).unwrap()[0].id);
+
+        let query = [1.0, 1.0];
+        let distances = disk_storage
+            .distances_to_query(Metric::L2, &query, None, &[1, 3])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec![
+                Metric::L2.distance(&query, &[1.0, 2.0]),
+                Metric::L2.distance(&query, &[5.0, 6.0])
+            ],
+            distances
+        );
+
+        disk_storage.remove_node(2).unwrap();
+        assert!(disk_storage.get_node(2).is_err());
+        assert!(disk_storage.vector(2).is_err());
+        assert!(disk_storage.vector(4).is_err());
+
+        disk_storage.set_read_mode(ReadMode::File).unwrap();
+        assert!(disk_storage
+            .distances_to_query(Metric::L2, &query, None, &[1])
+            .is_none());
+    }
+
+    #[test]
+    fn test_get_nodes() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_get_nodes.index");
+        let free_path = temp_dir.as_path().join("test_get_nodes.free");
+
+        let mut disk_storage = NaiveDisk::new(
+            1,
+            2,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        let vectors: Vec<Vec<f32>> = (0..20).map(|x| vec![x as f32]).collect();
+        disk_storage.add_nodes(&vectors).unwrap();
+        disk_storage
+            .set_connections(12, &HashSet::from([1u32, 20]))
+            .unwrap();
+        disk_storage.remove_node(3).unwrap();
+
+        // nodes are returned in the requested order, whether they were read in the same run or not
+        let node_indexes = [20, 1, 12, 2, 20, 15];
+        for backend in [IoBackend::Pread, IoBackend::IoUring] {
+            disk_storage.set_io_backend(backend);
+            let nodes = disk_storage.get_nodes(&node_indexes).unwrap();
+            assert_eq!(node_indexes.len(), nodes.len());
+            for (node_index, node) in node_indexes.iter().zip(nodes.iter()) {
+                let expected = disk_storage.get_node(*node_index).unwrap();
+                assert_eq!(expected.id, node.id);
+                assert_eq!(expected.vector, node.vector);
+                assert_eq!(expected.connected, node.connected);
+            }
+        }
+
+        assert!(disk_storage.get_nodes(&[]).unwrap().is_empty());
+        assert!(disk_storage.get_nodes(&[1, 3]).is_err());
+        assert!(disk_storage.get_nodes(&[0]).is_err());
+    }
+
     #[test]
     fn test_set_node_and_get_node() {
         let temp_dir = env::temp_dir();
@@ -360,8 +1395,9 @@ mod tests {
             id: 5,
             vector: vec![5.0, 6.0],
             connected: HashSet::new(),
+            quantized: None,
         };
-        disk_storage.set_node(&node).unwrap();
+        disk_storage.set_nodes(&[&node]).unwrap();
 
         // Retrieve the node and verify
         let retrieved_node = disk_storage.get_node(5).unwrap();
@@ -397,4 +1433,151 @@ mod tests {
         assert!(node_indexes.contains(&1));
         assert!(node_indexes.contains(&2));
     }
+
+    #[test]
+    fn test_open_existing_index() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_open.index");
+        let free_path = temp_dir.as_path().join("test_open.free");
+
+        let mut disk_storage = NaiveDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        disk_storage
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0]])
+            .unwrap();
+        disk_storage
+            .set_connections(1, &HashSet::from([2u32]))
+            .unwrap();
+        disk_storage.set_metric(Metric::Cosine).unwrap();
+        disk_storage.set_entry_points(&[2, 1]).unwrap();
+        drop(disk_storage);
+
+        // Reopen and verify that nodes, connections, the graph metadata and the next node index survived
+        let mut reopened =
+            NaiveDisk::open(index_path.to_str().unwrap(), free_path.to_str().unwrap()).unwrap();
+        assert_eq!(3, reopened.max_neighbour_count());
+        assert_eq!(Metric::Cosine, reopened.get_metric());
+        assert_eq!(vec![2, 1], reopened.get_entry_points());
+
+        let retrieved_node1 = reopened.get_node(1).unwrap();
+        assert_eq!(vec![1.0, 2.0], retrieved_node1.vector);
+        assert_eq!(HashSet::from([2]), retrieved_node1.connected);
+        assert!(reopened.get_node(2).unwrap().connected.is_empty());
+
+        let ids = reopened.add_nodes(&[vec![5.0, 6.0]]).unwrap();
+        assert_eq!(ids, vec![3]);
+        assert_eq!(vec![1, 2, 3], reopened.get_all_node_indexes().unwrap());
+    }
+
+    #[test]
+    fn test_open_rejects_truncated_index() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_open_truncated.index");
+        let free_path = temp_dir.as_path().join("test_open_truncated.free");
+
+        let mut disk_storage = NaiveDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        disk_storage
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0]])
+            .unwrap();
+
+        // Chop off part of the last node
+        let index_file = OpenOptions::new().write(true).open(&index_path).unwrap();
+        let len = index_file.metadata().unwrap().len();
+        index_file.set_len(len - 1).unwrap();
+
+        assert!(
+            NaiveDisk::open(index_path.to_str().unwrap(), free_path.to_str().unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_removed_slots_are_reused() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_free.index");
+        let free_path = temp_dir.as_path().join("test_free.free");
+
+        let mut disk_storage = NaiveDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        disk_storage
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 6.0]])
+            .unwrap();
+
+        // Removed slots are excluded from scans
+        disk_storage.remove_nodes(&HashSet::from([1, 2])).unwrap();
+        assert_eq!(vec![3], disk_storage.get_all_node_indexes().unwrap());
+        assert!(disk_storage.get_node(2).is_err());
+
+        // The free list survives a reopen
+        drop(disk_storage);
+        let mut reopened =
+            NaiveDisk::open(index_path.to_str().unwrap(), free_path.to_str().unwrap()).unwrap();
+        assert_eq!(vec![3], reopened.get_all_node_indexes().unwrap());
+        assert_eq!((1, 0), reopened.node_counts().unwrap());
+
+        // Freed slots are filled before the index grows
+        let ids = reopened
+            .add_nodes(&[vec![7.0, 8.0], vec![9.0, 10.0], vec![11.0, 12.0]])
+            .unwrap();
+        assert_eq!(ids, vec![1, 2, 4]);
+        assert_eq!(vec![7.0, 8.0], reopened.get_node(1).unwrap().vector);
+        assert_eq!(vec![9.0, 10.0], reopened.get_node(2).unwrap().vector);
+        assert_eq!(vec![1, 2, 3, 4], reopened.get_all_node_indexes().unwrap());
+        reopened.delete_node(4).unwrap();
+        assert_eq!((4, 1), reopened.node_counts().unwrap());
+
+        drop(reopened);
+        let reopened =
+            NaiveDisk::open(index_path.to_str().unwrap(), free_path.to_str().unwrap()).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], reopened.get_all_node_indexes().unwrap());
+    }
+
+    #[test]
+    fn test_rejected_batch_changes_nothing() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_rejected_batch.index");
+        let free_path = temp_dir.as_path().join("test_rejected_batch.free");
+        let index_path = index_path.to_str().unwrap();
+        let free_path = free_path.to_str().unwrap();
+
+        let mut disk_storage = NaiveDisk::new_quantized(2, 3, index_path, free_path).unwrap();
+        disk_storage
+            .add_nodes(&[vec![1.0, 0.5], vec![0.5, 1.0], vec![0.0, 0.0]])
+            .unwrap();
+        disk_storage.remove_nodes(&HashSet::from([2])).unwrap();
+        let quantizer = disk_storage.get_scalar_quantizer();
+        let quantized = disk_storage.get_node(1).unwrap().quantized;
+
+        // the first vector would take the free slot and grow the scale, the second one has a dimension too many
+        let result = disk_storage.add_nodes(&[vec![100.0, 0.0], vec![1.0, 2.0, 3.0]]);
+        assert!(matches!(result, Err(error::Error::InvalidInput(_))));
+
+        assert_eq!(4, disk_storage.next_node_index);
+        assert_eq!(BTreeSet::from([2]), disk_storage.free_list);
+        assert_eq!(quantizer, disk_storage.get_scalar_quantizer());
+        assert_eq!(quantized, disk_storage.get_node(1).unwrap().quantized);
+
+        drop(disk_storage);
+        let reopened = NaiveDisk::open(index_path, free_path).unwrap();
+        assert_eq!(4, reopened.next_node_index);
+        assert_eq!(BTreeSet::from([2]), reopened.free_list);
+        assert_eq!(quantizer, reopened.get_scalar_quantizer());
+        assert_eq!(quantized, reopened.get_node(1).unwrap().quantized);
+        assert_eq!(vec![1, 3], reopened.get_all_node_indexes().unwrap());
+    }
 }
@@ -1,21 +1,184 @@
-use crate::{prelude::Error, prelude::*, Node};
+use crate::{prelude::Error, prelude::*, Metric, NaiveDisk, Node};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    sync::{Arc, Condvar, Mutex, RwLock},
-    time::Duration,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use super::IndexStore;
+use super::{
+    merge,
+    wal::{Wal, WalRecord},
+    IndexStore, IoThrottle,
+};
+
+// TempIndex holds the latest version of recently updated nodes. None marks a node removed from the graph, which is flushed as a removal to the long term index
+pub(super) type TempIndex = HashMap<u32, Option<Node>>;
+
+// FrozenSegment is a read-only temp index waiting to be flushed, along with the WAL files that hold its updates.
+// The WAL files are removed once the segment is persisted in the long term index
+struct FrozenSegment {
+    nodes: TempIndex,
+    wal_paths: Vec<PathBuf>,
+    // estimate of the memory taken by nodes, see entry_bytes
+    bytes: usize,
+    frozen_at: Instant,
+}
+
+impl FrozenSegment {
+    fn new(nodes: TempIndex, wal_paths: Vec<PathBuf>) -> Self {
+        FrozenSegment {
+            bytes: nodes.values().map(entry_bytes).sum(),
+            nodes,
+            wal_paths,
+            frozen_at: Instant::now(),
+        }
+    }
+}
+
+// entry_bytes estimates the memory taken by an entry of a temp index: the vector and edges of its node, plus the map entry
+fn entry_bytes(node: &Option<Node>) -> usize {
+    std::mem::size_of::<(u32, Option<Node>)>()
+        + node.as_ref().map_or(0, |node| {
+            node.vector.len() * std::mem::size_of::<f32>()
+                + node.connected.len() * std::mem::size_of::<u32>()
+        })
+}
+
+// FreshDiskConfig sets when the rw temp index is frozen into a segment to flush, and how many frozen segments may wait
+// to be flushed. Bigger segments mean fewer merges for writers with a high rate, and max_segment_age gets the updates
+// of writers with a low rate flushed too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreshDiskConfig {
+    // the rw temp index is frozen once it holds max_segment_entries entries or max_segment_bytes bytes
+    pub max_segment_entries: usize,
+    pub max_segment_bytes: usize,
+    // or once its oldest update is max_segment_age old, if set
+    pub max_segment_age: Option<Duration>,
+    pub backpressure: Backpressure,
+    // bytes per second merges write to the long term index, so that they leave the disk to reads. None doesn't limit
+    // them
+    pub max_flush_bytes_per_sec: Option<u64>,
+}
+
+impl Default for FreshDiskConfig {
+    fn default() -> Self {
+        FreshDiskConfig {
+            max_segment_entries: 10000,
+            max_segment_bytes: 256 << 20,
+            max_segment_age: Some(Duration::from_secs(60)),
+            backpressure: Backpressure::default(),
+            max_flush_bytes_per_sec: None,
+        }
+    }
+}
+
+// Backpressure bounds the memory taken by frozen segments waiting to be merged into the long term index. A full rw
+// temp index is only frozen while fewer than max_frozen_segments segments, holding less than max_frozen_bytes, are
+// waiting, or none is. When writes come in faster than segments are merged, writers wait for a merge if block is
+// set, and get Error::Busy otherwise, without their write being taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backpressure {
+    pub max_frozen_segments: usize,
+    pub max_frozen_bytes: usize,
+    pub block: bool,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Backpressure {
+            max_frozen_segments: 4,
+            max_frozen_bytes: 1 << 30,
+            block: true,
+        }
+    }
+}
+
+// FlushLag tells how far the merges of frozen segments are behind the writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FlushLag {
+    pub frozen_segments: usize,
+    pub frozen_entries: usize,
+    pub frozen_bytes: usize,
+    // how long the oldest frozen segment has been waiting, None when no segment is
+    pub oldest_frozen_age: Option<Duration>,
+    // segments merged since the index was opened
+    pub merged_segments: u64,
+    // how long writers waited for merges in total, see Backpressure
+    pub blocked: Duration,
+    // how long merges waited for max_flush_bytes_per_sec in total
+    pub throttled: Duration,
+}
+
+// FlushSignals is how FreshDisk and its flush thread signal each other
+#[derive(Default)]
+struct FlushSignals {
+    // set when a segment is frozen, when the config changes, or when the flush thread should stop
+    ready: Mutex<bool>,
+    condvar: Condvar,
+    stop: AtomicBool,
+    // progress of the flush thread, notified by merged_condvar after every merge and when the thread fails
+    state: Mutex<FlushState>,
+    merged_condvar: Condvar,
+}
+
+#[derive(Default)]
+struct FlushState {
+    merged_segments: u64,
+    // the error the flush thread stopped on. Frozen segments are left to the WAL, and waiting for them fails
+    error: Option<String>,
+}
+
+impl FlushState {
+    fn check(&self) -> Result<()> {
+        match &self.error {
+            Some(e) => Err(Error::IO(std::io::Error::other(format!(
+                "flushing failed: {}",
+                e
+            )))),
+            None => Ok(()),
+        }
+    }
+}
+
+// Writer is the WAL file that updates to the rw temp index and delete list are logged in, until the rw temp index
+// is frozen. Updates are logged and applied with the writer locked, so that a frozen segment and its WAL file always
+// hold the same updates
+struct Writer {
+    wal: Wal,
+    wal_sequence: u64,
+    index_path: String,
+    // estimate of the memory taken by the rw temp index, see entry_bytes
+    rw_bytes: usize,
+    // when the oldest update of the rw temp index was made, None while it is empty
+    rw_since: Option<Instant>,
+}
+
+// Shared is the part of FreshDisk that its flush thread works on too, as it freezes the rw temp index once it is
+// max_segment_age old. Locks held together are always taken in the order writer, long_term_index, rw_temp_index,
+// ro_temp_index, so that readers and a freeze from the flush thread never wait on each other
+struct Shared {
+    writer: Mutex<Writer>,
+    long_term_index: RwLock<NaiveDisk>,
+    rw_temp_index: RwLock<TempIndex>,
+    ro_temp_index: RwLock<VecDeque<Arc<FrozenSegment>>>,
+    delete_list: RwLock<HashSet<u32>>,
+    config: RwLock<FreshDiskConfig>,
+    throttle: IoThrottle,
+    signals: FlushSignals,
+}
 
 // FreshDisk is the storage implementation of the system described in the FreshDiskANN paper
 pub struct FreshDisk {
-    long_term_index: Arc<RwLock<crate::NaiveDisk>>,
-    delete_list: Vec<u32>, // delete not implemented yet
-    ro_temp_index: Arc<RwLock<VecDeque<HashMap<u32, Node>>>>,
-    rw_temp_index: Arc<RwLock<HashMap<u32, Node>>>,
+    shared: Arc<Shared>,
     next_node_index: u32,
-    ready: Arc<Mutex<bool>>,
-    condvar: Arc<Condvar>,
+    // None once the flush thread is stopped
+    flush_thread: Option<JoinHandle<()>>,
+    blocked: Duration,
 }
 
 impl FreshDisk {
@@ -25,111 +188,489 @@ impl FreshDisk {
         index_path: &str,
         free_path: &str,
     ) -> Result<Self> {
-        let long_term_index = Arc::new(RwLock::new(crate::NaiveDisk::new(
-            dimensions,
-            max_neighbor_count,
+        let long_term_index =
+            NaiveDisk::new(dimensions, max_neighbor_count, index_path, free_path)?;
+
+        // logs left behind by a previous index at index_path no longer apply
+        for (_, wal_path) in Wal::list(index_path)? {
+            Wal::remove(&wal_path)?;
+        }
+
+        Self::start(
+            long_term_index,
             index_path,
-            free_path,
-        )?));
-
-        let ro_temp_index = Arc::new(RwLock::new(VecDeque::new()));
-        let rw_temp_index = RwLock::new(HashMap::new());
-
-        let ready = Arc::new(Mutex::new(false));
-        let condvar = Arc::new(Condvar::new());
-
-        let fresh_disk = FreshDisk {
-            long_term_index: long_term_index.clone(),
-            delete_list: Vec::new(),
-            ro_temp_index: ro_temp_index.clone(),
-            rw_temp_index: Arc::new(rw_temp_index),
-            next_node_index: 1, // node_index=0 is reserved to indicate that node doesn't exist
-            ready: ready.clone(),
-            condvar: condvar.clone(),
-        };
+            None,
+            HashSet::new(),
+            0,
+            1, // node_index=0 is reserved to indicate that node doesn't exist
+        )
+    }
+
+    // reopen a FreshDisk index, replaying the WAL on top of the long term index to recover updates that weren't flushed yet
+    pub fn open(index_path: &str, free_path: &str) -> Result<Self> {
+        let long_term_index = NaiveDisk::open(index_path, free_path)?;
 
-        std::thread::spawn(move || {
-            Self::periodic_flush(long_term_index, ro_temp_index, ready, condvar);
+        let wal_files = Wal::list(index_path)?;
+        let mut replayed = TempIndex::new();
+        let mut delete_list = HashSet::new();
+        // a merge that was cut short by a crash may have written some of the nodes its segment added. Those already
+        // have their merged edges in the long term index, so their records are skipped rather than replayed over them
+        let mut merged: HashSet<u32> = HashSet::new();
+        let long_term_nodes: HashSet<u32> = long_term_index
+            .get_all_node_indexes()?
+            .into_iter()
+            .collect();
+        for (_, wal_path) in wal_files.iter() {
+            for record in Wal::replay(wal_path)? {
+                match &record {
+                    WalRecord::AddNode { node_index, .. }
+                        if long_term_nodes.contains(node_index) =>
+                    {
+                        merged.insert(*node_index);
+                        continue;
+                    }
+                    WalRecord::SetConnections { node_index, .. } if merged.contains(node_index) => {
+                        continue;
+                    }
+                    _ => {}
+                }
+                Self::apply_wal_record(&long_term_index, &mut replayed, &mut delete_list, record)?;
+            }
+        }
+
+        let next_node_index = replayed
+            .keys()
+            .map(|node_index| node_index + 1)
+            .fold(long_term_index.next_node_index(), u32::max);
+        let wal_sequence = wal_files.last().map_or(0, |(sequence, _)| sequence + 1);
+
+        // replayed updates are frozen right away, so that they are flushed before the logs are dropped
+        let replayed_segment = FrozenSegment::new(
+            replayed,
+            wal_files
+                .into_iter()
+                .map(|(_, wal_path)| wal_path)
+                .collect(),
+        );
+
+        Self::start(
+            long_term_index,
+            index_path,
+            Some(replayed_segment),
+            delete_list,
+            wal_sequence,
+            next_node_index,
+        )
+    }
+
+    fn start(
+        long_term_index: NaiveDisk,
+        index_path: &str,
+        replayed_segment: Option<FrozenSegment>,
+        delete_list: HashSet<u32>,
+        wal_sequence: u64,
+        next_node_index: u32,
+    ) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            long_term_index: RwLock::new(long_term_index),
+            delete_list: RwLock::new(delete_list),
+            signals: FlushSignals {
+                ready: Mutex::new(replayed_segment.is_some()),
+                ..Default::default()
+            },
+            ro_temp_index: RwLock::new(VecDeque::from_iter(replayed_segment.map(Arc::new))),
+            rw_temp_index: RwLock::new(HashMap::new()),
+            writer: Mutex::new(Writer {
+                wal: Wal::create(Wal::segment_path(index_path, wal_sequence))?,
+                wal_sequence,
+                index_path: index_path.to_string(),
+                rw_bytes: 0,
+                rw_since: None,
+            }),
+            config: RwLock::new(FreshDiskConfig::default()),
+            throttle: IoThrottle::default(),
         });
+        shared.log_delete_list(&mut shared.writer.lock().unwrap())?;
 
-        Ok(fresh_disk)
+        let flush_shared = shared.clone();
+        Ok(FreshDisk {
+            shared,
+            next_node_index,
+            flush_thread: Some(std::thread::spawn(move || {
+                Self::periodic_flush(flush_shared);
+            })),
+            blocked: Duration::ZERO,
+        })
     }
 
-    fn check_and_convert_rw_index(&mut self) {
-        let rw_temp = self.rw_temp_index.read().unwrap();
-        if rw_temp.len() < 10000 {
-            return;
+    // apply_wal_record replays a single logged update into the temp index
+    fn apply_wal_record(
+        long_term_index: &NaiveDisk,
+        temp_index: &mut TempIndex,
+        delete_list: &mut HashSet<u32>,
+        record: WalRecord,
+    ) -> Result<()> {
+        match record {
+            WalRecord::AddNode { node_index, vector } => {
+                let node = Node {
+                    id: node_index,
+                    vector,
+                    connected: HashSet::new(),
+                    quantized: None,
+                };
+                temp_index.insert(node_index, Some(node));
+            }
+            WalRecord::SetConnections {
+                node_index,
+                connections,
+            } => {
+                let mut node = match temp_index.get(&node_index) {
+                    Some(Some(node)) => node.clone(),
+                    Some(None) => {
+                        return Err(Error::InvalidInput(format!(
+                            "WAL sets connections of removed node {}",
+                            node_index
+                        )))
+                    }
+                    None => long_term_index.get_node(node_index)?,
+                };
+                node.connected = connections.into_iter().collect();
+                temp_index.insert(node_index, Some(node));
+            }
+            WalRecord::DeleteNode { node_index } => {
+                delete_list.insert(node_index);
+            }
+            WalRecord::RemoveNode { node_index } => {
+                temp_index.insert(node_index, None);
+                delete_list.remove(&node_index);
+            }
         }
-        drop(rw_temp);
+        Ok(())
+    }
 
-        let old_rw_temp_index = std::mem::replace(
-            &mut self.rw_temp_index,
-            Arc::new(RwLock::new(HashMap::new())),
-        );
-        let mut ro_temp = self.ro_temp_index.write().unwrap();
-        ro_temp.push_back(old_rw_temp_index.write().unwrap().clone());
+    pub fn config(&self) -> FreshDiskConfig {
+        *self.shared.config.read().unwrap()
+    }
 
-        // notify that ro_temp can be flushed
-        let mut ready = self.ready.lock().unwrap();
-        *ready = true;
-        self.condvar.notify_all();
+    // set_config applies to the rw temp index right away, so a smaller segment size freezes it on the next write
+    pub fn set_config(&mut self, config: FreshDiskConfig) {
+        *self.shared.config.write().unwrap() = config;
+        // a merge in progress goes on at the new rate
+        self.shared
+            .throttle
+            .set_rate(config.max_flush_bytes_per_sec);
+        // the flush thread waits for max_segment_age, which may have changed
+        self.shared.signals.notify_ready();
     }
 
-    fn periodic_flush(
-        long_term_index: Arc<RwLock<crate::NaiveDisk>>,
-        ro_temp_index: Arc<RwLock<VecDeque<HashMap<u32, Node>>>>,
-        ready: Arc<Mutex<bool>>,
-        condvar: Arc<Condvar>,
-    ) {
-        loop {
-            Self::wait_for_ready_and_reset(ready.clone(), condvar.clone());
+    pub fn flush_lag(&self) -> FlushLag {
+        let merged_segments = self.shared.signals.state.lock().unwrap().merged_segments;
+        let ro_temp = self.shared.ro_temp_index.read().unwrap();
+        FlushLag {
+            frozen_segments: ro_temp.len(),
+            frozen_entries: ro_temp.iter().map(|segment| segment.nodes.len()).sum(),
+            frozen_bytes: ro_temp.iter().map(|segment| segment.bytes).sum(),
+            oldest_frozen_age: ro_temp.front().map(|segment| segment.frozen_at.elapsed()),
+            merged_segments,
+            blocked: self.blocked,
+            throttled: self.shared.throttle.throttled(),
+        }
+    }
+
+    // make_room is called before a write. It freezes the rw temp index when a previous write left it full, waiting
+    // for a merge first, or failing with Error::Busy, when there is no room for another frozen segment
+    fn make_room(&mut self) -> Result<()> {
+        if !self.shared.is_rw_index_full() {
+            return Ok(());
+        }
+        if !self.shared.can_freeze() {
+            if !self.config().backpressure.block {
+                let lag = self.flush_lag();
+                return Err(Error::Busy(format!(
+                    "{} frozen segments of {} bytes are waiting to be flushed",
+                    lag.frozen_segments, lag.frozen_bytes
+                )));
+            }
+            self.wait_for_room()?;
+        }
+        self.shared.freeze_rw_index()
+    }
 
-            let ro_temp = ro_temp_index.read().unwrap();
-            let to_flush_length = ro_temp.len();
-            drop(ro_temp);
+    // wait_for_room waits until the flush thread has merged enough segments for another one to be frozen
+    fn wait_for_room(&mut self) -> Result<()> {
+        let waiting_since = Instant::now();
+        let mut state = self.shared.signals.state.lock().unwrap();
+        while !self.shared.can_freeze() {
+            state.check()?;
+            state = self.shared.signals.merged_condvar.wait(state).unwrap();
+        }
+        drop(state);
+        self.blocked += waiting_since.elapsed();
+        Ok(())
+    }
 
-            for _ in 0..to_flush_length {
-                let ro_temp = ro_temp_index.read().unwrap();
-                let to_flush = ro_temp.front().unwrap().clone();
-                drop(ro_temp);
+    // check_and_convert_rw_index is called after a write, and freezes a full rw temp index if there is room for it.
+    // Otherwise the next write waits in make_room
+    fn check_and_convert_rw_index(&mut self) -> Result<()> {
+        if self.shared.is_rw_index_full() && self.shared.can_freeze() {
+            self.shared.freeze_rw_index()?;
+        }
+        Ok(())
+    }
 
-                let mut long_term = long_term_index.write().unwrap();
+    // close flushes the index and stops the flush thread. Dropping a FreshDisk stops the thread without flushing,
+    // leaving updates that weren't merged yet to be replayed from the WAL by open
+    pub fn close(mut self) -> Result<()> {
+        self.flush()?;
+        self.stop_flush_thread();
+        Ok(())
+    }
 
-                // Flush the ro_temp_index from the back
-                // TODO: this flush can be improved using io_uring
-                for (_, node) in to_flush.iter() {
-                    long_term.set_node(node).unwrap();
+    // stop_flush_thread lets the flush thread finish the segment it is merging, and waits for it to exit
+    fn stop_flush_thread(&mut self) {
+        let Some(flush_thread) = self.flush_thread.take() else {
+            return;
+        };
+        self.shared.signals.stop.store(true, Ordering::SeqCst);
+        self.shared.signals.notify_ready();
+        // a panic of the flush thread has been reported already, and the WAL still holds what it didn't merge
+        let _ = flush_thread.join();
+    }
+
+    // periodic_flush merges frozen segments as they come, and freezes the rw temp index once its oldest update is
+    // max_segment_age old
+    fn periodic_flush(shared: Arc<Shared>) {
+        loop {
+            shared
+                .signals
+                .wait_for_ready_and_reset(shared.next_freeze_in());
+
+            loop {
+                if shared.signals.stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                if shared.is_rw_index_expired() && shared.can_freeze() {
+                    if let Err(e) = shared.freeze_rw_index() {
+                        shared.fail(e);
+                        return;
+                    }
+                }
+
+                let Some(to_flush) = shared.ro_temp_index.read().unwrap().front().cloned() else {
+                    break;
+                };
+                if let Err(e) = Self::flush_segment(&shared, &to_flush) {
+                    shared.fail(e);
+                    return;
                 }
-                drop(long_term);
-                ro_temp_index.write().unwrap().pop_front();
+                shared.ro_temp_index.write().unwrap().pop_front();
+
+                // wake up writers waiting for room to freeze the rw temp index, and flush calls
+                shared.signals.state.lock().unwrap().merged_segments += 1;
+                shared.signals.merged_condvar.notify_all();
             }
         }
     }
 
-    fn wait_for_ready_and_reset(ready: Arc<Mutex<bool>>, condvar: Arc<Condvar>) {
-        let mut ready = ready.lock().unwrap();
-        while !*ready {
-            ready = condvar.wait(ready).unwrap();
+    fn flush_segment(shared: &Shared, segment: &FrozenSegment) -> Result<()> {
+        // segments are merged from the oldest, as later segments hold newer versions of their nodes
+        merge::streaming_merge(&shared.long_term_index, &segment.nodes, &shared.throttle)?;
+
+        // the segment's WAL files can only be dropped once the long term index is durable
+        shared.long_term_index.read().unwrap().sync()?;
+        for wal_path in segment.wal_paths.iter() {
+            Wal::remove(wal_path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    // apply puts an update logged to the writer's WAL into the rw temp index
+    fn apply(&self, writer: &mut Writer, node_index: u32, node: Option<Node>) {
+        writer.rw_bytes += entry_bytes(&node);
+        if let Some(replaced) = self.rw_temp_index.write().unwrap().insert(node_index, node) {
+            writer.rw_bytes -= entry_bytes(&replaced);
+        }
+        if writer.rw_since.is_none() {
+            writer.rw_since = Some(Instant::now());
+            // the flush thread doesn't wait for an empty rw temp index to expire
+            self.signals.notify_ready();
+        }
+    }
+
+    // new_node_indexes picks count node indexes for new nodes. Slots freed in the long term index are reused first, but
+    // not while a temp index still holds an update to them: the slot of a node removed by a segment is only free once
+    // the segment is merged. Then the index grows from next_node_index, as it does while a merge writes to the long term
+    // index, which writes don't wait for. Taking the writer keeps other writes from picking the same slots
+    fn new_node_indexes(
+        &self,
+        _writer: &Writer,
+        next_node_index: &mut u32,
+        count: usize,
+    ) -> Vec<u32> {
+        let mut node_indexes = Vec::new();
+        if let Ok(long_term_index) = self.long_term_index.try_read() {
+            let rw_index = self.rw_temp_index.read().unwrap();
+            let ro_index = self.ro_temp_index.read().unwrap();
+            node_indexes.extend(
+                long_term_index
+                    .free_slots()
+                    .iter()
+                    .filter(|node_index| {
+                        !rw_index.contains_key(node_index)
+                            && ro_index
+                                .iter()
+                                .all(|segment| !segment.nodes.contains_key(node_index))
+                    })
+                    .take(count),
+            );
+        }
+        while node_indexes.len() < count {
+            node_indexes.push(*next_node_index);
+            *next_node_index += 1;
+        }
+        node_indexes
+    }
+
+    // tombstones outlive the segment that logged them, so they are logged again at the start of every WAL file
+    fn log_delete_list(&self, writer: &mut Writer) -> Result<()> {
+        for node_index in self.delete_list.read().unwrap().iter() {
+            writer.wal.append(&WalRecord::DeleteNode {
+                node_index: *node_index,
+            })?;
+        }
+        writer.wal.flush()?;
+        Ok(())
+    }
+
+    fn is_rw_index_full(&self) -> bool {
+        let config = *self.config.read().unwrap();
+        // the writer is locked before the rw temp index, as in freeze_rw_index
+        let rw_bytes = self.writer.lock().unwrap().rw_bytes;
+        self.rw_temp_index.read().unwrap().len() >= config.max_segment_entries
+            || rw_bytes >= config.max_segment_bytes
+    }
+
+    fn is_rw_index_expired(&self) -> bool {
+        self.next_freeze_in() == Some(Duration::ZERO)
+    }
+
+    // next_freeze_in returns how long until the rw temp index is max_segment_age old, None if it is empty or has no
+    // max age
+    fn next_freeze_in(&self) -> Option<Duration> {
+        let max_segment_age = self.config.read().unwrap().max_segment_age?;
+        let rw_since = self.writer.lock().unwrap().rw_since?;
+        Some(max_segment_age.saturating_sub(rw_since.elapsed()))
+    }
+
+    // can_freeze tells if the frozen segments leave room for another one under the limits of backpressure
+    fn can_freeze(&self) -> bool {
+        let backpressure = self.config.read().unwrap().backpressure;
+        let ro_temp = self.ro_temp_index.read().unwrap();
+        ro_temp.is_empty()
+            || (ro_temp.len() < backpressure.max_frozen_segments
+                && ro_temp.iter().map(|segment| segment.bytes).sum::<usize>()
+                    < backpressure.max_frozen_bytes)
+    }
+
+    fn freeze_rw_index(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.rw_temp_index.read().unwrap().is_empty() {
+            return Ok(());
+        }
+
+        // the frozen segment keeps its WAL file until it is flushed, new updates go to a new file
+        writer.wal_sequence += 1;
+        let wal = Wal::create(Wal::segment_path(&writer.index_path, writer.wal_sequence))?;
+        let mut old_wal = std::mem::replace(&mut writer.wal, wal);
+        old_wal.flush()?;
+        self.log_delete_list(&mut writer)?;
+
+        // the segment is pushed while the rw temp index is still locked, so that readers see its nodes in either
+        let mut rw_temp = self.rw_temp_index.write().unwrap();
+        let mut ro_temp = self.ro_temp_index.write().unwrap();
+        ro_temp.push_back(Arc::new(FrozenSegment::new(
+            std::mem::take(&mut *rw_temp),
+            vec![old_wal.path().to_path_buf()],
+        )));
+        drop(ro_temp);
+        drop(rw_temp);
+        writer.rw_bytes = 0;
+        writer.rw_since = None;
+        drop(writer);
+
+        // notify that ro_temp can be flushed
+        self.signals.notify_ready();
+        Ok(())
+    }
+
+    // fail records the error the flush thread stops on, and wakes up whoever waits for it
+    fn fail(&self, e: Error) {
+        self.signals.state.lock().unwrap().error = Some(e.to_string());
+        self.signals.merged_condvar.notify_all();
+    }
+}
+
+impl FlushSignals {
+    fn notify_ready(&self) {
+        *self.ready.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    // wait_for_ready_and_reset waits until ready is set, or until timeout if there is one
+    fn wait_for_ready_and_reset(&self, timeout: Option<Duration>) {
+        let mut ready = self.ready.lock().unwrap();
+        match timeout {
+            Some(timeout) => {
+                ready = self
+                    .condvar
+                    .wait_timeout_while(ready, timeout, |ready| !*ready)
+                    .unwrap()
+                    .0
+            }
+            None => {
+                while !*ready {
+                    ready = self.condvar.wait(ready).unwrap();
+                }
+            }
         }
         *ready = false
     }
 }
 
+impl Drop for FreshDisk {
+    fn drop(&mut self) {
+        self.stop_flush_thread();
+    }
+}
+
 impl IndexStore for FreshDisk {
     fn add_nodes(&mut self, data: &[Vec<f32>]) -> Result<Vec<u32>> {
+        self.make_room()?;
         let mut created_node_indices = Vec::new();
 
-        for datum in data {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let node_indexes =
+            self.shared
+                .new_node_indexes(&writer, &mut self.next_node_index, data.len());
+        for (datum, node_index) in data.iter().zip(node_indexes) {
             let node = Node {
-                id: self.next_node_index,
+                id: node_index,
                 vector: datum.clone(),
                 connected: HashSet::new(),
+                quantized: None,
             };
-            self.rw_temp_index.write().unwrap().insert(node.id, node);
-            created_node_indices.push(self.next_node_index);
-            self.next_node_index += 1;
-            self.check_and_convert_rw_index();
+            writer.wal.append(&WalRecord::AddNode {
+                node_index: node.id,
+                vector: node.vector.clone(),
+            })?;
+            self.shared.apply(&mut writer, node.id, Some(node));
+            created_node_indices.push(node_index);
         }
+        writer.wal.flush()?;
+        drop(writer);
+        self.check_and_convert_rw_index()?;
         Ok(created_node_indices)
     }
 
@@ -138,57 +679,145 @@ impl IndexStore for FreshDisk {
             return Err(Error::InvalidInput("node_id=0 is reserved".to_owned()));
         }
 
-        let from_rw_index = self.rw_temp_index.read().unwrap().get(&node_id).cloned();
+        let from_rw_index = self
+            .shared
+            .rw_temp_index
+            .read()
+            .unwrap()
+            .get(&node_id)
+            .cloned();
         if let Some(node) = from_rw_index {
-            return Ok(node);
+            return node.ok_or_else(|| Error::InvalidInput("node not found".to_owned()));
         }
 
-        let from_ro_index = self.ro_temp_index.read().unwrap();
-        for index in from_ro_index.iter().rev() {
-            if let Some(node) = index.get(&node_id) {
-                return Ok(node.clone());
-            }
+        // the ro temp index is released before the long term index is read, see Shared. A segment is only dropped
+        // once it is merged, so a node missing from it by then is in the long term index
+        let from_ro_index = self
+            .shared
+            .ro_temp_index
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .find_map(|index| index.nodes.get(&node_id).cloned());
+        if let Some(node) = from_ro_index {
+            return node.ok_or_else(|| Error::InvalidInput("node not found".to_owned()));
         }
 
-        let node = self.long_term_index.read().unwrap().get_node(node_id)?;
+        let node = self
+            .shared
+            .long_term_index
+            .read()
+            .unwrap()
+            .get_node(node_id)?;
         Ok(node)
     }
 
+    // get_nodes looks nodes up in the temp indexes, and reads the rest from the long term index in one batch
+    fn get_nodes(&self, node_ids: &[u32]) -> Result<Vec<Node>> {
+        if node_ids.contains(&0) {
+            return Err(Error::InvalidInput("node_id=0 is reserved".to_owned()));
+        }
+
+        let mut nodes: Vec<Option<Node>> = vec![None; node_ids.len()];
+        let mut missing: Vec<usize> = Vec::new();
+        {
+            let rw_temp_index = self.shared.rw_temp_index.read().unwrap();
+            for (position, node_id) in node_ids.iter().enumerate() {
+                match rw_temp_index.get(node_id) {
+                    Some(Some(node)) => nodes[position] = Some(node.clone()),
+                    Some(None) => return Err(Error::InvalidInput("node not found".to_owned())),
+                    None => missing.push(position),
+                }
+            }
+        }
+
+        let mut from_long_term: Vec<usize> = Vec::new();
+        {
+            let ro_temp_index = self.shared.ro_temp_index.read().unwrap();
+            for position in missing {
+                let node_id = node_ids[position];
+                match ro_temp_index
+                    .iter()
+                    .rev()
+                    .find_map(|index| index.nodes.get(&node_id))
+                {
+                    Some(Some(node)) => nodes[position] = Some(node.clone()),
+                    Some(None) => return Err(Error::InvalidInput("node not found".to_owned())),
+                    None => from_long_term.push(position),
+                }
+            }
+        }
+
+        let long_term_node_ids: Vec<u32> = from_long_term
+            .iter()
+            .map(|position| node_ids[*position])
+            .collect();
+        let long_term_nodes = self
+            .shared
+            .long_term_index
+            .read()
+            .unwrap()
+            .get_nodes(&long_term_node_ids)?;
+        for (position, node) in from_long_term.into_iter().zip(long_term_nodes) {
+            nodes[position] = Some(node);
+        }
+
+        Ok(nodes.into_iter().flatten().collect())
+    }
+
     fn set_connections(&mut self, node_index: u32, connections: &HashSet<u32>) -> Result<()> {
         if node_index == 0 {
             return Err(Error::InvalidInput("node_id=0 is reserved".to_owned()));
         }
 
+        self.make_room()?;
         let mut node = self.get_node(node_index)?;
         node.connected = connections.clone();
-        self.rw_temp_index.write().unwrap().insert(node_index, node);
-        self.check_and_convert_rw_index();
+
+        let mut writer = self.shared.writer.lock().unwrap();
+        writer.wal.append(&WalRecord::SetConnections {
+            node_index,
+            connections: connections.iter().copied().collect(),
+        })?;
+        self.shared.apply(&mut writer, node_index, Some(node));
+        writer.wal.flush()?;
+        drop(writer);
+        self.check_and_convert_rw_index()?;
         Ok(())
     }
 
     fn get_random_node(&self) -> Option<Node> {
-        self.get_node(1).ok()
+        self.get_node(1).ok().or_else(|| {
+            let node_index = *self.get_all_node_indexes().ok()?.first()?;
+            self.get_node(node_index).ok()
+        })
     }
 
     fn get_all_node_indexes(&self) -> Result<Vec<u32>> {
-        let long_term_index = self.long_term_index.read().unwrap();
-        let ro_index = self.ro_temp_index.read().unwrap();
-        let rw_index = self.rw_temp_index.read().unwrap();
+        let long_term_index = self.shared.long_term_index.read().unwrap();
+        let rw_index = self.shared.rw_temp_index.read().unwrap();
+        let ro_index = self.shared.ro_temp_index.read().unwrap();
 
         let mut node_indexes: HashSet<u32> = long_term_index
             .get_all_node_indexes()?
             .into_iter()
             .collect();
 
-        ro_index.iter().for_each(|index| {
-            index.keys().for_each(|node_id| {
-                node_indexes.insert(*node_id);
+        // must apply from the first ro_index, because outdated data will be before updated data
+        ro_index
+            .iter()
+            .map(|segment| &segment.nodes)
+            .chain(std::iter::once(&*rw_index))
+            .for_each(|index| {
+                index.iter().for_each(|(node_id, node)| {
+                    if node.is_some() {
+                        node_indexes.insert(*node_id);
+                    } else {
+                        node_indexes.remove(node_id);
+                    }
+                });
             });
-        });
-
-        rw_index.keys().for_each(|node_id| {
-            node_indexes.insert(*node_id);
-        });
 
         let mut node_indexes: Vec<u32> = node_indexes.into_iter().collect();
         node_indexes.sort_unstable();
@@ -196,23 +825,26 @@ impl IndexStore for FreshDisk {
     }
 
     fn get_all_nodes(&self) -> Result<HashMap<u32, Node>> {
-        let long_term_index = self.long_term_index.read().unwrap();
-        let ro_index = self.ro_temp_index.read().unwrap();
-        let rw_index = self.rw_temp_index.read().unwrap();
+        let long_term_index = self.shared.long_term_index.read().unwrap();
+        let rw_index = self.shared.rw_temp_index.read().unwrap();
+        let ro_index = self.shared.ro_temp_index.read().unwrap();
 
         // order of insertion must be long term index > ro index > rw index
         let mut all_nodes: HashMap<u32, Node> = long_term_index.get_all_nodes()?;
 
         // must read from the first ro_index, because outdated data will be before updated data
-        ro_index.iter().for_each(|index| {
-            for (node_id, node) in index.iter() {
-                all_nodes.insert(*node_id, node.clone());
-            }
-        });
-
-        rw_index.iter().for_each(|(node_id, node)| {
-            all_nodes.insert(*node_id, node.clone());
-        });
+        ro_index
+            .iter()
+            .map(|segment| &segment.nodes)
+            .chain(std::iter::once(&*rw_index))
+            .for_each(|index| {
+                for (node_id, node) in index.iter() {
+                    match node {
+                        Some(node) => all_nodes.insert(*node_id, node.clone()),
+                        None => all_nodes.remove(node_id),
+                    };
+                }
+            });
 
         Ok(all_nodes)
     }
@@ -220,6 +852,130 @@ impl IndexStore for FreshDisk {
     fn get_name(&self) -> String {
         "FreshDisk".into()
     }
+
+    fn get_metric(&self) -> Metric {
+        self.shared.long_term_index.read().unwrap().get_metric()
+    }
+
+    fn set_metric(&mut self, metric: Metric) -> Result<()> {
+        self.shared
+            .long_term_index
+            .write()
+            .unwrap()
+            .set_metric(metric)
+    }
+
+    fn get_entry_points(&self) -> Vec<u32> {
+        self.shared
+            .long_term_index
+            .read()
+            .unwrap()
+            .get_entry_points()
+    }
+
+    // entry points go straight into the long term index header, and may point at nodes that are still only in the WAL
+    fn set_entry_points(&mut self, entry_points: &[u32]) -> Result<()> {
+        self.shared
+            .long_term_index
+            .write()
+            .unwrap()
+            .set_entry_points(entry_points)
+    }
+
+    fn delete_node(&mut self, node_index: u32) -> Result<()> {
+        self.get_node(node_index)?;
+        let mut writer = self.shared.writer.lock().unwrap();
+        writer.wal.append(&WalRecord::DeleteNode { node_index })?;
+        writer.wal.flush()?;
+        self.shared.delete_list.write().unwrap().insert(node_index);
+        Ok(())
+    }
+
+    fn is_deleted(&self, node_index: u32) -> bool {
+        self.shared
+            .delete_list
+            .read()
+            .unwrap()
+            .contains(&node_index)
+    }
+
+    fn get_deleted_node_indexes(&self) -> Result<HashSet<u32>> {
+        Ok(self.shared.delete_list.read().unwrap().clone())
+    }
+
+    // node_counts adjusts the count of the long-term index by the nodes the temp indexes add or remove
+    fn node_counts(&self) -> Result<(usize, usize)> {
+        let node_count = {
+            let long_term_index = self.shared.long_term_index.read().unwrap();
+            let rw_index = self.shared.rw_temp_index.read().unwrap();
+            let ro_index = self.shared.ro_temp_index.read().unwrap();
+
+            // the last segment that holds a node decides whether it is in the graph
+            let mut temp_nodes: HashMap<u32, bool> = HashMap::new();
+            ro_index
+                .iter()
+                .map(|segment| &segment.nodes)
+                .chain(std::iter::once(&*rw_index))
+                .for_each(|index| {
+                    index.iter().for_each(|(node_id, node)| {
+                        temp_nodes.insert(*node_id, node.is_some());
+                    });
+                });
+
+            let (mut node_count, _) = long_term_index.node_counts()?;
+            for (node_index, in_graph) in temp_nodes {
+                match (long_term_index.has_node(node_index), in_graph) {
+                    (false, true) => node_count += 1,
+                    (true, false) => node_count -= 1,
+                    _ => {}
+                }
+            }
+            node_count
+        };
+        Ok((node_count, self.shared.delete_list.read().unwrap().len()))
+    }
+
+    fn remove_nodes(&mut self, node_indexes: &HashSet<u32>) -> Result<()> {
+        self.make_room()?;
+        let mut writer = self.shared.writer.lock().unwrap();
+        for node_index in node_indexes {
+            writer.wal.append(&WalRecord::RemoveNode {
+                node_index: *node_index,
+            })?;
+            self.shared.apply(&mut writer, *node_index, None);
+            self.shared.delete_list.write().unwrap().remove(node_index);
+        }
+        writer.wal.flush()?;
+        drop(writer);
+        self.check_and_convert_rw_index()?;
+        Ok(())
+    }
+
+    // flush blocks until every update so far is persisted and fsynced in the long term index, freezing the rw temp
+    // index even if it isn't full. Unlike other writes, it waits for room whatever the backpressure
+    fn flush(&mut self) -> Result<()> {
+        if !self.shared.rw_temp_index.read().unwrap().is_empty() {
+            if !self.shared.can_freeze() {
+                self.wait_for_room()?;
+            }
+            self.shared.freeze_rw_index()?;
+        }
+
+        let mut state = self.shared.signals.state.lock().unwrap();
+        while !self.shared.ro_temp_index.read().unwrap().is_empty() {
+            state.check()?;
+            state = self.shared.signals.merged_condvar.wait(state).unwrap();
+        }
+        drop(state);
+
+        // entry points and the metric are written to the long term index directly, outside of merges
+        self.shared.long_term_index.read().unwrap().sync()?;
+        Ok(())
+    }
+
+    fn as_sync(&self) -> Option<&(dyn IndexStore + Sync)> {
+        Some(self)
+    }
 }
 
 #[cfg(test)]
@@ -267,4 +1023,390 @@ mod tests {
         assert_eq!(vec![3.0, 4.0], retrieved_node2.vector);
         assert_eq!(HashSet::from([1]), retrieved_node2.connected);
     }
+
+    #[test]
+    fn test_open_replays_wal() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_wal_replay.index");
+        let free_path = temp_dir.as_path().join("test_wal_replay.free");
+
+        let mut fresh_disk = FreshDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        fresh_disk
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 6.0]])
+            .unwrap();
+        fresh_disk
+            .set_connections(1, &HashSet::from([2u32, 3u32]))
+            .unwrap();
+        fresh_disk.delete_node(3).unwrap();
+        fresh_disk.remove_nodes(&HashSet::from([2u32])).unwrap();
+
+        // nothing was flushed to the long term index, so every update must come back from the WAL
+        drop(fresh_disk);
+        let mut reopened =
+            FreshDisk::open(index_path.to_str().unwrap(), free_path.to_str().unwrap()).unwrap();
+
+        let retrieved_node1 = reopened.get_node(1).unwrap();
+        assert_eq!(vec![1.0, 2.0], retrieved_node1.vector);
+        assert_eq!(HashSet::from([2, 3]), retrieved_node1.connected);
+        assert!(reopened.get_node(2).is_err());
+        assert!(reopened.is_deleted(3));
+        assert_eq!(vec![1, 3], reopened.get_all_node_indexes().unwrap());
+
+        let ids = reopened.add_nodes(&[vec![7.0, 8.0]]).unwrap();
+        assert_eq!(ids, vec![4]);
+    }
+
+    #[test]
+    fn test_open_after_partial_merge() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_partial_merge.index");
+        let free_path = temp_dir.as_path().join("test_partial_merge.free");
+        let index_path = index_path.to_str().unwrap();
+        let free_path = free_path.to_str().unwrap();
+
+        // a segment added 3 nodes, and its merge crashed once it had written the first 2 with their merged edges
+        let mut long_term_index = NaiveDisk::new(2, 3, index_path, free_path).unwrap();
+        long_term_index
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0]])
+            .unwrap();
+        long_term_index
+            .set_connections(1, &HashSet::from([2u32]))
+            .unwrap();
+        long_term_index
+            .set_connections(2, &HashSet::from([1u32]))
+            .unwrap();
+        drop(long_term_index);
+        for (_, wal_path) in Wal::list(index_path).unwrap() {
+            Wal::remove(&wal_path).unwrap();
+        }
+        let mut wal = Wal::create(Wal::segment_path(index_path, 0)).unwrap();
+        for (node_index, vector) in [
+            (1, vec![1.0, 2.0]),
+            (2, vec![3.0, 4.0]),
+            (3, vec![5.0, 6.0]),
+        ] {
+            wal.append(&WalRecord::AddNode { node_index, vector })
+                .unwrap();
+        }
+        for (node_index, connections) in [(1, vec![3]), (2, vec![3]), (3, vec![1, 2])] {
+            wal.append(&WalRecord::SetConnections {
+                node_index,
+                connections,
+            })
+            .unwrap();
+        }
+        wal.flush().unwrap();
+        drop(wal);
+
+        // the merged nodes keep their merged edges, the rest of the segment is replayed
+        let mut reopened = FreshDisk::open(index_path, free_path).unwrap();
+        assert_eq!(HashSet::from([2]), reopened.get_node(1).unwrap().connected);
+        assert_eq!(HashSet::from([1]), reopened.get_node(2).unwrap().connected);
+        assert_eq!(
+            HashSet::from([1, 2]),
+            reopened.get_node(3).unwrap().connected
+        );
+
+        reopened.flush().unwrap();
+        assert_eq!(vec![1, 2, 3], reopened.get_all_node_indexes().unwrap());
+        assert!(reopened.get_node(1).unwrap().connected.contains(&2));
+        assert_eq!(vec![4], reopened.add_nodes(&[vec![7.0, 8.0]]).unwrap());
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_backpressure.index");
+        let free_path = temp_dir.as_path().join("test_backpressure.free");
+
+        let mut fresh_disk = FreshDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        fresh_disk.set_config(FreshDiskConfig {
+            backpressure: Backpressure {
+                max_frozen_segments: 1,
+                block: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let vectors: Vec<Vec<f32>> = (0..10000).map(|i| vec![i as f32, 0.0]).collect();
+
+        // holding the long term index keeps the flush thread from merging
+        let shared = fresh_disk.shared.clone();
+        let (locked_sender, locked) = std::sync::mpsc::channel();
+        let (release, release_receiver) = std::sync::mpsc::channel::<()>();
+        let locker = std::thread::spawn(move || {
+            let _long_term = shared.long_term_index.write().unwrap();
+            locked_sender.send(()).unwrap();
+            release_receiver.recv().unwrap();
+        });
+        locked.recv().unwrap();
+
+        // the first full temp index is frozen, the second one has to wait for it to be merged
+        fresh_disk.add_nodes(&vectors).unwrap();
+        fresh_disk.add_nodes(&vectors).unwrap();
+        let lag = fresh_disk.flush_lag();
+        assert_eq!(1, lag.frozen_segments);
+        assert_eq!(10000, lag.frozen_entries);
+        assert!(lag.frozen_bytes > 0);
+        assert!(matches!(
+            fresh_disk.add_nodes(&vectors[..1]),
+            Err(Error::Busy(_))
+        ));
+
+        // a blocking writer gets through once the segment is merged
+        fresh_disk.set_config(FreshDiskConfig {
+            backpressure: Backpressure {
+                max_frozen_segments: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        release.send(()).unwrap();
+        locker.join().unwrap();
+        assert_eq!(vec![20001], fresh_disk.add_nodes(&vectors[..1]).unwrap());
+        assert!(fresh_disk.flush_lag().merged_segments >= 1);
+        assert_eq!(20001, fresh_disk.get_all_node_indexes().unwrap().len());
+    }
+
+    #[test]
+    fn test_flush_and_close() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_flush.index");
+        let free_path = temp_dir.as_path().join("test_flush.free");
+
+        let mut fresh_disk = FreshDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        fresh_disk
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 6.0]])
+            .unwrap();
+        fresh_disk
+            .set_connections(1, &HashSet::from([2u32, 3u32]))
+            .unwrap();
+        fresh_disk.set_entry_points(&[1]).unwrap();
+
+        // the temp index is far from full, flush persists it anyway
+        fresh_disk.flush().unwrap();
+        let lag = fresh_disk.flush_lag();
+        assert_eq!(0, lag.frozen_segments);
+        assert_eq!(1, lag.merged_segments);
+        assert_eq!(
+            vec![1, 2, 3],
+            fresh_disk
+                .shared
+                .long_term_index
+                .read()
+                .unwrap()
+                .get_all_node_indexes()
+                .unwrap()
+        );
+        // only the WAL of new updates is left
+        assert_eq!(1, Wal::list(index_path.to_str().unwrap()).unwrap().len());
+
+        fresh_disk.remove_nodes(&HashSet::from([3u32])).unwrap();
+        fresh_disk.close().unwrap();
+
+        let reopened =
+            FreshDisk::open(index_path.to_str().unwrap(), free_path.to_str().unwrap()).unwrap();
+        assert_eq!(vec![1, 2], reopened.get_all_node_indexes().unwrap());
+        assert!(!reopened.get_node(1).unwrap().connected.contains(&3));
+    }
+
+    #[test]
+    fn test_add_nodes_reuses_freed_slots() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_fresh_free.index");
+        let free_path = temp_dir.as_path().join("test_fresh_free.free");
+
+        let mut fresh_disk = FreshDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        fresh_disk
+            .add_nodes(&[
+                vec![1.0, 2.0],
+                vec![3.0, 4.0],
+                vec![5.0, 6.0],
+                vec![7.0, 8.0],
+            ])
+            .unwrap();
+        fresh_disk
+            .remove_nodes(&HashSet::from([2u32, 3u32]))
+            .unwrap();
+
+        // slots are only free once the segment that removed their nodes is merged
+        assert_eq!(vec![5], fresh_disk.add_nodes(&[vec![9.0, 10.0]]).unwrap());
+        fresh_disk.delete_node(4).unwrap();
+        assert_eq!((3, 1), fresh_disk.node_counts().unwrap());
+        fresh_disk.remove_nodes(&HashSet::from([5u32])).unwrap();
+        fresh_disk.flush().unwrap();
+
+        let ids = fresh_disk
+            .add_nodes(&[vec![11.0, 12.0], vec![13.0, 14.0], vec![15.0, 16.0]])
+            .unwrap();
+        assert_eq!(vec![2, 3, 5], ids);
+        fresh_disk.flush().unwrap();
+        assert_eq!((5, 1), fresh_disk.node_counts().unwrap());
+
+        let long_term_index = fresh_disk.shared.long_term_index.read().unwrap();
+        assert_eq!(
+            vec![1, 2, 3, 4, 5],
+            long_term_index.get_all_node_indexes().unwrap()
+        );
+        assert!(long_term_index.free_slots().is_empty());
+        assert_eq!(
+            vec![11.0, 12.0],
+            long_term_index.get_node(2).unwrap().vector
+        );
+    }
+
+    #[test]
+    fn test_segment_thresholds() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_segment_thresholds.index");
+        let free_path = temp_dir.as_path().join("test_segment_thresholds.free");
+
+        let mut fresh_disk = FreshDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+
+        // a segment over max_segment_bytes is frozen however few entries it holds
+        fresh_disk.set_config(FreshDiskConfig {
+            max_segment_bytes: 1,
+            max_segment_age: None,
+            ..Default::default()
+        });
+        fresh_disk.add_nodes(&[vec![1.0, 2.0]]).unwrap();
+        assert!(fresh_disk.shared.rw_temp_index.read().unwrap().is_empty());
+
+        // a low rate writer gets its updates merged once they are max_segment_age old, without a flush
+        fresh_disk.set_config(FreshDiskConfig {
+            max_segment_age: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        fresh_disk.add_nodes(&[vec![3.0, 4.0]]).unwrap();
+        let waiting_since = Instant::now();
+        while fresh_disk.flush_lag().merged_segments < 2 {
+            assert!(waiting_since.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(fresh_disk.shared.rw_temp_index.read().unwrap().is_empty());
+        assert_eq!(
+            vec![1, 2],
+            fresh_disk
+                .shared
+                .long_term_index
+                .read()
+                .unwrap()
+                .get_all_node_indexes()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_flush_throttle() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.as_path().join("test_flush_throttle.index");
+        let free_path = temp_dir.as_path().join("test_flush_throttle.free");
+
+        let mut fresh_disk = FreshDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        // a node takes 24 bytes, so the merge writes a few hundred bytes
+        fresh_disk.set_config(FreshDiskConfig {
+            max_flush_bytes_per_sec: Some(1000),
+            ..Default::default()
+        });
+        fresh_disk
+            .add_nodes(&[vec![1.0, 2.0], vec![3.0, 4.0], vec![5.0, 6.0]])
+            .unwrap();
+        fresh_disk.flush().unwrap();
+        assert!(fresh_disk.flush_lag().throttled > Duration::ZERO);
+        assert_eq!(vec![1, 2, 3], fresh_disk.get_all_node_indexes().unwrap());
+
+        // lifting the limit lets merges write right away
+        fresh_disk.set_config(FreshDiskConfig::default());
+        let throttled = fresh_disk.flush_lag().throttled;
+        fresh_disk.add_nodes(&[vec![7.0, 8.0]]).unwrap();
+        fresh_disk.flush().unwrap();
+        assert_eq!(throttled, fresh_disk.flush_lag().throttled);
+    }
+
+    #[test]
+    fn test_reads_during_age_freezes() {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir
+            .as_path()
+            .join("test_reads_during_age_freezes.index");
+        let free_path = temp_dir
+            .as_path()
+            .join("test_reads_during_age_freezes.free");
+
+        let mut fresh_disk = FreshDisk::new(
+            2,
+            3,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+        fresh_disk.set_config(FreshDiskConfig {
+            max_segment_age: Some(Duration::from_millis(1)),
+            ..Default::default()
+        });
+
+        // the flush thread keeps freezing the rw temp index under readers going through every index
+        let fresh_disk = RwLock::new(fresh_disk);
+        let writing = AtomicBool::new(true);
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    while writing.load(Ordering::SeqCst) {
+                        let fresh_disk = fresh_disk.read().unwrap();
+                        fresh_disk.get_all_nodes().unwrap();
+                        fresh_disk.get_all_node_indexes().unwrap();
+                    }
+                });
+            }
+            for i in 0..200 {
+                fresh_disk
+                    .write()
+                    .unwrap()
+                    .add_nodes(&[vec![i as f32, 0.0]])
+                    .unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            writing.store(false, Ordering::SeqCst);
+        });
+
+        let fresh_disk = fresh_disk.into_inner().unwrap();
+        assert!(fresh_disk.flush_lag().merged_segments > 1);
+        assert_eq!(200, fresh_disk.get_all_nodes().unwrap().len());
+    }
 }
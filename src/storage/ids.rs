@@ -0,0 +1,316 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::prelude::*;
+
+// ExternalId is the id a caller picks for a vector, mapped by IdMap onto the node index the index store gave it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExternalId {
+    U64(u64),
+    String(String),
+}
+
+impl From<u64> for ExternalId {
+    fn from(id: u64) -> Self {
+        ExternalId::U64(id)
+    }
+}
+
+impl From<String> for ExternalId {
+    fn from(id: String) -> Self {
+        ExternalId::String(id)
+    }
+}
+
+impl From<&str> for ExternalId {
+    fn from(id: &str) -> Self {
+        ExternalId::String(id.to_string())
+    }
+}
+
+impl fmt::Display for ExternalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternalId::U64(id) => write!(f, "{}", id),
+            ExternalId::String(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+// id log layout
+// append-only, replayed into memory by IdMap::open
+//
+// .ids file:
+// [record][record]...
+//
+// where [record], as in the FreshDisk write-ahead log:
+// [payload length][crc32 of payload][payload          ]
+// [u32           ][u32             ][u8 * length      ]
+//
+// where [payload]:
+// [op][node index][op specific fields]
+// [u8][u32       ][                  ]
+//
+// op specific fields:
+// set:    [id kind][id                              ]
+//         [u8     ][u64, or UTF-8 bytes of a string ]
+// remove: none
+//
+// set maps the id onto the node index, replacing the previous node of the id. remove unmaps the node index.
+// A record that is cut short or fails its checksum is treated as the end of the log
+const OP_SET: u8 = 0;
+const OP_REMOVE: u8 = 1;
+
+const ID_KIND_U64: u8 = 0;
+const ID_KIND_STRING: u8 = 1;
+
+// IdMap maps external ids onto node indexes and back, in RAM. A map created with new or open also appends every
+// change to a log, so that it survives restarts together with the index
+#[derive(Default)]
+pub struct IdMap {
+    node_indexes: HashMap<ExternalId, u32>,
+    external_ids: HashMap<u32, ExternalId>,
+    // None for a map that is only kept in RAM
+    log: Option<BufWriter<File>>,
+}
+
+impl IdMap {
+    // create an empty map logged at path, truncating any existing log
+    pub fn new(path: &str) -> Result<Self> {
+        Ok(IdMap {
+            log: Some(BufWriter::new(File::create(path)?)),
+            ..Default::default()
+        })
+    }
+
+    // reopen a map previously created with IdMap::new. The log is rewritten with only the current mappings, which
+    // drops the records of replaced and removed ids as well as a record torn by a crash
+    pub fn open(path: &str) -> Result<Self> {
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+
+        let mut ids = IdMap::default();
+        let mut offset = 0;
+        while let Some(header) = buffer.get(offset..offset + 8) {
+            let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let Some(payload) = buffer.get(offset + 8..offset + 8 + length) else {
+                break;
+            };
+            if crc32fast::hash(payload) != checksum || !ids.apply(payload) {
+                break;
+            }
+            offset += 8 + length;
+        }
+
+        // the compacted log replaces the old one at once, so a crash leaves either of them intact
+        let compacted_path = PathBuf::from(format!("{}.compact", path));
+        let mut log = BufWriter::new(File::create(&compacted_path)?);
+        for (node_index, id) in ids.external_ids.iter() {
+            Self::append(&mut log, &Self::encode_set(id, *node_index))?;
+        }
+        log.flush()?;
+        log.get_ref().sync_all()?;
+        fs::rename(&compacted_path, path)?;
+
+        let file = OpenOptions::new().append(true).open(Path::new(path))?;
+        ids.log = Some(BufWriter::new(file));
+        Ok(ids)
+    }
+
+    pub fn len(&self) -> usize {
+        self.node_indexes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.node_indexes.is_empty()
+    }
+
+    pub fn node_index(&self, id: &ExternalId) -> Option<u32> {
+        self.node_indexes.get(id).copied()
+    }
+
+    pub fn external_id(&self, node_index: u32) -> Option<&ExternalId> {
+        self.external_ids.get(&node_index)
+    }
+
+    // set maps id onto node_index, returning the node index id was mapped onto before
+    pub fn set(&mut self, id: ExternalId, node_index: u32) -> Result<Option<u32>> {
+        self.log(&Self::encode_set(&id, node_index))?;
+        if let Some(previous_id) = self.external_ids.insert(node_index, id.clone()) {
+            if previous_id != id {
+                self.node_indexes.remove(&previous_id);
+            }
+        }
+        let previous = self.node_indexes.insert(id, node_index);
+        if let Some(previous) = previous.filter(|previous| *previous != node_index) {
+            self.external_ids.remove(&previous);
+        }
+        Ok(previous)
+    }
+
+    // remove unmaps id, returning the node index it was mapped onto
+    pub fn remove(&mut self, id: &ExternalId) -> Result<Option<u32>> {
+        let Some(node_index) = self.node_index(id) else {
+            return Ok(None);
+        };
+        self.remove_nodes(&HashSet::from([node_index]))?;
+        Ok(Some(node_index))
+    }
+
+    // remove_nodes unmaps node_indexes, such as nodes removed from the graph whose slots may be reused
+    pub fn remove_nodes(&mut self, node_indexes: &HashSet<u32>) -> Result<()> {
+        for node_index in node_indexes {
+            let Some(id) = self.external_ids.remove(node_index) else {
+                continue;
+            };
+            self.node_indexes.remove(&id);
+            self.log(&Self::encode_remove(*node_index))?;
+        }
+        Ok(())
+    }
+
+    // sync fsyncs the log, if any, so that the changes so far survive a crash of the machine
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        if let Some(log) = &mut self.log {
+            log.flush()?;
+            log.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    // append payload to the log, if any, and hand it to the OS so that it survives a crash of the process
+    fn log(&mut self, payload: &[u8]) -> Result<()> {
+        if let Some(log) = &mut self.log {
+            Self::append(log, payload)?;
+            log.flush()?;
+        }
+        Ok(())
+    }
+
+    fn append(log: &mut BufWriter<File>, payload: &[u8]) -> Result<()> {
+        log.write_all(&(payload.len() as u32).to_be_bytes())?;
+        log.write_all(&crc32fast::hash(payload).to_be_bytes())?;
+        log.write_all(payload)?;
+        Ok(())
+    }
+
+    fn encode_set(id: &ExternalId, node_index: u32) -> Vec<u8> {
+        let mut payload = vec![OP_SET];
+        payload.extend_from_slice(&node_index.to_be_bytes());
+        match id {
+            ExternalId::U64(id) => {
+                payload.push(ID_KIND_U64);
+                payload.extend_from_slice(&id.to_be_bytes());
+            }
+            ExternalId::String(id) => {
+                payload.push(ID_KIND_STRING);
+                payload.extend_from_slice(id.as_bytes());
+            }
+        }
+        payload
+    }
+
+    fn encode_remove(node_index: u32) -> Vec<u8> {
+        let mut payload = vec![OP_REMOVE];
+        payload.extend_from_slice(&node_index.to_be_bytes());
+        payload
+    }
+
+    // apply a replayed record to the map, returning false if it can't be decoded
+    fn apply(&mut self, payload: &[u8]) -> bool {
+        let Some((&op, rest)) = payload.split_first() else {
+            return false;
+        };
+        let Some(node_index) = rest.get(0..4) else {
+            return false;
+        };
+        let node_index = u32::from_be_bytes(node_index.try_into().unwrap());
+        let rest = &rest[4..];
+
+        let id = match (op, rest.split_first()) {
+            (OP_REMOVE, None) => {
+                // the map has no log yet, so these can't fail
+                let _ = self.remove_nodes(&HashSet::from([node_index]));
+                return true;
+            }
+            (OP_SET, Some((&ID_KIND_U64, id))) => match id.try_into() {
+                Ok(id) => ExternalId::U64(u64::from_be_bytes(id)),
+                Err(_) => return false,
+            },
+            (OP_SET, Some((&ID_KIND_STRING, id))) => match std::str::from_utf8(id) {
+                Ok(id) => ExternalId::String(id.to_string()),
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+        let _ = self.set(id, node_index);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_set_and_remove() {
+        let mut ids = IdMap::default();
+        assert_eq!(None, ids.set(7u64.into(), 1).unwrap());
+        assert_eq!(None, ids.set("seven".into(), 2).unwrap());
+        assert_eq!(Some(1), ids.node_index(&ExternalId::U64(7)));
+        assert_eq!(Some(&ExternalId::from("seven")), ids.external_id(2));
+
+        // setting an existing id moves it to the new node
+        assert_eq!(Some(1), ids.set(7u64.into(), 3).unwrap());
+        assert_eq!(Some(3), ids.node_index(&ExternalId::U64(7)));
+        assert_eq!(None, ids.external_id(1));
+
+        // setting a node that already has an id replaces the id
+        ids.set("eight".into(), 2).unwrap();
+        assert_eq!(None, ids.node_index(&"seven".into()));
+        assert_eq!(2, ids.len());
+
+        assert_eq!(Some(3), ids.remove(&ExternalId::U64(7)).unwrap());
+        ids.remove_nodes(&HashSet::from([2, 5])).unwrap();
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn test_open_existing_id_map() {
+        let path = env::temp_dir().join("test.ids");
+        let path = path.to_str().unwrap();
+
+        let mut ids = IdMap::new(path).unwrap();
+        ids.set(7u64.into(), 1).unwrap();
+        ids.set("seven".into(), 2).unwrap();
+        ids.set(7u64.into(), 3).unwrap();
+        ids.set("eight".into(), 4).unwrap();
+        ids.remove_nodes(&HashSet::from([4])).unwrap();
+        drop(ids);
+
+        // a torn record at the end of the log is dropped
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[0, 0, 0, 9, 1, 2]).unwrap();
+        drop(file);
+
+        let mut reopened = IdMap::open(path).unwrap();
+        assert_eq!(2, reopened.len());
+        assert_eq!(Some(3), reopened.node_index(&ExternalId::U64(7)));
+        assert_eq!(Some(2), reopened.node_index(&"seven".into()));
+        assert_eq!(None, reopened.node_index(&"eight".into()));
+
+        // changes after reopening are appended to the compacted log
+        reopened.set("nine".into(), 5).unwrap();
+        drop(reopened);
+        let reopened = IdMap::open(path).unwrap();
+        assert_eq!(3, reopened.len());
+        assert_eq!(Some(&ExternalId::from("nine")), reopened.external_id(5));
+    }
+}
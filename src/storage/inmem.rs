@@ -5,15 +5,19 @@ use std::{
 
 use rand::Rng;
 
-use crate::graph::Node;
+use crate::graph::{Metric, Node};
 use crate::{prelude::*, Error};
 
 use super::{storage::DataStore, IndexStore};
 
 #[derive(Default)]
 pub struct InMemStorage {
-    nodes: Vec<Node>,
-    data: HashMap<u32, String>,
+    // removed nodes are kept as None so that node ids stay stable
+    nodes: Vec<Option<Node>>,
+    deleted: HashSet<u32>,
+    metric: Metric,
+    entry_points: Vec<u32>,
+    data: HashMap<u32, Vec<u8>>,
 }
 
 impl IndexStore for InMemStorage {
@@ -21,11 +25,12 @@ impl IndexStore for InMemStorage {
         let mut node_ids = Vec::new();
         for vector in data {
             let node_id = self.nodes.len() as u32;
-            self.nodes.push(Node {
+            self.nodes.push(Some(Node {
                 id: node_id,
                 vector: vector.clone(),
                 connected: HashSet::new(),
-            });
+                quantized: None,
+            }));
             node_ids.push(node_id);
         }
         Ok(node_ids)
@@ -34,12 +39,13 @@ impl IndexStore for InMemStorage {
     fn get_node(&self, node_id: u32) -> Result<Node> {
         self.nodes
             .get(node_id as usize)
+            .and_then(|node| node.as_ref())
             .cloned()
             .ok_or_else(|| Error::InvalidInput("Node not found".to_owned()))
     }
 
     fn set_connections(&mut self, node_index: u32, connections: &HashSet<u32>) -> Result<()> {
-        if let Some(node) = self.nodes.get_mut(node_index as usize) {
+        if let Some(Some(node)) = self.nodes.get_mut(node_index as usize) {
             node.connected = connections.clone();
             Ok(())
         } else {
@@ -48,17 +54,18 @@ impl IndexStore for InMemStorage {
     }
 
     fn get_random_node(&self) -> Option<Node> {
-        return self.nodes.get(0).cloned();
+        self.nodes.iter().flatten().next().cloned()
     }
 
     fn get_all_node_indexes(&self) -> Result<Vec<u32>> {
-        Ok((0..self.nodes.len() as u32).collect())
+        Ok(self.nodes.iter().flatten().map(|node| node.id).collect())
     }
 
     fn get_all_nodes(&self) -> Result<HashMap<u32, Node>> {
         Ok(self
             .nodes
             .iter()
+            .flatten()
             .map(|node| (node.id, node.clone()))
             .collect())
     }
@@ -66,15 +73,70 @@ impl IndexStore for InMemStorage {
     fn get_name(&self) -> String {
         "InMem".into()
     }
+
+    fn get_metric(&self) -> Metric {
+        self.metric
+    }
+
+    fn set_metric(&mut self, metric: Metric) -> Result<()> {
+        self.metric = metric;
+        Ok(())
+    }
+
+    fn get_entry_points(&self) -> Vec<u32> {
+        self.entry_points.clone()
+    }
+
+    fn set_entry_points(&mut self, entry_points: &[u32]) -> Result<()> {
+        self.entry_points = entry_points.to_vec();
+        Ok(())
+    }
+
+    fn delete_node(&mut self, node_index: u32) -> Result<()> {
+        self.get_node(node_index)?;
+        self.deleted.insert(node_index);
+        Ok(())
+    }
+
+    fn is_deleted(&self, node_index: u32) -> bool {
+        self.deleted.contains(&node_index)
+    }
+
+    fn get_deleted_node_indexes(&self) -> Result<HashSet<u32>> {
+        Ok(self.deleted.clone())
+    }
+
+    fn remove_nodes(&mut self, node_indexes: &HashSet<u32>) -> Result<()> {
+        for node_index in node_indexes {
+            if let Some(node) = self.nodes.get_mut(*node_index as usize) {
+                *node = None;
+            }
+            self.deleted.remove(node_index);
+        }
+        Ok(())
+    }
+
+    fn as_sync(&self) -> Option<&(dyn IndexStore + Sync)> {
+        Some(self)
+    }
 }
 
 impl DataStore for InMemStorage {
-    fn add_data(&mut self, node_id: u32, data: String) -> Result<()> {
-        self.data.insert(node_id, data);
+    fn add_data(&mut self, node_id: u32, data: &[u8]) -> Result<()> {
+        self.data.insert(node_id, data.to_vec());
+        Ok(())
+    }
+
+    fn get_data(&self, node_id: u32) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(&node_id).cloned())
+    }
+
+    fn delete_data(&mut self, node_id: u32) -> Result<()> {
+        self.data.remove(&node_id);
         Ok(())
     }
 
-    fn get_data(&self, node_id: u32) -> Option<String> {
-        self.data.get(&node_id).cloned()
+    fn as_sync(&self) -> Option<&(dyn DataStore + Sync)> {
+        Some(self)
     }
 }
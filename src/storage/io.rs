@@ -0,0 +1,295 @@
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+#[cfg(target_os = "linux")]
+use std::{cell::RefCell, os::unix::io::AsRawFd};
+
+#[cfg(target_os = "linux")]
+use io_uring::{opcode, types, EnterFlags, IoUring};
+
+// at most QUEUE_DEPTH reads or writes are in flight at once, larger batches are submitted in chunks
+#[cfg(target_os = "linux")]
+const QUEUE_DEPTH: u32 = 64;
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    // every thread submits to a ring of its own, so that threads reading nodes at once don't wait for each other.
+    // It is set up on first use, and dropped when an error leaves entries in it that were never submitted
+    static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+// IoBackend is how NaiveDisk reads and writes batches of node records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    // one pread or pwrite syscall per record
+    Pread,
+    // all records of a batch are submitted to an io_uring at once
+    IoUring,
+}
+
+// DiskIo reads and writes batches of (offset, buffer) of a file. Every buffer is read or written in full
+pub(crate) struct DiskIo {
+    backend: IoBackend,
+}
+
+impl DiskIo {
+    // new sets up backend, falling back to pread when io_uring isn't available
+    pub(crate) fn new(backend: IoBackend) -> Self {
+        #[cfg(target_os = "linux")]
+        if backend == IoBackend::IoUring && Self::with_ring(|_| Ok(())).is_ok() {
+            return DiskIo { backend };
+        }
+        DiskIo {
+            backend: IoBackend::Pread,
+        }
+    }
+
+    pub(crate) fn backend(&self) -> IoBackend {
+        self.backend
+    }
+
+    pub(crate) fn read_many(&self, file: &File, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.backend == IoBackend::IoUring {
+            for chunk in reads.chunks_mut(QUEUE_DEPTH as usize) {
+                let entries: Vec<_> = chunk
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(i, (offset, buffer))| {
+                        opcode::Read::new(
+                            types::Fd(file.as_raw_fd()),
+                            buffer.as_mut_ptr(),
+                            buffer.len() as u32,
+                        )
+                        .offset(*offset)
+                        .build()
+                        .user_data(i as u64)
+                    })
+                    .collect();
+                let done = Self::with_ring(|ring| Self::submit(ring, &entries))?;
+
+                // short reads, such as reads cut by the end of the file, are finished with pread
+                for (i, read) in done {
+                    let (offset, buffer) = &mut chunk[i];
+                    file.read_exact_at(&mut buffer[read..], *offset + read as u64)?;
+                }
+            }
+            return Ok(());
+        }
+
+        for (offset, buffer) in reads.iter_mut() {
+            file.read_exact_at(buffer, *offset)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_many(&self, file: &File, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.backend == IoBackend::IoUring {
+            for chunk in writes.chunks(QUEUE_DEPTH as usize) {
+                let entries: Vec<_> = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (offset, buffer))| {
+                        opcode::Write::new(
+                            types::Fd(file.as_raw_fd()),
+                            buffer.as_ptr(),
+                            buffer.len() as u32,
+                        )
+                        .offset(*offset)
+                        .build()
+                        .user_data(i as u64)
+                    })
+                    .collect();
+                let done = Self::with_ring(|ring| Self::submit(ring, &entries))?;
+
+                for (i, written) in done {
+                    let (offset, buffer) = chunk[i];
+                    file.write_all_at(&buffer[written..], offset + written as u64)?;
+                }
+            }
+            return Ok(());
+        }
+
+        for (offset, buffer) in writes {
+            file.write_all_at(buffer, *offset)?;
+        }
+        Ok(())
+    }
+
+    // with_ring runs f with the ring of the current thread, setting it up if needed
+    #[cfg(target_os = "linux")]
+    fn with_ring<T>(f: impl FnOnce(&mut IoUring) -> io::Result<T>) -> io::Result<T> {
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            let result = match ring.as_mut() {
+                Some(ring) => f(ring),
+                None => f(ring.insert(IoUring::new(QUEUE_DEPTH)?)),
+            };
+            // the buffers of entries left in the queue may be freed once this returns, so they must never be submitted
+            if ring
+                .as_mut()
+                .is_some_and(|ring| !ring.submission().is_empty())
+            {
+                *ring = None;
+            }
+            result
+        })
+    }
+
+    // submit entries and wait for all of them to complete, returning (user data, bytes transferred) of each.
+    // Buffers of the entries must stay valid until this returns, so it only returns once the kernel is done with
+    // every entry it took, even on errors. Entries it didn't take are left in the submission queue
+    #[cfg(target_os = "linux")]
+    fn submit(
+        ring: &mut IoUring,
+        entries: &[io_uring::squeue::Entry],
+    ) -> io::Result<Vec<(usize, usize)>> {
+        let mut error = None;
+        let mut pushed = 0;
+        for entry in entries {
+            // the queue is only ever filled up to QUEUE_DEPTH entries
+            if let Err(e) = unsafe { ring.submission().push(entry) } {
+                error = Some(io::Error::other(e));
+                break;
+            }
+            pushed += 1;
+        }
+
+        // entries that the kernel took and that haven't completed yet
+        let in_flight = |ring: &mut IoUring, done: usize| pushed - done - ring.submission().len();
+        let mut done = Vec::with_capacity(pushed);
+        while done.len() < pushed {
+            let entered = if error.is_none() {
+                ring.submit_and_wait(pushed - done.len())
+            } else if in_flight(ring, done.len()) > 0 {
+                // after an error, only wait for the entries the kernel took
+                let want = in_flight(ring, done.len()) as u32;
+                unsafe {
+                    ring.submitter()
+                        .enter::<()>(0, want, EnterFlags::GETEVENTS.bits(), None)
+                }
+            } else {
+                break;
+            };
+            match entered {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // the kernel is short of resources until the completions in flight are reaped
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy
+                    ) && in_flight(ring, done.len()) > 0 => {}
+                Err(e) if error.is_none() => error = Some(e),
+                // returning would let the caller free buffers that the kernel still reads or writes
+                Err(e) => {
+                    eprintln!("waiting for io_uring completions failed: {}", e);
+                    std::process::abort();
+                }
+            }
+            for completion in ring.completion() {
+                let result = completion.result();
+                if result < 0 {
+                    error.get_or_insert(io::Error::from_raw_os_error(-result));
+                }
+                done.push((completion.user_data() as usize, result.max(0) as usize));
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(done),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs::OpenOptions};
+
+    #[test]
+    fn test_read_and_write_many() {
+        for backend in [IoBackend::Pread, IoBackend::IoUring] {
+            let path = env::temp_dir().join(format!("test_disk_io_{:?}", backend));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            let io = DiskIo::new(backend);
+
+            // more writes than fit in the queue at once
+            let records: Vec<Vec<u8>> = (0..200u8).map(|i| vec![i; 10]).collect();
+            let writes: Vec<(u64, &[u8])> = records
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, record)| (i as u64 * 10, record.as_slice()))
+                .collect();
+            io.write_many(&file, &writes).unwrap();
+            assert_eq!(2000, file.metadata().unwrap().len());
+
+            let mut buffers = vec![vec![0u8; 10]; 100];
+            let mut reads: Vec<(u64, &mut [u8])> = buffers
+                .iter_mut()
+                .enumerate()
+                .map(|(i, buffer)| (i as u64 * 20, buffer.as_mut_slice()))
+                .collect();
+            io.read_many(&file, &mut reads).unwrap();
+            for (i, buffer) in buffers.iter().enumerate() {
+                assert_eq!(&vec![(i * 2) as u8; 10], buffer);
+            }
+
+            // reading past the end of the file fails instead of returning a partial record
+            let mut buffer = [0u8; 10];
+            assert!(io.read_many(&file, &mut [(1995, &mut buffer)]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_failed_batches_leave_no_completions_behind() {
+        let path = env::temp_dir().join("test_disk_io_failed_batches");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let records: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 10]).collect();
+        let writes: Vec<(u64, &[u8])> = records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| (i as u64 * 10, record.as_slice()))
+            .collect();
+        let io = DiskIo::new(IoBackend::IoUring);
+        io.write_many(&file, &writes).unwrap();
+        let write_only = OpenOptions::new().write(true).open(&path).unwrap();
+
+        // threads read through rings of their own, and a batch whose reads fail doesn't leave completions that a
+        // later batch would mistake for its own
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let io = DiskIo::new(IoBackend::IoUring);
+                    for _ in 0..50 {
+                        let mut buffers = vec![vec![0u8; 10]; 100];
+                        let mut reads: Vec<(u64, &mut [u8])> = buffers
+                            .iter_mut()
+                            .enumerate()
+                            .map(|(i, buffer)| (i as u64 * 10, buffer.as_mut_slice()))
+                            .collect();
+                        assert!(io.read_many(&write_only, &mut reads).is_err());
+                        io.read_many(&file, &mut reads).unwrap();
+                        for (i, buffer) in buffers.iter().enumerate() {
+                            assert_eq!(&vec![i as u8; 10], buffer);
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
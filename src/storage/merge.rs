@@ -0,0 +1,438 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use crate::graph::filter::LabelIndex;
+use crate::graph::graph::GraphView;
+use crate::prelude::*;
+use crate::{NaiveDisk, Node};
+
+use super::{fresh_disk::TempIndex, IndexStore, IoThrottle};
+
+// distance threshold (alpha) of the prunes done while merging, 1.2 as in the FreshDiskANN paper
+const MERGE_DISTANCE_THRESHOLD: f32 = 1.2;
+// search list size of the searches that find the long term neighbourhood of inserted nodes
+const MERGE_SEARCH_LIST_SIZE: usize = 50;
+// nodes read and written at once by the passes over the long term index
+const MERGE_BATCH_SIZE: usize = 1024;
+
+// streaming_merge persists a frozen segment into the long term index with the StreamingMerge of the FreshDiskANN paper:
+// - delete phase: one pass over the long term index replaces the edges to nodes the segment removed with the
+//   out-neighbours of those nodes, prunes the result and frees the removed slots
+// - insert phase: every node the segment added is searched for in the long term index, and gets the pruned result as
+//   its neighbourhood instead of the one it had in the temp index
+// - patch phase: one pass over the nodes that gained edges in the insert phase or were updated by the segment adds
+//   those edges, pruning nodes that end up over the degree bound
+// Nodes are visited in file order, so that every phase reads and writes the file front to back. The lock is only
+// written to for the writes of a batch: readers see the segment on top of a half merged long term index until the
+// segment is dropped, and every half merged node only points at nodes the segment can serve. Batches are only written
+// once the throttle lets their bytes through
+pub(super) fn streaming_merge(
+    long_term_index: &RwLock<NaiveDisk>,
+    segment: &TempIndex,
+    throttle: &IoThrottle,
+) -> Result<()> {
+    let long_term_nodes: HashSet<u32> = long_term_index
+        .read()
+        .unwrap()
+        .get_all_node_indexes()?
+        .into_iter()
+        .collect();
+
+    // nodes added and removed within the segment never reach the long term index
+    let removed: HashSet<u32> = segment
+        .iter()
+        .filter(|(node_index, node)| node.is_none() && long_term_nodes.contains(node_index))
+        .map(|(node_index, _)| *node_index)
+        .collect();
+    let mut updated: HashMap<u32, HashSet<u32>> = HashMap::new();
+    let mut inserted: Vec<&Node> = Vec::new();
+    for node in segment.values().flatten() {
+        if long_term_nodes.contains(&node.id) {
+            updated.insert(node.id, node.connected.clone());
+        } else {
+            inserted.push(node);
+        }
+    }
+    inserted.sort_unstable_by_key(|node| node.id);
+
+    if !removed.is_empty() {
+        delete_phase(
+            long_term_index,
+            &long_term_nodes,
+            &removed,
+            &mut updated,
+            throttle,
+        )?;
+    }
+    let reverse_edges = insert_phase(long_term_index, &inserted, &removed, throttle)?;
+    patch_phase(long_term_index, updated, reverse_edges, &removed, throttle)?;
+
+    // the slots of nodes added and removed within the segment are freed, even past the last inserted node
+    let next_node_index = segment
+        .keys()
+        .filter(|node_index| !long_term_nodes.contains(node_index))
+        .map(|node_index| node_index + 1)
+        .max();
+    if let Some(next_node_index) = next_node_index {
+        long_term_index.write().unwrap().grow_to(next_node_index)?;
+    }
+    Ok(())
+}
+
+// write_nodes writes nodes to the long term index a batch at a time, waiting for the throttle before the write lock is
+// taken, so that readers aren't held up while the merge waits
+fn write_nodes(
+    long_term_index: &RwLock<NaiveDisk>,
+    nodes: &[Node],
+    throttle: &IoThrottle,
+) -> Result<()> {
+    let node_size = long_term_index.read().unwrap().index_node_size();
+    for batch in nodes.chunks(MERGE_BATCH_SIZE) {
+        throttle.acquire(batch.len() * node_size);
+        long_term_index
+            .write()
+            .unwrap()
+            .set_nodes(&batch.iter().collect::<Vec<_>>())?;
+    }
+    Ok(())
+}
+
+// view returns a view to search and prune the long term index with. Labels are left out, as the index store doesn't
+// know them, so merges prune like Vamana
+fn view<'a>(long_term: &'a NaiveDisk, labels: &'a LabelIndex) -> GraphView<'a, NaiveDisk> {
+    GraphView {
+        index_store: long_term,
+        metric: long_term.get_metric(),
+        pq: None,
+        quantizer: long_term.get_scalar_quantizer(),
+        beam_width: 1,
+        labels,
+    }
+}
+
+// delete_phase writes every long term node with the segment's version of its edges, where edges to removed nodes
+// are replaced, then frees the removed nodes. The updated nodes it writes are taken out of updated
+fn delete_phase(
+    long_term_index: &RwLock<NaiveDisk>,
+    long_term_nodes: &HashSet<u32>,
+    removed: &HashSet<u32>,
+    updated: &mut HashMap<u32, HashSet<u32>>,
+    throttle: &IoThrottle,
+) -> Result<()> {
+    let labels = LabelIndex::default();
+    let mut removed_connections: HashMap<u32, HashSet<u32>> = HashMap::new();
+    let removed_indexes: Vec<u32> = removed.iter().copied().collect();
+    for node in long_term_index
+        .read()
+        .unwrap()
+        .get_nodes(&removed_indexes)?
+    {
+        removed_connections.insert(node.id, node.connected);
+    }
+
+    let mut node_indexes: Vec<u32> = long_term_nodes.difference(removed).copied().collect();
+    node_indexes.sort_unstable();
+    for batch in node_indexes.chunks(MERGE_BATCH_SIZE) {
+        let mut changed: Vec<Node> = Vec::new();
+        {
+            let long_term = long_term_index.read().unwrap();
+            let view = view(&long_term, &labels);
+            let degree_bound = long_term.max_neighbour_count() as usize;
+            for mut node in long_term.get_nodes(batch)? {
+                let was_updated = match updated.remove(&node.id) {
+                    Some(connections) => {
+                        node.connected = connections;
+                        true
+                    }
+                    None => false,
+                };
+                if node.connected.is_disjoint(removed) {
+                    if was_updated {
+                        changed.push(node);
+                    }
+                    continue;
+                }
+
+                let mut candidates: HashSet<u32> = HashSet::new();
+                for neighbour in node.connected.iter() {
+                    match removed_connections.get(neighbour) {
+                        Some(connections) => candidates.extend(connections),
+                        None => {
+                            candidates.insert(*neighbour);
+                        }
+                    }
+                }
+                candidates
+                    .retain(|candidate| *candidate != node.id && !removed.contains(candidate));
+                node.connected = HashSet::new();
+                node.connected =
+                    view.prune(&node, &candidates, MERGE_DISTANCE_THRESHOLD, degree_bound)?;
+                changed.push(node);
+            }
+        }
+        write_nodes(long_term_index, &changed, throttle)?;
+    }
+
+    let mut long_term = long_term_index.write().unwrap();
+    for node_index in removed_indexes {
+        long_term.remove_node(node_index)?;
+    }
+    Ok(())
+}
+
+// insert_phase appends the nodes the segment added to the long term index and picks their neighbourhoods, returning
+// the reverse edges to add to the nodes they picked
+fn insert_phase(
+    long_term_index: &RwLock<NaiveDisk>,
+    inserted: &[&Node],
+    removed: &HashSet<u32>,
+    throttle: &IoThrottle,
+) -> Result<HashMap<u32, HashSet<u32>>> {
+    let mut reverse_edges: HashMap<u32, HashSet<u32>> = HashMap::new();
+    if inserted.is_empty() {
+        return Ok(reverse_edges);
+    }
+
+    // nodes are first written with their temp index edges, so that searches and prunes can read every candidate
+    let mut nodes: Vec<Node> = inserted
+        .iter()
+        .map(|node| {
+            let mut node = (*node).clone();
+            node.connected
+                .retain(|neighbour| !removed.contains(neighbour));
+            node
+        })
+        .collect();
+    write_nodes(long_term_index, &nodes, throttle)?;
+
+    {
+        let labels = LabelIndex::default();
+        let long_term = long_term_index.read().unwrap();
+        let view = view(&long_term, &labels);
+        let degree_bound = long_term.max_neighbour_count() as usize;
+
+        // entry points may point at nodes of later segments, which aren't in the long term index yet
+        let mut start_nodes: Vec<u32> = long_term
+            .get_entry_points()
+            .into_iter()
+            .filter(|node_index| long_term.get_node(*node_index).is_ok())
+            .collect();
+        if start_nodes.is_empty() {
+            start_nodes.push(nodes[0].id);
+        }
+
+        for node in nodes.iter_mut() {
+            let (_, visited) = view.greedy_search(
+                &start_nodes,
+                &node.vector,
+                &HashSet::new(),
+                1,
+                MERGE_SEARCH_LIST_SIZE,
+            )?;
+            node.connected = view.prune(node, &visited, MERGE_DISTANCE_THRESHOLD, degree_bound)?;
+            for neighbour in node.connected.iter() {
+                reverse_edges.entry(*neighbour).or_default().insert(node.id);
+            }
+        }
+    }
+
+    write_nodes(long_term_index, &nodes, throttle)?;
+    Ok(reverse_edges)
+}
+
+// patch_phase writes the nodes the segment updated and adds the reverse edges of inserted nodes
+fn patch_phase(
+    long_term_index: &RwLock<NaiveDisk>,
+    mut updated: HashMap<u32, HashSet<u32>>,
+    mut reverse_edges: HashMap<u32, HashSet<u32>>,
+    removed: &HashSet<u32>,
+    throttle: &IoThrottle,
+) -> Result<()> {
+    let mut node_indexes: Vec<u32> = updated
+        .keys()
+        .chain(reverse_edges.keys())
+        .filter(|node_index| !removed.contains(node_index))
+        .copied()
+        .collect();
+    node_indexes.sort_unstable();
+    node_indexes.dedup();
+
+    let labels = LabelIndex::default();
+    for batch in node_indexes.chunks(MERGE_BATCH_SIZE) {
+        let mut nodes;
+        {
+            let long_term = long_term_index.read().unwrap();
+            nodes = long_term.get_nodes(batch)?;
+            let view = view(&long_term, &labels);
+            let degree_bound = long_term.max_neighbour_count() as usize;
+            for node in nodes.iter_mut() {
+                if let Some(connections) = updated.remove(&node.id) {
+                    node.connected = connections;
+                }
+                if let Some(connections) = reverse_edges.remove(&node.id) {
+                    node.connected.extend(connections);
+                }
+                let node_index = node.id;
+                node.connected
+                    .retain(|neighbour| *neighbour != node_index && !removed.contains(neighbour));
+                if node.connected.len() > degree_bound {
+                    node.connected = view.prune(
+                        node,
+                        &HashSet::new(),
+                        MERGE_DISTANCE_THRESHOLD,
+                        degree_bound,
+                    )?;
+                }
+            }
+        }
+        write_nodes(long_term_index, &nodes, throttle)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeSet, env};
+
+    const MAX_NEIGHBOUR_COUNT: u8 = 6;
+
+    // long_term_grid returns a long term index of the points of a size x size grid, each connected to the points
+    // next to it
+    fn long_term_grid(name: &str, size: u32) -> NaiveDisk {
+        let temp_dir = env::temp_dir();
+        let index_path = temp_dir.join(format!("{}.index", name));
+        let free_path = temp_dir.join(format!("{}.free", name));
+        let mut long_term = NaiveDisk::new(
+            2,
+            MAX_NEIGHBOUR_COUNT,
+            index_path.to_str().unwrap(),
+            free_path.to_str().unwrap(),
+        )
+        .unwrap();
+
+        let vectors: Vec<Vec<f32>> = (0..size * size)
+            .map(|i| vec![(i % size) as f32, (i / size) as f32])
+            .collect();
+        long_term.add_nodes(&vectors).unwrap();
+        for i in 0..size * size {
+            let (x, y) = (i % size, i / size);
+            let mut connections = HashSet::new();
+            if x > 0 {
+                connections.insert(i);
+            }
+            if x + 1 < size {
+                connections.insert(i + 2);
+            }
+            if y > 0 {
+                connections.insert(i + 1 - size);
+            }
+            if y + 1 < size {
+                connections.insert(i + 1 + size);
+            }
+            long_term.set_connections(i + 1, &connections).unwrap();
+        }
+        long_term.set_entry_points(&[1]).unwrap();
+        long_term
+    }
+
+    #[test]
+    fn test_streaming_merge() {
+        let size = 10;
+        let long_term_index = RwLock::new(long_term_grid("test_merge", size));
+        let first_inserted = size * size + 1;
+
+        // the segment removes a column of the grid, adds points between the remaining ones with a single temp index
+        // edge each, and updates the edges of a long term node
+        let mut segment = TempIndex::new();
+        for y in 0..size {
+            segment.insert(y * size + 5 + 1, None);
+        }
+        let mut inserted_vectors = Vec::new();
+        for i in 0..20 {
+            let vector = vec![(i % 9) as f32 + 0.5, (i / 9) as f32 * 3.0 + 0.5];
+            let node_index = first_inserted + i;
+            segment.insert(
+                node_index,
+                Some(Node {
+                    id: node_index,
+                    vector: vector.clone(),
+                    connected: HashSet::from([1]),
+                    quantized: None,
+                }),
+            );
+            inserted_vectors.push((node_index, vector));
+        }
+        let mut updated = long_term_index.read().unwrap().get_node(1).unwrap();
+        updated.connected = HashSet::from([2, first_inserted]);
+        segment.insert(1, Some(updated));
+
+        streaming_merge(&long_term_index, &segment, &IoThrottle::default()).unwrap();
+
+        let long_term = long_term_index.read().unwrap();
+        let all_nodes = long_term.get_all_nodes().unwrap();
+        assert_eq!((size * size - size + 20) as usize, all_nodes.len());
+        for node in all_nodes.values() {
+            assert!(node.connected.len() <= MAX_NEIGHBOUR_COUNT as usize);
+            assert!(!node.connected.is_empty());
+            assert!(node
+                .connected
+                .iter()
+                .all(|neighbour| all_nodes.contains_key(neighbour)));
+        }
+        assert!(all_nodes[&1].connected.contains(&first_inserted));
+
+        // inserted nodes got neighbourhoods of their own, and the long term index leads to them
+        let labels = LabelIndex::default();
+        let view = view(&long_term, &labels);
+        for (node_index, vector) in inserted_vectors {
+            assert_ne!(HashSet::from([1]), all_nodes[&node_index].connected);
+            let (closest, _) = view
+                .greedy_search(&[1], &vector, &HashSet::new(), 1, 10)
+                .unwrap();
+            assert_eq!(node_index, closest[0].1);
+        }
+    }
+
+    #[test]
+    fn test_merge_frees_nodes_removed_within_segment() {
+        let size = 4;
+        let long_term_index = RwLock::new(long_term_grid("test_merge_removed", size));
+        let first_inserted = size * size + 1;
+
+        // of 5 nodes added by the segment, the second, third and last are removed again before the merge
+        let mut segment = TempIndex::new();
+        for i in 0..5 {
+            let node_index = first_inserted + i;
+            let node = (i == 0 || i == 3).then(|| Node {
+                id: node_index,
+                vector: vec![i as f32 + 0.5, 0.5],
+                connected: HashSet::from([1]),
+                quantized: None,
+            });
+            segment.insert(node_index, node);
+        }
+
+        streaming_merge(&long_term_index, &segment, &IoThrottle::default()).unwrap();
+
+        // their slots were never written, and are free for the next nodes
+        let mut long_term = long_term_index.write().unwrap();
+        assert_eq!(first_inserted + 5, long_term.next_node_index());
+        assert_eq!(
+            &BTreeSet::from([first_inserted + 1, first_inserted + 2, first_inserted + 4]),
+            long_term.free_slots()
+        );
+        let mut expected: Vec<u32> = (1..=size * size).collect();
+        expected.extend([first_inserted, first_inserted + 3]);
+        assert_eq!(expected, long_term.get_all_node_indexes().unwrap());
+
+        let ids = long_term
+            .add_nodes(&[vec![0.0, 0.0], vec![1.0, 1.0], vec![2.0, 2.0]])
+            .unwrap();
+        assert_eq!(
+            vec![first_inserted + 1, first_inserted + 2, first_inserted + 4],
+            ids
+        );
+        assert_eq!(first_inserted + 5, long_term.next_node_index());
+    }
+}
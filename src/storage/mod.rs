@@ -1,9 +1,19 @@
+mod data_disk;
 mod disk;
 mod fresh_disk;
+mod ids;
 mod inmem;
+mod io;
+mod merge;
 mod storage;
+mod throttle;
+mod wal;
 
-pub use disk::NaiveDisk;
-pub use fresh_disk::FreshDisk;
+pub use data_disk::DiskDataStore;
+pub use disk::{NaiveDisk, ReadMode, VectorRef};
+pub use fresh_disk::{Backpressure, FlushLag, FreshDisk, FreshDiskConfig};
+pub use ids::{ExternalId, IdMap};
 pub use inmem::InMemStorage;
-pub use storage::{DataStore, IndexStore};
+pub use io::IoBackend;
+pub use storage::{DataStore, IndexStore, MAX_ENTRY_POINTS};
+pub use throttle::IoThrottle;
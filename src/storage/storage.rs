@@ -2,19 +2,85 @@ use std::collections::{HashMap, HashSet};
 
 use crate::prelude::*;
 
-use crate::graph::Node;
+use crate::graph::{sq::ScalarQuantizer, Metric, Node};
+
+// the number of entry points every IndexStore must be able to persist
+pub const MAX_ENTRY_POINTS: usize = 8;
 
 pub trait IndexStore {
     fn add_nodes(&mut self, data: &[Vec<f32>]) -> Result<Vec<u32>>;
     fn get_node(&self, node_id: u32) -> Result<Node>;
+    // get_nodes returns the nodes of node_ids, in order. Disk backends serve it with fewer reads than one get_node per node
+    fn get_nodes(&self, node_ids: &[u32]) -> Result<Vec<Node>> {
+        node_ids
+            .iter()
+            .map(|node_id| self.get_node(*node_id))
+            .collect()
+    }
+    // distances_to_query returns the distance under metric from query to each of node_ids, in order, computed on the
+    // store's own copy of their vectors. query_quantized is query quantized with get_scalar_quantizer.
+    // Stores that would have to read whole nodes for it return None, and callers use get_nodes instead
+    fn distances_to_query(
+        &self,
+        _metric: Metric,
+        _query: &[f32],
+        _query_quantized: Option<&[i8]>,
+        _node_ids: &[u32],
+    ) -> Option<Result<Vec<i64>>> {
+        None
+    }
     fn set_connections(&mut self, node_index: u32, connections: &HashSet<u32>) -> Result<()>;
     fn get_random_node(&self) -> Option<Node>;
     fn get_all_node_indexes(&self) -> Result<Vec<u32>>;
     fn get_all_nodes(&self) -> Result<HashMap<u32, Node>>;
     fn get_name(&self) -> String;
+    // the metric of the graph is stored alongside it, so that a reopened graph keeps using it
+    fn get_metric(&self) -> Metric;
+    fn set_metric(&mut self, metric: Metric) -> Result<()>;
+    // entry points are where searches and inserts start from
+    fn get_entry_points(&self) -> Vec<u32>;
+    fn set_entry_points(&mut self, entry_points: &[u32]) -> Result<()>;
+    // tombstone a node. It stays in the graph until it is removed with remove_nodes
+    fn delete_node(&mut self, node_index: u32) -> Result<()>;
+    fn is_deleted(&self, node_index: u32) -> bool;
+    fn get_deleted_node_indexes(&self) -> Result<HashSet<u32>>;
+    // node_counts returns the number of nodes in the graph, tombstoned ones included, and the number of tombstoned nodes.
+    // Stores that track them in memory override the scan
+    fn node_counts(&self) -> Result<(usize, usize)> {
+        Ok((
+            self.get_all_node_indexes()?.len(),
+            self.get_deleted_node_indexes()?.len(),
+        ))
+    }
+    // remove nodes from the graph and clear their tombstones
+    fn remove_nodes(&mut self, node_indexes: &HashSet<u32>) -> Result<()>;
+    // stores that keep vectors as i8 return their quantizer, so that distances are computed on the quantized vectors of nodes
+    fn get_scalar_quantizer(&self) -> Option<ScalarQuantizer> {
+        None
+    }
+    // flush blocks until every update so far is durable, for stores that persist updates in the background or
+    // leave them to the OS
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    // stores that can be read from many threads at once return themselves, which lets Graph::index_parallel use them
+    fn as_sync(&self) -> Option<&(dyn IndexStore + Sync)> {
+        None
+    }
 }
 
+// DataStore holds the payload of every node, arbitrary bytes such as UTF-8 text or JSON
 pub trait DataStore {
-    fn add_data(&mut self, node_id: u32, data: String) -> Result<()>;
-    fn get_data(&self, node_id: u32) -> Option<String>;
+    // add_data sets the payload of node_id, replacing any previous one
+    fn add_data(&mut self, node_id: u32, data: &[u8]) -> Result<()>;
+    fn get_data(&self, node_id: u32) -> Result<Option<Vec<u8>>>;
+    fn delete_data(&mut self, node_id: u32) -> Result<()>;
+    // flush blocks until every payload added so far is durable
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    // stores that can be read from many threads at once return themselves, which lets the server share them
+    fn as_sync(&self) -> Option<&(dyn DataStore + Sync)> {
+        None
+    }
 }
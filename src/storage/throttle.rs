@@ -0,0 +1,130 @@
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+// IoThrottle is a token bucket limiting the bytes per second written by background work, such as the merges of
+// FreshDisk, so that they leave the disk to foreground reads. The bucket holds up to a second of writes, and a write
+// bigger than what is left waits for the tokens it lacks. The rate can be changed while writes wait, None lifts it
+#[derive(Default)]
+pub struct IoThrottle {
+    bucket: Mutex<Bucket>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct Bucket {
+    bytes_per_sec: Option<u64>,
+    // bytes that may be written right away, negative while writes wait for the ones they lack
+    tokens: f64,
+    refilled_at: Option<Instant>,
+    // how long writes waited in total
+    throttled: Duration,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = self
+            .refilled_at
+            .map_or(Duration::ZERO, |refilled_at| now - refilled_at);
+        self.refilled_at = Some(now);
+        self.tokens = match self.bytes_per_sec {
+            Some(bytes_per_sec) => (self.tokens + elapsed.as_secs_f64() * bytes_per_sec as f64)
+                .min(bytes_per_sec as f64),
+            None => 0.0,
+        };
+    }
+}
+
+impl IoThrottle {
+    // new starts with a full bucket
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        IoThrottle {
+            bucket: Mutex::new(Bucket {
+                bytes_per_sec,
+                tokens: bytes_per_sec.unwrap_or(0) as f64,
+                refilled_at: Some(Instant::now()),
+                throttled: Duration::ZERO,
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().bytes_per_sec
+    }
+
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        // tokens are refilled at the old rate up to now, and capped at a second of the new one
+        bucket.refill();
+        bucket.bytes_per_sec = bytes_per_sec;
+        bucket.refill();
+        drop(bucket);
+        // waiting writes recompute how long they wait with the new rate
+        self.condvar.notify_all();
+    }
+
+    // throttled returns how long writes were held back in total
+    pub fn throttled(&self) -> Duration {
+        self.bucket.lock().unwrap().throttled
+    }
+
+    // acquire waits until bytes may be written
+    pub(crate) fn acquire(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        if bucket.bytes_per_sec.is_none() {
+            return;
+        }
+        bucket.tokens -= bytes as f64;
+
+        let waiting_since = Instant::now();
+        while bucket.tokens < 0.0 {
+            let Some(bytes_per_sec) = bucket.bytes_per_sec else {
+                break;
+            };
+            let lacking = Duration::from_secs_f64(-bucket.tokens / bytes_per_sec as f64);
+            bucket = self.condvar.wait_timeout(bucket, lacking).unwrap().0;
+            bucket.refill();
+        }
+        bucket.throttled += waiting_since.elapsed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let throttle = IoThrottle::new(Some(1000));
+
+        // a second of writes goes through right away, the rest waits for the bucket to refill
+        let start = Instant::now();
+        throttle.acquire(1000);
+        throttle.acquire(500);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        assert!(throttle.throttled() >= Duration::from_millis(450));
+
+        // without a rate, nothing waits
+        throttle.set_rate(None);
+        let throttled = throttle.throttled();
+        throttle.acquire(1 << 30);
+        assert_eq!(throttled, throttle.throttled());
+    }
+
+    #[test]
+    fn test_set_rate_releases_waiting_writes() {
+        let throttle = std::sync::Arc::new(IoThrottle::new(Some(1)));
+        throttle.acquire(1);
+
+        let waiting = throttle.clone();
+        let writer = std::thread::spawn(move || waiting.acquire(3600));
+        std::thread::sleep(Duration::from_millis(50));
+        throttle.set_rate(None);
+        writer.join().unwrap();
+        assert!(throttle.throttled() < Duration::from_secs(60));
+    }
+}
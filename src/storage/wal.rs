@@ -0,0 +1,285 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::prelude::Result;
+
+// write-ahead log layout
+// append-only, one file per FreshDisk temp index segment
+//
+// .wal file:
+// [record][record]...
+//
+// where [record]:
+// [payload length][crc32 of payload][payload          ]
+// [u32           ][u32             ][u8 * length      ]
+//
+// where [payload]:
+// [op][node index][op specific fields]
+// [u8][u32       ][                  ]
+//
+// op specific fields:
+// add node:        [vector length][vector      ]
+//                  [u32          ][f32 * length]
+// set connections: [count][neighbor indexes]
+//                  [u32  ][u32 * count     ]
+// delete node, remove node: none
+//
+// A record that is cut short or fails its checksum is treated as the end of the log, as it can only come from a crash mid-append
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum WalRecord {
+    AddNode {
+        node_index: u32,
+        vector: Vec<f32>,
+    },
+    SetConnections {
+        node_index: u32,
+        connections: Vec<u32>,
+    },
+    DeleteNode {
+        node_index: u32,
+    },
+    RemoveNode {
+        node_index: u32,
+    },
+}
+
+const OP_ADD_NODE: u8 = 0;
+const OP_SET_CONNECTIONS: u8 = 1;
+const OP_DELETE_NODE: u8 = 2;
+const OP_REMOVE_NODE: u8 = 3;
+
+impl WalRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            WalRecord::AddNode { node_index, vector } => {
+                payload.push(OP_ADD_NODE);
+                payload.extend_from_slice(&node_index.to_be_bytes());
+                payload.extend_from_slice(&(vector.len() as u32).to_be_bytes());
+                for value in vector {
+                    payload.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            WalRecord::SetConnections {
+                node_index,
+                connections,
+            } => {
+                payload.push(OP_SET_CONNECTIONS);
+                payload.extend_from_slice(&node_index.to_be_bytes());
+                payload.extend_from_slice(&(connections.len() as u32).to_be_bytes());
+                for neighbor in connections {
+                    payload.extend_from_slice(&neighbor.to_be_bytes());
+                }
+            }
+            WalRecord::DeleteNode { node_index } => {
+                payload.push(OP_DELETE_NODE);
+                payload.extend_from_slice(&node_index.to_be_bytes());
+            }
+            WalRecord::RemoveNode { node_index } => {
+                payload.push(OP_REMOVE_NODE);
+                payload.extend_from_slice(&node_index.to_be_bytes());
+            }
+        }
+        payload
+    }
+
+    fn decode(payload: &[u8]) -> Option<WalRecord> {
+        let (&op, rest) = payload.split_first()?;
+        let node_index = u32::from_be_bytes(rest.get(0..4)?.try_into().unwrap());
+        let rest = &rest[4..];
+
+        match op {
+            OP_ADD_NODE => {
+                let values = Self::decode_list(rest)?;
+                let vector = values
+                    .chunks_exact(4)
+                    .map(|chunk| f32::from_be_bytes(chunk.try_into().unwrap()))
+                    .collect();
+                Some(WalRecord::AddNode { node_index, vector })
+            }
+            OP_SET_CONNECTIONS => {
+                let values = Self::decode_list(rest)?;
+                let connections = values
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+                    .collect();
+                Some(WalRecord::SetConnections {
+                    node_index,
+                    connections,
+                })
+            }
+            OP_DELETE_NODE if rest.is_empty() => Some(WalRecord::DeleteNode { node_index }),
+            OP_REMOVE_NODE if rest.is_empty() => Some(WalRecord::RemoveNode { node_index }),
+            _ => None,
+        }
+    }
+
+    // decode_list returns the bytes of a length-prefixed list of 4 byte values
+    fn decode_list(bytes: &[u8]) -> Option<&[u8]> {
+        let count = u32::from_be_bytes(bytes.get(0..4)?.try_into().unwrap()) as usize;
+        let values = &bytes[4..];
+        if values.len() != count * 4 {
+            return None;
+        }
+        Some(values)
+    }
+}
+
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl Wal {
+    // create an empty log at path, truncating any existing one
+    pub(crate) fn create(path: PathBuf) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(&path)?);
+        Ok(Wal { path, file })
+    }
+
+    // append a record. Records are buffered until flush is called
+    pub(crate) fn append(&mut self, record: &WalRecord) -> io::Result<()> {
+        let payload = record.encode();
+        self.file.write_all(&(payload.len() as u32).to_be_bytes())?;
+        self.file
+            .write_all(&crc32fast::hash(&payload).to_be_bytes())?;
+        self.file.write_all(&payload)
+    }
+
+    // write buffered records and fsync them, so that they survive a crash of the process or a power failure
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // read all intact records of the log at path, stopping at the first torn or corrupted record
+    pub(crate) fn replay(path: &Path) -> Result<Vec<WalRecord>> {
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some(header) = buffer.get(offset..offset + 8) {
+            let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let Some(payload) = buffer.get(offset + 8..offset + 8 + length) else {
+                break;
+            };
+            if crc32fast::hash(payload) != checksum {
+                break;
+            }
+            let Some(record) = WalRecord::decode(payload) else {
+                break;
+            };
+            records.push(record);
+            offset += 8 + length;
+        }
+        Ok(records)
+    }
+
+    // list the segment logs written for index_path, oldest first
+    pub(crate) fn list(index_path: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+        let index_path = Path::new(index_path);
+        let dir = match index_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.wal.",
+            index_path.file_name().unwrap_or_default().to_string_lossy()
+        );
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut logs = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(Ok(sequence)) = file_name.strip_prefix(&prefix).map(str::parse::<u64>) {
+                logs.push((sequence, path));
+            }
+        }
+        logs.sort();
+        Ok(logs)
+    }
+
+    pub(crate) fn segment_path(index_path: &str, sequence: u64) -> PathBuf {
+        PathBuf::from(format!("{}.wal.{}", index_path, sequence))
+    }
+
+    pub(crate) fn remove(path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_append_and_replay() {
+        let path = env::temp_dir().join("test_append_and_replay.wal.0");
+        let records = vec![
+            WalRecord::AddNode {
+                node_index: 1,
+                vector: vec![1.0, 2.0],
+            },
+            WalRecord::SetConnections {
+                node_index: 1,
+                connections: vec![2, 3],
+            },
+            WalRecord::DeleteNode { node_index: 2 },
+            WalRecord::RemoveNode { node_index: 3 },
+        ];
+
+        let mut wal = Wal::create(path.clone()).unwrap();
+        for record in &records {
+            wal.append(record).unwrap();
+        }
+        wal.flush().unwrap();
+
+        assert_eq!(records, Wal::replay(&path).unwrap());
+    }
+
+    #[test]
+    fn test_replay_stops_at_torn_record() {
+        let path = env::temp_dir().join("test_replay_torn.wal.0");
+        let record = WalRecord::AddNode {
+            node_index: 1,
+            vector: vec![1.0, 2.0],
+        };
+
+        let mut wal = Wal::create(path.clone()).unwrap();
+        wal.append(&record).unwrap();
+        wal.append(&record).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+
+        // corrupt the last byte of the second record
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(vec![record.clone()], Wal::replay(&path).unwrap());
+
+        // a partially written record is ignored too
+        bytes.truncate(last);
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(vec![record], Wal::replay(&path).unwrap());
+    }
+}